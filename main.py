@@ -1,5 +1,58 @@
 from modules import promethee_sort, promethee_calculate
-import sys, os
+import sys, os, subprocess
+
+BINARY = 'rust/target/release/promethee'
+
+def build():
+    # The Rust version is built with Cargo on the first run
+    if not os.path.isfile(BINARY):
+        status = subprocess.call(['cargo', 'build', '--release', '--manifest-path', 'rust/Cargo.toml'])
+        if status != 0:
+            sys.exit(1)
+
+def fast(args):
+    # Sorting, ranking and unsorting are done in a single Rust command, which
+    # removes its temporary files even when it fails
+    build()
+
+    options, positional, function = [], [], None
+    for arg in args:
+        if arg == '-fp':
+            continue
+        elif arg.startswith('-type='):
+            function = arg[len('-type='):]
+        elif arg.startswith('-size='):
+            # -size counted buckets of 512 KiB, --mem counts MB
+            options += ['--mem', str(max(1, int(arg[len('-size='):]) // 2))]
+        elif arg.startswith('-proc='):
+            options += ['--threads', arg[len('-proc='):]]
+        elif arg == '-ismax':
+            options.append('--max')
+        else:
+            positional.append(arg)
+
+    criterion, weight, params = positional[0], positional[1], positional[2:]
+    if function == 'linear':
+        function = ['linear', params[0]]
+    elif function == 'linearWithIndifference':
+        p, q = float(params[0]), float(params[1])
+        function = ['linear-with-indeference', str(q), str(p - q)]
+    else:
+        raise ValueError("Preference function must be linear or linearWithIndifference")
+
+    status = subprocess.call([BINARY, 'fast', criterion, weight] + options + function)
+    if status != 0:
+        sys.exit(1)
+
+def merge(args):
+    # Flow rasters are added up by the Rust merge command, the last one
+    # being where the sum is written
+    build()
+
+    paths = [arg for arg in args if arg != '-merge']
+    status = subprocess.call([BINARY, 'merge'] + paths[:-1] + ['--output', paths[-1]])
+    if status != 0:
+        sys.exit(1)
 
 def main():
     if '-sort' in sys.argv:
@@ -7,23 +60,9 @@ def main():
     elif '-reverse' in sys.argv:
         promethee_sort.unsort(sys.argv[1:])
     elif '-fp' in sys.argv:
-        promethee_sort.sort(sys.argv[1:])
-        promethee_calculate.calculate(sys.argv[1:])
-        param = []
-        param.append('-reverse')
-        os.system('mv out.* result_' + sys.argv[2])
-        param.append('result_' + sys.argv[2])
-        param.append('positions.tif')
-        for i in xrange(3, len(sys.argv)):
-            param.append(sys.argv[i])
-        promethee_sort.unsort(param)
-        os.system("rm *values*.tif")
-        os.system("rm *positions*.tif")
+        fast(sys.argv[1:])
     elif '-merge' in sys.argv:
-        exists = os.path.isfile('caja/playground/src/merge')
-        if not exists:
-            os.system("g++ caja/playground/src/merge.cpp -o caja/playground/src/merge -std=c++14 -ltiff")
-        os.system(' '.join(['./caja/playground/src/merge'] + list(sys.argv[2:])))
+        merge(sys.argv[1:])
     else:
         promethee_calculate.calculate(sys.argv[1:])
 
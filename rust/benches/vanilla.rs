@@ -0,0 +1,91 @@
+//! Times the vanilla flows against comparing every ordered pair, as they
+//! were before each pair was evaluated once. Run with `cargo bench`.
+
+// The crate is a binary, so its modules are compiled in again here, where
+// most of them and the imports of their tests go unused
+#![allow(dead_code, unused_imports)]
+#[path = "../src/external_sort/mod.rs"]
+mod external_sort;
+#[path = "../src/function/mod.rs"]
+mod function;
+#[path = "../src/promethee/mod.rs"]
+mod promethee;
+#[path = "../src/raster/mod.rs"]
+mod raster;
+
+// The modules find what main.rs brings into the crate root there
+use clap::Clap;
+use promethee::*;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use function::{ComparisonFunction, GaussianFunction};
+use promethee::{label::About, vanilla::Vanilla};
+
+const ALTERNATIVES: usize = 4000;
+
+fn actions() -> Vec<f64> {
+    (0..ALTERNATIVES)
+        .map(|x| ((x * 7919) % ALTERNATIVES) as f64 / 10.0)
+        .collect()
+}
+
+fn gaussian() -> GaussianFunction {
+    GaussianFunction {
+        std_dev: 30.0.into(),
+    }
+}
+
+/// Positive and negative flows from the preferences of every ordered pair,
+/// self pairs included.
+fn ordered_pairs(actions: &[f64], function: &GaussianFunction) -> (Vec<f64>, Vec<f64>) {
+    let mut positive = vec![0.0; actions.len()];
+    let mut negative = vec![0.0; actions.len()];
+    for (index, action) in actions.iter().enumerate() {
+        for other in actions {
+            let lower = action.min(*other);
+            positive[index] += function.preference(action - other, lower);
+            negative[index] += function.preference(other - action, lower);
+        }
+    }
+    (positive, negative)
+}
+
+fn vanilla(c: &mut Criterion) {
+    let actions = actions();
+    let mut group = c.benchmark_group("vanilla");
+    group.sample_size(10);
+
+    group.bench_function("ordered pairs", |b| {
+        b.iter(|| ordered_pairs(black_box(&actions), &gaussian()))
+    });
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut threads = vec![1, 2, 4, cores];
+    threads.sort_unstable();
+    threads.dedup();
+    for threads in threads {
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &threads,
+            |b, threads| {
+                b.iter(|| {
+                    Vanilla::new(false)
+                        .with_threads(*threads)
+                        .rank_vetoed(vec![Criteria {
+                            actions: black_box(&actions).clone().into_iter(),
+                            weight: 1.0,
+                            function: gaussian(),
+                            goal: Goal::Max,
+                            veto: None,
+                            about: About::default(),
+                        }])
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vanilla);
+criterion_main!(benches);
@@ -0,0 +1,448 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    mem::size_of,
+    path::PathBuf,
+};
+
+use tempfile::NamedTempFile;
+
+/// Most runs merged at once, to keep the number of open files bounded.
+const MAX_FAN_IN: usize = 256;
+
+/// Fixed size record that can be sorted out of core.
+pub(crate) trait Record: Ord + Copy + Send {
+    const SIZE: usize;
+
+    fn write_to(&self, bytes: &mut [u8]);
+    fn read_from(bytes: &[u8]) -> Self;
+}
+
+/// Sorts more records than fit in memory, the way the C++ `ExternalSort`
+/// did: sorted runs bounded by `memory` bytes are written to `temp_dir` and
+/// merged afterwards.
+///
+/// Temporary files are removed when they are no longer needed, including
+/// when sorting fails halfway.
+#[derive(Debug, Clone)]
+pub(crate) struct ExternalSort {
+    memory: usize,
+    temp_dir: PathBuf,
+    threads: usize,
+}
+
+impl ExternalSort {
+    pub(crate) fn new<P: Into<PathBuf>>(memory: usize, temp_dir: P) -> Self {
+        Self {
+            memory,
+            temp_dir: temp_dir.into(),
+            threads: 1,
+        }
+    }
+
+    /// Sorts each run in `threads` chunks at the same time (1 by default).
+    /// Chunks are merged as any other run, so the result does not change.
+    pub(crate) fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub(crate) fn sorter<R: Record>(&self) -> Sorter<R> {
+        Sorter {
+            config: self.clone(),
+            buffer: vec![],
+            capacity: (self.memory / size_of::<R>()).max(1),
+            runs: vec![],
+            len: 0,
+        }
+    }
+
+    /// One of `parts` equal shares of the memory, for sorts running while
+    /// other buffers are held.
+    pub(crate) fn share(&self, parts: usize) -> Self {
+        Self {
+            memory: (self.memory / parts.max(1)).max(1),
+            ..self.clone()
+        }
+    }
+
+    /// Moves sorted records to disk, releasing their memory, to be walked by
+    /// up to `readers` iterators at once that share the memory with a sort
+    /// of their own.
+    pub(crate) fn spill<R: Record>(
+        &self,
+        sorted: Sorted<R>,
+        readers: usize,
+    ) -> io::Result<Sorted<R>> {
+        let buffer_size = self.buffer_size(readers);
+        Ok(match sorted {
+            Sorted::Memory(records) => Sorted::File {
+                file: self.write_run(&records)?,
+                len: records.len() as u64,
+                buffer_size,
+            },
+            Sorted::File { file, len, .. } => Sorted::File {
+                file,
+                len,
+                buffer_size,
+            },
+        })
+    }
+
+    fn buffer_size(&self, files: usize) -> usize {
+        (self.memory / (files + 1)).max(8 * 1024)
+    }
+
+    fn write_run<R: Record>(&self, records: &[R]) -> io::Result<NamedTempFile> {
+        let mut run = NamedTempFile::new_in(&self.temp_dir)?;
+        {
+            let mut writer = BufWriter::with_capacity(self.buffer_size(1), run.as_file_mut());
+            let mut bytes = vec![0; R::SIZE];
+            for record in records {
+                record.write_to(&mut bytes);
+                writer.write_all(&bytes)?;
+            }
+            writer.flush()?;
+        }
+        Ok(run)
+    }
+
+    /// k-way merge of sorted runs into a single one.
+    fn merge<R: Record>(&self, runs: Vec<NamedTempFile>) -> io::Result<NamedTempFile> {
+        let buffer_size = self.buffer_size(runs.len());
+        let mut readers = runs
+            .iter()
+            .map(|run| Ok(RunReader::new(run.reopen()?, buffer_size)))
+            .collect::<io::Result<Vec<RunReader<R>>>>()?;
+
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some(record) = reader.next().transpose()? {
+                heap.push(Reverse((record, index)));
+            }
+        }
+
+        let mut merged = NamedTempFile::new_in(&self.temp_dir)?;
+        {
+            let mut writer = BufWriter::with_capacity(buffer_size, merged.as_file_mut());
+            let mut bytes = vec![0; R::SIZE];
+            while let Some(Reverse((record, index))) = heap.pop() {
+                record.write_to(&mut bytes);
+                writer.write_all(&bytes)?;
+                if let Some(record) = readers[index].next().transpose()? {
+                    heap.push(Reverse((record, index)));
+                }
+            }
+            writer.flush()?;
+        }
+        Ok(merged)
+    }
+}
+
+/// Collects records to be sorted, spilling sorted runs to disk whenever the
+/// memory budget is reached.
+pub(crate) struct Sorter<R: Record> {
+    config: ExternalSort,
+    buffer: Vec<R>,
+    capacity: usize,
+    runs: Vec<NamedTempFile>,
+    len: u64,
+}
+
+impl<R: Record> Sorter<R> {
+    pub(crate) fn push(&mut self, record: R) -> io::Result<()> {
+        if self.buffer.len() == self.capacity {
+            self.spill()?;
+        }
+        self.buffer.push(record);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let chunk = sort_chunks(&mut self.buffer, self.config.threads);
+        for records in self.buffer.chunks(chunk) {
+            self.runs.push(self.config.write_run(records)?);
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<Sorted<R>> {
+        if self.runs.is_empty() {
+            if sort_chunks(&mut self.buffer, self.config.threads) < self.buffer.len() {
+                // Merges the sorted chunks
+                self.buffer.sort();
+            }
+            return Ok(Sorted::Memory(self.buffer));
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        self.buffer = vec![];
+
+        let mut runs = self.runs;
+        while runs.len() > MAX_FAN_IN {
+            let mut merged = vec![];
+            while !runs.is_empty() {
+                let group = runs.split_off(runs.len().saturating_sub(MAX_FAN_IN));
+                merged.push(self.config.merge::<R>(group)?);
+            }
+            runs = merged;
+        }
+        let file = match runs.len() {
+            1 => runs.pop().unwrap(),
+            _ => self.config.merge::<R>(runs)?,
+        };
+
+        Ok(Sorted::File {
+            file,
+            len: self.len,
+            buffer_size: self.config.buffer_size(1),
+        })
+    }
+}
+
+/// Sorts `records` split in up to `threads` chunks, each in its own thread.
+/// Returns the length of the chunks.
+fn sort_chunks<R: Record>(records: &mut [R], threads: usize) -> usize {
+    let chunk = records.len().div_ceil(threads).max(1);
+    if threads == 1 || chunk >= records.len() {
+        records.sort_unstable();
+        return records.len().max(1);
+    }
+
+    std::thread::scope(|scope| {
+        for records in records.chunks_mut(chunk) {
+            scope.spawn(move || records.sort_unstable());
+        }
+    });
+    chunk
+}
+
+/// Sorted records, kept in memory when they fit in a single run.
+pub(crate) enum Sorted<R: Record> {
+    Memory(Vec<R>),
+    File {
+        file: NamedTempFile,
+        len: u64,
+        buffer_size: usize,
+    },
+}
+
+impl<R: Record> Sorted<R> {
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Sorted::Memory(records) => records.len() as u64,
+            Sorted::File { len, .. } => *len,
+        }
+    }
+
+    /// Iterates over the records in order. It can be called many times to
+    /// walk the records with independent cursors.
+    pub(crate) fn iter(&self) -> io::Result<SortedIter<'_, R>> {
+        Ok(match self {
+            Sorted::Memory(records) => SortedIter::Memory(records.iter()),
+            Sorted::File {
+                file, buffer_size, ..
+            } => SortedIter::File(RunReader::new(file.reopen()?, *buffer_size)),
+        })
+    }
+}
+
+pub(crate) enum SortedIter<'a, R: Record> {
+    Memory(std::slice::Iter<'a, R>),
+    File(RunReader<R>),
+}
+
+impl<'a, R: Record> Iterator for SortedIter<'a, R> {
+    type Item = io::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedIter::Memory(records) => records.next().map(|record| Ok(*record)),
+            SortedIter::File(reader) => reader.next(),
+        }
+    }
+}
+
+pub(crate) struct RunReader<R: Record> {
+    reader: BufReader<File>,
+    bytes: Vec<u8>,
+    _record: PhantomData<R>,
+}
+
+impl<R: Record> RunReader<R> {
+    fn new(file: File, buffer_size: usize) -> Self {
+        Self {
+            reader: BufReader::with_capacity(buffer_size, file),
+            bytes: vec![0; R::SIZE],
+            _record: PhantomData,
+        }
+    }
+}
+
+impl<R: Record> Iterator for RunReader<R> {
+    type Item = io::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_exact(&mut self.bytes) {
+            Ok(()) => Some(Ok(R::read_from(&self.bytes))),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    struct Key(u32, u32);
+
+    impl Record for Key {
+        const SIZE: usize = 8;
+
+        fn write_to(&self, bytes: &mut [u8]) {
+            bytes[..4].copy_from_slice(&self.0.to_le_bytes());
+            bytes[4..].copy_from_slice(&self.1.to_le_bytes());
+        }
+
+        fn read_from(bytes: &[u8]) -> Self {
+            let mut first = [0; 4];
+            let mut second = [0; 4];
+            first.copy_from_slice(&bytes[..4]);
+            second.copy_from_slice(&bytes[4..]);
+            Key(u32::from_le_bytes(first), u32::from_le_bytes(second))
+        }
+    }
+
+    fn shuffled(n: u32) -> Vec<Key> {
+        // A full period linear congruential sequence, good enough to shuffle
+        let mut state = 7u32;
+        (0..n)
+            .map(|position| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                Key(state % 1000, position)
+            })
+            .collect()
+    }
+
+    fn sort(memory: usize, records: &[Key], temp_dir: &std::path::Path) -> Vec<Key> {
+        let mut sorter = ExternalSort::new(memory, temp_dir).sorter();
+        for record in records {
+            sorter.push(*record).unwrap();
+        }
+        let sorted = sorter.finish().unwrap();
+        assert_eq!(records.len() as u64, sorted.len());
+        sorted.iter().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn sorts_in_memory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let records = shuffled(1000);
+        let mut want = records.clone();
+        want.sort();
+
+        assert_eq!(want, sort(1 << 20, &records, temp_dir.path()));
+        assert_eq!(0, std::fs::read_dir(temp_dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn sorts_out_of_core() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // 80 bytes per run gives more runs than merged at once
+        let records = shuffled(10 * MAX_FAN_IN as u32 + 3);
+        let mut want = records.clone();
+        want.sort();
+
+        let mut sorter = ExternalSort::new(80, temp_dir.path()).sorter();
+        for record in records.iter() {
+            sorter.push(*record).unwrap();
+        }
+        let sorted = sorter.finish().unwrap();
+        assert_eq!(1, std::fs::read_dir(temp_dir.path()).unwrap().count());
+
+        // Independent cursors over the same records
+        let mut first = sorted.iter().unwrap();
+        let mut second = sorted.iter().unwrap();
+        assert_eq!(want[0], first.next().unwrap().unwrap());
+        assert_eq!(want[1], first.next().unwrap().unwrap());
+        assert_eq!(want[0], second.next().unwrap().unwrap());
+        assert_eq!(
+            want,
+            sorted
+                .iter()
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+
+        drop(sorted);
+        assert_eq!(0, std::fs::read_dir(temp_dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn spills_sorted_records() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let records = shuffled(1000);
+        let mut want = records.clone();
+        want.sort();
+
+        let sorting = ExternalSort::new(1 << 20, temp_dir.path());
+        let mut sorter = sorting.sorter();
+        for record in records.iter() {
+            sorter.push(*record).unwrap();
+        }
+        let sorted = sorting.spill(sorter.finish().unwrap(), 4).unwrap();
+        assert!(matches!(sorted, Sorted::File { buffer_size, .. } if buffer_size == (1 << 20) / 5));
+        assert_eq!(1, std::fs::read_dir(temp_dir.path()).unwrap().count());
+        assert_eq!(1000, sorted.len());
+        assert_eq!(
+            want,
+            sorted
+                .iter()
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!((1 << 20) / 5, sorting.share(5).memory);
+    }
+
+    #[test]
+    fn sorts_with_threads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let records = shuffled(1000);
+        let mut want = records.clone();
+        want.sort();
+
+        for memory in &[80, 1 << 20] {
+            let mut sorter = ExternalSort::new(*memory, temp_dir.path())
+                .threads(3)
+                .sorter();
+            for record in records.iter() {
+                sorter.push(*record).unwrap();
+            }
+            let sorted = sorter.finish().unwrap();
+            assert_eq!(
+                want,
+                sorted
+                    .iter()
+                    .unwrap()
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn sorts_nothing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert_eq!(Vec::<Key>::new(), sort(80, &[], temp_dir.path()));
+    }
+}
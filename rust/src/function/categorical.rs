@@ -0,0 +1,375 @@
+use std::{cmp::Ordering, ops::Sub, str::FromStr};
+
+use itertools::Itertools;
+
+use super::*;
+
+/// Category of a preference table, with how much it is preferred to each
+/// category of the table, in order. Written as label:preferences, such as
+/// `sandy:0,0.5,1`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Category {
+    pub(crate) label: String,
+    pub(crate) preferences: Vec<f64>,
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let wrong = || {
+            format!(
+                "{} is not a category, write it as label:preferences, such as sandy:0,0.5,1",
+                text
+            )
+        };
+        let (label, preferences) = text.split_once(':').ok_or_else(wrong)?;
+        Ok(Self {
+            label: label.trim().to_string(),
+            preferences: preferences
+                .split(',')
+                .map(|preference| preference.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| wrong())?,
+        })
+    }
+}
+
+/// Preference between categories read from a table, the preference of a
+/// category over another being the one in its row and in the column of the
+/// other. Actions are the codes of their categories: the label itself when
+/// it is a number, such as the classes of a land-use raster, and its
+/// position in the table otherwise. Unlike the other functions, categories
+/// are compared by their codes rather than by their difference, and the
+/// lesser action of a pair may be the preferred one.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct CategoricalFunction {
+    /// Categories written as label:preferences over each category, such as sandy:0,0.5,1
+    #[clap(required = true)]
+    pub(crate) categories: Vec<Category>,
+}
+
+impl CategoricalFunction {
+    /// Codes of the categories, in order.
+    fn codes(&self) -> impl Iterator<Item = f64> + '_ {
+        self.categories
+            .iter()
+            .enumerate()
+            .map(|(position, category)| {
+                category
+                    .label
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|code| code.is_finite())
+                    .unwrap_or(position as f64)
+            })
+    }
+
+    /// Labels of the categories with their codes, to read actions written as
+    /// labels.
+    pub(crate) fn scale(&self) -> Scale {
+        Scale {
+            scores: self
+                .categories
+                .iter()
+                .map(|category| category.label.clone())
+                .zip(self.codes())
+                .collect(),
+        }
+    }
+
+    /// Position of the category coded by `action`, if any.
+    fn position<T: From<f64> + PartialOrd>(&self, action: T) -> Option<usize> {
+        self.codes()
+            .position(|code| T::from(code).partial_cmp(&action) == Some(Ordering::Equal))
+    }
+}
+
+impl<T> ComparisonFunction<T> for CategoricalFunction
+where
+    T: From<f64> + PartialOrd + Copy,
+{
+    /// A difference does not tell which categories were compared, so engines
+    /// compare the actions of categorical criteria instead.
+    fn preference(&self, _difference: T, _lower: T) -> T {
+        unreachable!("categories are compared by their codes, not by their difference")
+    }
+
+    /// Preference in the row of the category coded by `arg1` and the column
+    /// of the one coded by `arg2`, none when either is not in the table.
+    fn compare_at(&self, arg1: T, arg2: T, _lower: T) -> T
+    where
+        T: Sub<Output = T> + PartialOrd + Copy,
+    {
+        match (self.position(arg1), self.position(arg2)) {
+            (Some(better), Some(worse)) => T::from(self.categories[better].preferences[worse]),
+            _ => T::from(0.0),
+        }
+    }
+
+    fn compares_actions(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.categories.is_empty() {
+            return Err("categories are missing".to_string());
+        }
+        if let Some(label) = self
+            .categories
+            .iter()
+            .map(|category| &category.label)
+            .duplicates()
+            .next()
+        {
+            return Err(format!("category {} is in the table twice", label));
+        }
+        if let Some((first, second)) = self
+            .codes()
+            .zip(&self.categories)
+            .collect_vec()
+            .into_iter()
+            .tuple_combinations()
+            .find(|((first, _), (second, _))| first == second)
+        {
+            return Err(format!(
+                "categories {} and {} have the same code {}, label them both with numbers or neither",
+                first.1.label, second.1.label, first.0
+            ));
+        }
+        for (position, category) in self.categories.iter().enumerate() {
+            if category.preferences.len() != self.categories.len() {
+                return Err(format!(
+                    "category {} has {} preferences, one for each of the {} categories is needed",
+                    category.label,
+                    category.preferences.len(),
+                    self.categories.len()
+                ));
+            }
+            if let Some((other, preference)) = category
+                .preferences
+                .iter()
+                .zip(&self.categories)
+                .map(|(preference, other)| (&other.label, *preference))
+                .find(|(_, preference)| !(0.0..=1.0).contains(preference))
+            {
+                return Err(format!(
+                    "preference must be between 0 and 1, not {} for {} over {}",
+                    preference, category.label, other
+                ));
+            }
+            if category.preferences[position] != 0.0 {
+                return Err(format!(
+                    "category {} can not be preferred to itself",
+                    category.label
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scores given to the labels of categories, to read actions written as
+/// labels. An ordinal scale is ranked by the preference function of its
+/// criteria as any other number.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(from = "ScaleFile")
+)]
+pub(crate) struct Scale {
+    scores: Vec<(String, f64)>,
+}
+
+/// Scales are written in problem files as a table of scores, or as a list of
+/// labels from the worst to the best.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ScaleFile {
+    Ordered(Vec<String>),
+    Scored(std::collections::BTreeMap<String, f64>),
+}
+
+#[cfg(feature = "serde")]
+impl From<ScaleFile> for Scale {
+    fn from(file: ScaleFile) -> Self {
+        match file {
+            ScaleFile::Ordered(labels) => Self::ordered(labels),
+            ScaleFile::Scored(scores) => Self {
+                scores: scores.into_iter().collect(),
+            },
+        }
+    }
+}
+
+impl Scale {
+    /// Scale scoring `labels` 1, 2, 3... from the worst to the best.
+    fn ordered(labels: Vec<String>) -> Self {
+        Self {
+            scores: labels
+                .into_iter()
+                .enumerate()
+                .map(|(position, label)| (label, (position + 1) as f64))
+                .collect(),
+        }
+    }
+
+    /// Score of a category, None when it is not in the scale.
+    pub(crate) fn score(&self, label: &str) -> Option<f64> {
+        self.scores
+            .iter()
+            .find(|(other, _)| other == label)
+            .map(|(_, score)| *score)
+    }
+
+    /// Labels of the scale, comma separated, to tell which ones there are.
+    pub(crate) fn labels(&self) -> String {
+        self.scores.iter().map(|(label, _)| label).join(", ")
+    }
+
+    /// Checks the scale has some labels, each once, with finite scores.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.scores.is_empty() {
+            return Err("the scale has no labels".to_string());
+        }
+        if let Some(label) = self
+            .scores
+            .iter()
+            .map(|(label, _)| label)
+            .duplicates()
+            .next()
+        {
+            return Err(format!("{} is in the scale twice", label));
+        }
+        match self.scores.iter().find(|(_, score)| !score.is_finite()) {
+            Some((label, score)) => {
+                Err(format!("score of {} must be finite, not {}", label, score))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    /// Reads labels separated by whitespace, written as label:score, or
+    /// without scores from the worst to the best.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words = text.split_whitespace().collect_vec();
+        if !words.iter().any(|word| word.contains(':')) {
+            return Ok(Self::ordered(words.into_iter().map(String::from).collect()));
+        }
+        Ok(Self {
+            scores: words
+                .into_iter()
+                .map(|word| {
+                    let wrong = || format!("{} is not a score, write it as label:score", word);
+                    let (label, score) = word.split_once(':').ok_or_else(wrong)?;
+                    Ok((label.to_string(), score.parse().map_err(|_| wrong())?))
+                })
+                .collect::<Result<_, String>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soils() -> CategoricalFunction {
+        CategoricalFunction {
+            categories: vec![
+                "sandy:0,0.2,0.5".parse().unwrap(),
+                "clay:0.6,0,0.1".parse().unwrap(),
+                "loam:1,0.8,0".parse().unwrap(),
+            ],
+        }
+    }
+
+    #[test]
+    fn reads_categories() {
+        assert_eq!(
+            Ok(Category {
+                label: "sandy".to_string(),
+                preferences: vec![0.0, 0.5, 1.0]
+            }),
+            "sandy:0,0.5,1".parse()
+        );
+        assert!("sandy".parse::<Category>().is_err());
+        assert!("sandy:0,half".parse::<Category>().is_err());
+    }
+
+    #[test]
+    fn looks_categories_up() {
+        let f = soils();
+        assert_eq!(Ok(()), ComparisonFunction::<f64>::validate(&f));
+        // Codes are positions: sandy 0, clay 1, loam 2
+        assert_eq!(0.2, f.compare(0.0, 1.0));
+        assert_eq!(0.6, f.compare(1.0, 0.0));
+        assert_eq!(1.0, f.compare(2.0, 0.0));
+        assert_eq!(0.5, f.compare(0.0, 2.0));
+        assert_eq!(0.0, f.compare(1.0, 1.0));
+        assert_eq!(0.0, f.compare(3.0, 0.0));
+        assert_eq!(Some(2.0), f.scale().score("loam"));
+
+        // Classes labelled by numbers are their own codes
+        let classes = CategoricalFunction {
+            categories: vec!["11:0,1".parse().unwrap(), "21:0.5,0".parse().unwrap()],
+        };
+        assert_eq!(1.0, classes.compare(11.0, 21.0));
+        assert_eq!(0.5, classes.compare(21.0, 11.0));
+        assert_eq!(0.0, classes.compare(1.0, 0.0));
+    }
+
+    #[test]
+    fn validates_tables() {
+        let wrong = |categories: Vec<&str>| {
+            ComparisonFunction::<f64>::validate(&CategoricalFunction {
+                categories: categories.iter().map(|c| c.parse().unwrap()).collect(),
+            })
+            .unwrap_err()
+        };
+        assert_eq!(
+            "category a has 1 preferences, one for each of the 2 categories is needed",
+            wrong(vec!["a:0", "b:0,0"])
+        );
+        assert_eq!(
+            "preference must be between 0 and 1, not 2 for a over b",
+            wrong(vec!["a:0,2", "b:0,0"])
+        );
+        assert_eq!(
+            "category b can not be preferred to itself",
+            wrong(vec!["a:0,0", "b:0,1"])
+        );
+        assert_eq!(
+            "category a is in the table twice",
+            wrong(vec!["a:0,0", "a:0,0"])
+        );
+        assert_eq!(
+            "categories a and 0 have the same code 0, label them both with numbers or neither",
+            wrong(vec!["a:0,0", "0:0,0"])
+        );
+    }
+
+    #[test]
+    fn reads_scales() {
+        let scale: Scale = "poor:1 fair:2 good:5".parse().unwrap();
+        assert_eq!(Some(5.0), scale.score("good"));
+        assert_eq!(None, scale.score("bad"));
+        assert_eq!("poor, fair, good", scale.labels());
+
+        let ordered: Scale = "poor fair good".parse().unwrap();
+        assert_eq!(Some(3.0), ordered.score("good"));
+        assert!("poor:1 fair".parse::<Scale>().is_err());
+        assert!("poor:1 poor:2"
+            .parse::<Scale>()
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+}
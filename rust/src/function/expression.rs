@@ -0,0 +1,637 @@
+use std::{
+    f64::consts,
+    ops::{Add, Div, Mul, Neg, Sub},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use num_traits::Pow;
+
+use super::*;
+
+/// Preference written as a formula of the difference `d` and named
+/// parameters, such as `1 - exp(-(d - t)^2 / (2 * s^2))`. Formulas have
+/// numbers, `+ - * / ^`, parentheses, the constants `e` and `pi` and the
+/// functions `exp`, `sqrt`, `abs`, `min` and `max`. The preference is zero
+/// for differences that are not positive, and is kept between 0 and 1, an
+/// undefined result (NaN) being no preference.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ExpressionFunction {
+    pub(crate) formula: String,
+    /// Parameters of the formula written as name=value, such as s=2
+    #[cfg_attr(feature = "serde", serde(default, with = "as_map"))]
+    pub(crate) parameters: Vec<Parameter>,
+    /// Formula parsed on first use
+    #[clap(skip)]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    compiled: OnceLock<Result<Node, String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Parameter {
+    pub(crate) name: String,
+    pub(crate) value: f64,
+}
+
+impl FromStr for Parameter {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let wrong = || format!("{} is not a parameter, write it as name=value", text);
+        let (name, value) = text.split_once('=').ok_or_else(wrong)?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            value: value.trim().parse().map_err(|_| wrong())?,
+        })
+    }
+}
+
+/// Parameters are saved as a map from their names to their values.
+#[cfg(feature = "serde")]
+mod as_map {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Parameter;
+
+    pub(super) fn serialize<S: Serializer>(
+        parameters: &[Parameter],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        parameters
+            .iter()
+            .map(|parameter| (&parameter.name, parameter.value))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Parameter>, D::Error> {
+        Ok(BTreeMap::<String, f64>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, value)| Parameter { name, value })
+            .collect())
+    }
+}
+
+const FUNCTIONS: [(&str, Call, usize); 5] = [
+    ("exp", Call::Exp, 1),
+    ("sqrt", Call::Sqrt, 1),
+    ("abs", Call::Abs, 1),
+    ("min", Call::Min, 2),
+    ("max", Call::Max, 2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Call {
+    Exp,
+    Sqrt,
+    Abs,
+    Min,
+    Max,
+}
+
+/// Parsed formula, with parameters replaced by their values.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Difference,
+    Negate(Box<Node>),
+    Add(Box<Node>, Box<Node>),
+    Subtract(Box<Node>, Box<Node>),
+    Multiply(Box<Node>, Box<Node>),
+    Divide(Box<Node>, Box<Node>),
+    Power(Box<Node>, Box<Node>),
+    Call(Call, Vec<Node>),
+}
+
+impl Node {
+    /// Only the operations every engine number type has are used: `exp` and
+    /// `sqrt` are powers.
+    fn evaluate<T>(&self, d: T) -> T
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + Copy,
+    {
+        match self {
+            Node::Number(value) => T::from(*value),
+            Node::Difference => d,
+            Node::Negate(a) => -a.evaluate(d),
+            Node::Add(a, b) => a.evaluate(d) + b.evaluate(d),
+            Node::Subtract(a, b) => a.evaluate(d) - b.evaluate(d),
+            Node::Multiply(a, b) => a.evaluate(d) * b.evaluate(d),
+            Node::Divide(a, b) => a.evaluate(d) / b.evaluate(d),
+            Node::Power(a, b) => T::pow(a.evaluate(d), b.evaluate(d)),
+            Node::Call(call, arguments) => {
+                let a = arguments[0].evaluate(d);
+                match call {
+                    Call::Exp => T::pow(T::from(consts::E), a),
+                    Call::Sqrt => T::pow(a, T::from(0.5)),
+                    Call::Abs if a < T::from(0.0) => -a,
+                    Call::Abs => a,
+                    Call::Min | Call::Max => {
+                        let b = arguments[1].evaluate(d);
+                        if (a < b) == (*call == Call::Min) {
+                            a
+                        } else {
+                            b
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Number(f64),
+    Name(&'a str),
+    Symbol(char),
+}
+
+/// Deepest nesting of a formula, in parentheses, signs and operations, so
+/// that neither parsing nor evaluating it overflows the stack.
+const MAX_DEPTH: usize = 256;
+
+fn too_deep() -> String {
+    "formula nested too deeply".to_string()
+}
+
+/// Node parsed, with how deep its operations nest.
+type Parsed = (Node, usize);
+
+/// Depth of an operation on operands as deep as `depth`.
+fn deeper(depth: usize) -> Result<usize, String> {
+    if depth >= MAX_DEPTH {
+        return Err(too_deep());
+    }
+    Ok(depth + 1)
+}
+
+/// Recursive descent parser of a formula, whose errors tell the column of
+/// the character that could not be read.
+struct Parser<'a> {
+    tokens: Vec<(usize, Token<'a>)>,
+    next: usize,
+    end: usize,
+    parameters: &'a [Parameter],
+    /// How many parsing calls are nested in each other.
+    nesting: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(formula: &'a str, parameters: &'a [Parameter]) -> Result<Self, String> {
+        let mut tokens = vec![];
+        let mut chars = formula.char_indices().peekable();
+        // Counted from the previous token, as formulas can be long
+        let (mut column, mut counted) = (1, 0);
+        while let Some((start, c)) = chars.next() {
+            column += formula[counted..start].chars().count();
+            counted = start;
+            if c.is_whitespace() {
+                continue;
+            }
+            let mut end = start + c.len_utf8();
+            let mut take = |accept: &dyn Fn(char) -> bool| {
+                while let Some((at, c)) = chars.peek() {
+                    if !accept(*c) {
+                        break;
+                    }
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+                end
+            };
+            let token = if c.is_ascii_digit() || c == '.' {
+                let mut end = take(&|c| c.is_ascii_digit() || c == '.');
+                // An exponent, as in 1e-3, when digits follow the e and its sign
+                let mut exponent = chars.clone();
+                if matches!(exponent.next(), Some((_, 'e')) | Some((_, 'E'))) {
+                    exponent.next_if(|(_, c)| *c == '+' || *c == '-');
+                    if matches!(exponent.peek(), Some((_, c)) if c.is_ascii_digit()) {
+                        while let Some((at, _)) = exponent.next_if(|(_, c)| c.is_ascii_digit()) {
+                            end = at + 1;
+                        }
+                        chars = exponent;
+                    }
+                }
+                let text = &formula[start..end];
+                Token::Number(
+                    text.parse()
+                        .map_err(|_| format!("{} at column {} is not a number", text, column))?,
+                )
+            } else if c.is_alphabetic() || c == '_' {
+                let end = take(&|c| c.is_alphanumeric() || c == '_');
+                Token::Name(&formula[start..end])
+            } else if "+-*/^(),".contains(c) {
+                Token::Symbol(c)
+            } else {
+                return Err(format!("unexpected {} at column {}", c, column));
+            };
+            tokens.push((column, token));
+        }
+        Ok(Self {
+            tokens,
+            next: 0,
+            end: formula.chars().count() + 1,
+            parameters,
+            nesting: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.next).map(|(_, token)| *token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(column, _)| *column)
+    }
+
+    fn unexpected(&self) -> String {
+        match self.tokens.get(self.next) {
+            Some((column, Token::Number(value))) => {
+                format!("unexpected {} at column {}", value, column)
+            }
+            Some((column, Token::Name(name))) => {
+                format!("unexpected {} at column {}", name, column)
+            }
+            Some((column, Token::Symbol(c))) => format!("unexpected {} at column {}", c, column),
+            None => "unexpected end of formula".to_string(),
+        }
+    }
+
+    fn accept(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(Token::Symbol(symbol)) {
+            self.next += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.accept(symbol) {
+            return Ok(());
+        }
+        Err(format!("{} expected at column {}", symbol, self.column()))
+    }
+
+    fn formula(mut self) -> Result<Node, String> {
+        let (node, _) = self.sum()?;
+        if self.peek().is_some() {
+            return Err(self.unexpected());
+        }
+        Ok(node)
+    }
+
+    /// Parses with `parse` one call deeper, failing before the calls nest
+    /// too deeply.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Parsed, String>) -> Result<Parsed, String> {
+        if self.nesting >= MAX_DEPTH {
+            return Err(too_deep());
+        }
+        self.nesting += 1;
+        let parsed = parse(self);
+        self.nesting -= 1;
+        parsed
+    }
+
+    fn sum(&mut self) -> Result<Parsed, String> {
+        let (mut node, mut depth) = self.product()?;
+        loop {
+            let operation = if self.accept('+') {
+                Node::Add
+            } else if self.accept('-') {
+                Node::Subtract
+            } else {
+                return Ok((node, depth));
+            };
+            let (other, other_depth) = self.product()?;
+            depth = deeper(depth.max(other_depth))?;
+            node = operation(Box::new(node), Box::new(other));
+        }
+    }
+
+    fn product(&mut self) -> Result<Parsed, String> {
+        let (mut node, mut depth) = self.unary()?;
+        loop {
+            let operation = if self.accept('*') {
+                Node::Multiply
+            } else if self.accept('/') {
+                Node::Divide
+            } else {
+                return Ok((node, depth));
+            };
+            let (other, other_depth) = self.unary()?;
+            depth = deeper(depth.max(other_depth))?;
+            node = operation(Box::new(node), Box::new(other));
+        }
+    }
+
+    /// `-d^2` is `-(d^2)`, and powers group from the right.
+    fn unary(&mut self) -> Result<Parsed, String> {
+        if self.accept('-') {
+            let (node, depth) = self.nested(Self::unary)?;
+            return Ok((Node::Negate(Box::new(node)), deeper(depth)?));
+        }
+        let (base, base_depth) = self.atom()?;
+        if self.accept('^') {
+            let (exponent, depth) = self.nested(Self::unary)?;
+            return Ok((
+                Node::Power(Box::new(base), Box::new(exponent)),
+                deeper(base_depth.max(depth))?,
+            ));
+        }
+        Ok((base, base_depth))
+    }
+
+    fn atom(&mut self) -> Result<Parsed, String> {
+        let column = self.column();
+        let token = self.peek();
+        match token {
+            Some(Token::Number(value)) => {
+                self.next += 1;
+                Ok((Node::Number(value), 0))
+            }
+            Some(Token::Symbol('(')) => {
+                self.next += 1;
+                let parsed = self.nested(Self::sum)?;
+                self.expect(')')?;
+                Ok(parsed)
+            }
+            Some(Token::Name(name)) => {
+                self.next += 1;
+                if let Some((_, call, arity)) = FUNCTIONS.iter().find(|(n, _, _)| *n == name) {
+                    self.expect('(')?;
+                    let mut arguments = vec![self.nested(Self::sum)?];
+                    while self.accept(',') {
+                        arguments.push(self.nested(Self::sum)?);
+                    }
+                    self.expect(')')?;
+                    if arguments.len() != *arity {
+                        return Err(format!(
+                            "{} at column {} takes {} arguments, not {}",
+                            name,
+                            column,
+                            arity,
+                            arguments.len()
+                        ));
+                    }
+                    let depth = arguments.iter().map(|(_, depth)| *depth).max();
+                    return Ok((
+                        Node::Call(*call, arguments.into_iter().map(|(node, _)| node).collect()),
+                        deeper(depth.unwrap_or(0))?,
+                    ));
+                }
+                let node = match name {
+                    "d" => Node::Difference,
+                    "e" => Node::Number(consts::E),
+                    "pi" => Node::Number(consts::PI),
+                    _ => match self.parameters.iter().find(|p| p.name == name) {
+                        Some(parameter) => Node::Number(parameter.value),
+                        None => return Err(format!("unknown name {} at column {}", name, column)),
+                    },
+                };
+                Ok((node, 0))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+/// Parses `formula`, checking every parameter can be told apart from `d`, the
+/// constants and the functions.
+fn compile(formula: &str, parameters: &[Parameter]) -> Result<Node, String> {
+    for (index, parameter) in parameters.iter().enumerate() {
+        let name = parameter.name.as_str();
+        let is_name = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !is_name {
+            return Err(format!("{:?} can not name a parameter", name));
+        }
+        if ["d", "e", "pi"].contains(&name) || FUNCTIONS.iter().any(|(n, _, _)| *n == name) {
+            return Err(format!("{} is reserved and can not name a parameter", name));
+        }
+        if parameters[..index].iter().any(|other| other.name == name) {
+            return Err(format!("parameter {} is given twice", name));
+        }
+        if !parameter.value.is_finite() {
+            return Err(format!(
+                "parameter {} must be finite, not {}",
+                name, parameter.value
+            ));
+        }
+    }
+    Parser::new(formula, parameters)?.formula()
+}
+
+impl ExpressionFunction {
+    fn compiled(&self) -> &Result<Node, String> {
+        self.compiled
+            .get_or_init(|| compile(&self.formula, &self.parameters))
+    }
+}
+
+impl<T> ComparisonFunction<T> for ExpressionFunction
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + Copy,
+{
+    fn preference(&self, diff: T, _lower: T) -> T {
+        let zero = T::from(0.0);
+        if diff <= zero {
+            return zero;
+        }
+        let node = match self.compiled() {
+            Ok(node) => node,
+            Err(reason) => panic!("invalid formula {}: {}", self.formula, reason),
+        };
+
+        let one = T::from(1.0);
+        let preference = node.evaluate(diff);
+        if preference > one {
+            return one;
+        }
+        if preference > zero {
+            return preference;
+        }
+        // Negative or NaN
+        zero
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.compiled() {
+            Ok(_) => Ok(()),
+            Err(reason) => Err(format!("formula {}: {}", self.formula, reason)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(formula: &str, parameters: &[(&str, f64)]) -> ExpressionFunction {
+        ExpressionFunction {
+            formula: formula.to_string(),
+            parameters: parameters
+                .iter()
+                .map(|(name, value)| Parameter {
+                    name: name.to_string(),
+                    value: *value,
+                })
+                .collect(),
+            compiled: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn same_as_gaussian() {
+        let gaussian = GaussianFunction {
+            std_dev: 1.5.into(),
+        };
+        let formula = function("1 - exp(-d^2 / (2 * s^2))", &[("s", 1.5)]);
+        assert!(ComparisonFunction::<f64>::validate(&formula).is_ok());
+        for diff in &[-1.0, 0.0, 0.3, 1.0, 2.5, 10.0] {
+            let want: f64 = gaussian.preference(*diff, 0.0);
+            let got: f64 = formula.preference(*diff, 0.0);
+            assert!((want - got).abs() < 1e-12, "{}: {} {}", diff, want, got);
+        }
+    }
+
+    #[test]
+    fn evaluates_formulas() {
+        let preference = |formula: &str, diff: f64| -> f64 {
+            function(formula, &[("k", 2.0), ("c", 1.0)]).preference(diff, 0.0)
+        };
+
+        assert_eq!(0.5, preference("1 / (1 + exp(-k * (d - c)))", 1.0));
+        assert_eq!(0.25, preference("d^2 / 4", 1.0));
+        assert_eq!(0.25, preference("2^-2", 3.0));
+        assert_eq!(0.75, preference("-(-3) / 4", 3.0));
+        assert_eq!(0.5, preference("min(d, c) - abs(c - 1.5)", 3.0));
+        assert_eq!(0.5, preference("max(d / 8, sqrt(0.25))", 2.0));
+        assert_eq!(0.5, preference("5e-1", 3.0));
+        assert_eq!(0.25, preference("2.5E+2 / 1e3", 3.0));
+        assert_eq!(0.5, preference("1E0 - 5e-1", 3.0));
+        // Kept between 0 and 1
+        assert_eq!(1.0, preference("d", 3.0));
+        assert_eq!(0.0, preference("-d", 3.0));
+        assert_eq!(0.0, preference("sqrt(c - d)", 3.0));
+    }
+
+    #[test]
+    fn tells_what_is_wrong() {
+        let validate = |formula: &str, parameters: &[(&str, f64)]| {
+            ComparisonFunction::<f64>::validate(&function(formula, parameters)).unwrap_err()
+        };
+
+        assert_eq!(
+            "formula 1 - d / q: unknown name q at column 9",
+            validate("1 - d / q", &[])
+        );
+        assert_eq!(
+            "formula d * (2: ) expected at column 7",
+            validate("d * (2", &[])
+        );
+        assert_eq!(
+            "formula d $ 2: unexpected $ at column 3",
+            validate("d $ 2", &[])
+        );
+        assert_eq!(
+            "formula d 2: unexpected 2 at column 3",
+            validate("d 2", &[])
+        );
+        assert_eq!(
+            "formula d *: unexpected end of formula",
+            validate("d *", &[])
+        );
+        assert_eq!(
+            "formula min(d): min at column 1 takes 2 arguments, not 1",
+            validate("min(d)", &[])
+        );
+        assert_eq!(
+            "formula 1.2.3: 1.2.3 at column 1 is not a number",
+            validate("1.2.3", &[])
+        );
+        assert_eq!(
+            "formula 2e-: unexpected e at column 2",
+            validate("2e-", &[])
+        );
+        // Told instead of overflowing the stack
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("{}d{}", open.repeat(depth), close.repeat(depth))
+        };
+        for formula in &[
+            nested(10_000, "(", ")"),
+            nested(10_000, "-", ""),
+            nested(10_000, "abs(", ")"),
+            nested(10_000, "d^", ""),
+            nested(10_000, "d+", ""),
+            nested(10_000, "d*", ""),
+        ] {
+            let err = validate(formula, &[]);
+            assert!(err.ends_with(": formula nested too deeply"), "{}", err);
+        }
+        assert!(ComparisonFunction::<f64>::validate(&function(
+            &nested(MAX_DEPTH - 1, "(", ")"),
+            &[]
+        ))
+        .is_ok());
+        assert_eq!(
+            "formula d / e: e is reserved and can not name a parameter",
+            validate("d / e", &[("e", 2.0)])
+        );
+        assert_eq!(
+            "formula d / s: parameter s is given twice",
+            validate("d / s", &[("s", 2.0), ("s", 3.0)])
+        );
+        assert_eq!(
+            "formula d / s: parameter s must be finite, not NaN",
+            validate("d / s", &[("s", f64::NAN)])
+        );
+    }
+
+    #[test]
+    fn reads_command_line() {
+        let f = "expression 1-exp(-d/s) s=2"
+            .parse::<PreferenceFunction>()
+            .unwrap();
+        assert!((1.0 - (-0.5f64).exp() - f.compare(1.0, 0.0)).abs() < 1e-12);
+        assert_eq!(
+            "s:2 is not a parameter, write it as name=value",
+            "s:2".parse::<Parameter>().unwrap_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn saves_parameters_as_map() {
+        let saved = r#"{"type":"expression","formula":"d / s","parameters":{"s":4.0}}"#;
+        let read: PreferenceFunction = serde_json::from_str(saved).unwrap();
+        assert_eq!(0.25, read.compare(1.0, 0.0));
+        assert_eq!(saved, serde_json::to_string(&read).unwrap());
+    }
+}
@@ -1,51 +1,464 @@
 use std::{
     f64::consts,
-    ops::{Div, Mul, Neg, Sub},
+    ops::{Add, Div, Mul, Neg, Sub},
+    str::FromStr,
 };
 
+use itertools::Itertools;
 use num_traits::Pow;
 
 use super::*;
 
-#[derive(Clap, Debug)]
+pub(crate) mod categorical;
+pub(crate) mod expression;
+pub(crate) mod threshold;
+pub(crate) use categorical::{CategoricalFunction, Scale};
+pub(crate) use expression::ExpressionFunction;
+pub(crate) use threshold::{Resolved, Spread, Threshold};
+
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct UsualFunction {}
 
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct QuasiFunction {
-    pub(crate) l: f64,
+    pub(crate) l: Threshold,
 }
 
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct LinearFunction {
     #[clap()]
-    pub(crate) m: f64,
+    pub(crate) m: Threshold,
 }
 
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct LevelFunction {
-    pub(crate) weak_treshold: f64,
-    pub(crate) weak_area: f64,
+    pub(crate) weak_treshold: Threshold,
+    pub(crate) weak_area: Threshold,
 }
 
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct LinearWithIndeferenceFunction {
-    pub(crate) indiference_threshold: f64,
-    pub(crate) linear_area: f64,
+    pub(crate) indiference_threshold: Threshold,
+    pub(crate) linear_area: Threshold,
 }
 
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct GaussianFunction {
-    pub(crate) std_dev: f64,
+    pub(crate) std_dev: Threshold,
+}
+
+/// Gaussian that gives no preference up to the indifference threshold `q`
+/// and grows with scale `s` beyond it, as in Visual PROMETHEE. It has no
+/// linear pieces, so the fast version compares its pairs as the vanilla one
+/// does, while the umbu version still compares only distinct values. A zero
+/// `q` gives the plain Gaussian.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct GaussianWithIndifferenceFunction {
+    pub(crate) q: Threshold,
+    pub(crate) s: Threshold,
+}
+
+/// Preference drawn by an expert as breakpoints, linear between them. It
+/// grows from no preference at difference zero up to the first breakpoint and
+/// stays at the preference of the last one for greater differences.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct PiecewiseLinearFunction {
+    /// Breakpoints written as difference:preference, such as 2:0.3
+    #[clap(required = true)]
+    pub(crate) breakpoints: Vec<Breakpoint>,
+}
+
+/// S-shaped preference, none up to the indifference threshold `q` and full
+/// from the preference threshold `p` on, following a logistic curve between
+/// them.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct LogisticFunction {
+    pub(crate) q: Threshold,
+    pub(crate) p: Threshold,
+}
+
+/// V-shape raised to `exponent`, full from `p` on: convex above 1, concave
+/// below 1 and linear at 1.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct PowerFunction {
+    pub(crate) p: Threshold,
+    pub(crate) exponent: f64,
+}
+
+/// Preference saturating as `1 - exp(-d / scale)`.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ExponentialFunction {
+    pub(crate) scale: Threshold,
+}
+
+/// Level function with any number of levels: the preference of a step holds
+/// for differences greater than its threshold, up to the next step.
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct MultiLevelFunction {
+    /// Steps written as threshold:preference, such as 2:0.5
+    #[clap(required = true)]
+    pub(crate) steps: Vec<Breakpoint>,
+}
+
+/// Preference reached at a difference, saved as a `[difference, preference]`
+/// pair.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "(f64, f64)", into = "(f64, f64)")
+)]
+pub(crate) struct Breakpoint {
+    pub(crate) difference: f64,
+    pub(crate) preference: f64,
+}
+
+impl From<(f64, f64)> for Breakpoint {
+    fn from((difference, preference): (f64, f64)) -> Self {
+        Self {
+            difference,
+            preference,
+        }
+    }
+}
+
+impl From<Breakpoint> for (f64, f64) {
+    fn from(breakpoint: Breakpoint) -> Self {
+        (breakpoint.difference, breakpoint.preference)
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let wrong = || {
+            format!(
+                "{} is not a breakpoint, write it as difference:preference",
+                text
+            )
+        };
+        let (difference, preference) = text.split_once(':').ok_or_else(wrong)?;
+        Ok(Self {
+            difference: difference.trim().parse().map_err(|_| wrong())?,
+            preference: preference.trim().parse().map_err(|_| wrong())?,
+        })
+    }
+}
+
+/// Linear piece of a preference function: for differences between `from`
+/// and `to` the preference is `intercept + slope * difference`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Segment {
+    pub(crate) from: f64,
+    pub(crate) from_closed: bool,
+    pub(crate) to: f64,
+    pub(crate) to_closed: bool,
+    pub(crate) intercept: f64,
+    pub(crate) slope: f64,
+}
+
+impl Segment {
+    fn new(from: f64, from_closed: bool, to: f64, to_closed: bool) -> Self {
+        Self {
+            from,
+            from_closed,
+            to,
+            to_closed,
+            intercept: 0.0,
+            slope: 0.0,
+        }
+    }
+
+    fn constant(mut self, value: f64) -> Self {
+        self.intercept = value;
+        self
+    }
+
+    fn linear(mut self, intercept: f64, slope: f64) -> Self {
+        self.intercept = intercept;
+        self.slope = slope;
+        self
+    }
+}
+
+/// Keeps the part of the segments over positive differences, as every
+/// function prefers nothing when the difference is zero or negative.
+fn positive_part(segments: Vec<Segment>) -> Vec<Segment> {
+    segments
+        .into_iter()
+        .filter(|segment| segment.to > 0.0)
+        .map(|mut segment| {
+            if segment.from <= 0.0 {
+                segment.from = 0.0;
+                segment.from_closed = false;
+            }
+            segment
+        })
+        .filter(|segment| {
+            segment.from < segment.to
+                || (segment.from == segment.to && segment.from_closed && segment.to_closed)
+        })
+        .collect()
 }
 
 pub(crate) trait ComparisonFunction<T> {
-    fn compare(&self, arg1: T, arg2: T) -> T;
+    /// Preference for an action `difference` better than another, zero when
+    /// it is not better at all. Variable thresholds are evaluated at `lower`,
+    /// the lesser of the two actions of the pair as read, before any goal
+    /// aims them. Both directions of a pair come from a single difference and
+    /// its lesser action, so engines can evaluate each pair once.
+    fn preference(&self, difference: T, lower: T) -> T;
+
+    /// Preference of `arg1` over `arg2`, actions that are their own lesser
+    /// action as read.
+    #[cfg(test)]
+    fn compare(&self, arg1: T, arg2: T) -> T
+    where
+        T: Sub<Output = T> + PartialOrd + Copy,
+    {
+        let lower = if arg1 < arg2 { arg1 } else { arg2 };
+        self.compare_at(arg1, arg2, lower)
+    }
+
+    /// Preference of `arg1` over `arg2`, aimed at a goal, whose lesser action
+    /// as read is `lower`.
+    fn compare_at(&self, arg1: T, arg2: T, lower: T) -> T
+    where
+        T: Sub<Output = T> + PartialOrd + Copy,
+    {
+        self.preference(arg1 - arg2, lower)
+    }
+
+    /// Non zero pieces of the function, when it is piecewise-linear with the
+    /// same thresholds for every pair. The sort based engines need them to
+    /// sum many preferences at once.
+    fn segments(&self) -> Option<Vec<Segment>> {
+        None
+    }
+
+    /// Whether the actions of a pair are compared themselves rather than by
+    /// their difference, as categories are, either of them possibly being
+    /// preferred. Engines then get both preferences of a pair from `compare`
+    /// and never call `preference`.
+    fn compares_actions(&self) -> bool {
+        false
+    }
+
+    /// Checks the parameters of the function, telling which one is wrong.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Resolves the thresholds relative to the actions of the criteria,
+    /// telling what each was resolved to.
+    fn resolve(&mut self, _actions: &Spread) -> Vec<Resolved> {
+        vec![]
+    }
+
+    /// Thresholds of the function, by name.
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![]
+    }
+
+    /// Checks no threshold is still relative to the actions, as preferences
+    /// can only be computed once every threshold is resolved.
+    fn check_resolved(&self) -> Result<(), String> {
+        resolved(&self.thresholds())
+    }
+}
+
+/// Thresholds where preference starts may be zero, as then every difference
+/// counts.
+fn non_negative(name: &str, value: f64) -> Result<(), String> {
+    if value >= 0.0 && value.is_finite() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} must be finite and non-negative, not {}",
+        name, value
+    ))
+}
+
+/// Checks there is some breakpoint, their differences increase and their
+/// preferences, between 0 and 1, do not decrease.
+fn monotone(what: &str, breakpoints: &[Breakpoint]) -> Result<(), String> {
+    if breakpoints.is_empty() {
+        return Err(format!("{} are missing", what));
+    }
+    let mut previous: Option<&Breakpoint> = None;
+    for breakpoint in breakpoints.iter() {
+        let Breakpoint {
+            difference,
+            preference,
+        } = *breakpoint;
+        non_negative("difference", difference)?;
+        if !(0.0..=1.0).contains(&preference) {
+            return Err(format!(
+                "preference must be between 0 and 1, not {} at {}",
+                preference, difference
+            ));
+        }
+        if let Some(previous) = previous {
+            if difference <= previous.difference {
+                return Err(format!(
+                    "differences must increase, but {} comes after {}",
+                    difference, previous.difference
+                ));
+            }
+            if preference < previous.preference {
+                return Err(format!(
+                    "preferences can not decrease, but {} at {} comes after {} at {}",
+                    preference, difference, previous.preference, previous.difference
+                ));
+            }
+        }
+        previous = Some(breakpoint);
+    }
+    Ok(())
+}
+
+/// Checks none of the named `thresholds` is relative to the actions.
+fn resolved(thresholds: &[(&str, &Threshold)]) -> Result<(), String> {
+    match thresholds
+        .iter()
+        .find(|(_, threshold)| threshold.is_relative())
+    {
+        Some((name, threshold)) => Err(format!(
+            "threshold {} of {} was not resolved against the actions",
+            threshold, name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Resolves the relative ones among the named `thresholds`.
+fn resolve_all(actions: &Spread, thresholds: Vec<(&'static str, &mut Threshold)>) -> Vec<Resolved> {
+    thresholds
+        .into_iter()
+        .filter_map(|(name, threshold)| threshold.resolve(name, actions))
+        .collect()
+}
+
+/// Widths that preference grows over can not be zero, as they divide.
+fn positive(name: &str, value: f64) -> Result<(), String> {
+    if value > 0.0 && value.is_finite() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} must be finite and positive, not {}",
+        name, value
+    ))
+}
+
+#[derive(Clap, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "kebab-case")
+)]
+pub(crate) enum PreferenceFunction {
+    #[clap()]
+    Usual(UsualFunction),
+    #[clap()]
+    Quasi(QuasiFunction),
+    #[clap()]
+    Linear(LinearFunction),
+    #[clap()]
+    Level(LevelFunction),
+    #[clap()]
+    LinearWithIndeference(LinearWithIndeferenceFunction),
+    #[clap()]
+    Gaussian(GaussianFunction),
+    #[clap()]
+    PiecewiseLinear(PiecewiseLinearFunction),
+    #[clap()]
+    Expression(ExpressionFunction),
+    #[clap()]
+    Logistic(LogisticFunction),
+    #[clap()]
+    Power(PowerFunction),
+    #[clap()]
+    Exponential(ExponentialFunction),
+    #[clap()]
+    MultiLevel(MultiLevelFunction),
+    #[clap()]
+    GaussianWithIndifference(GaussianWithIndifferenceFunction),
+    #[clap()]
+    Categorical(CategoricalFunction),
+}
+
+/// Preference function written as on the command line, such as `linear 100`.
+#[derive(Clap, Debug)]
+struct FunctionWords {
+    #[clap(subcommand)]
+    function: PreferenceFunction,
+}
+
+impl FromStr for PreferenceFunction {
+    type Err = String;
+
+    fn from_str(words: &str) -> Result<Self, Self::Err> {
+        let words = std::iter::once("function").chain(words.split_whitespace());
+        match FunctionWords::try_parse_from(words) {
+            Ok(words) => Ok(words.function),
+            Err(err) => {
+                // Only the reason, without clap's usage
+                let message = err.to_string();
+                let reason = message
+                    .lines()
+                    .map(str::trim)
+                    .take_while(|line| !line.starts_with("USAGE") && !line.starts_with("If you"))
+                    .filter(|line| !line.is_empty())
+                    .join(" ");
+                Err(reason.trim_start_matches("error: ").to_string())
+            }
+        }
+    }
+}
+
+impl PreferenceFunction {
+    /// Whether some threshold is relative to the actions, and so can only be
+    /// known once every action is.
+    pub(crate) fn is_relative(&self) -> bool {
+        ComparisonFunction::<f64>::thresholds(self)
+            .iter()
+            .any(|(_, threshold)| threshold.is_relative())
+    }
+
+    /// Scale reading the actions of a criteria written as labels: the given
+    /// `scale` of an ordinal criteria, or the codes of the categories of a
+    /// categorical one. None when actions are numbers.
+    pub(crate) fn labels(&self, scale: Option<Scale>) -> Result<Option<Scale>, String> {
+        match (self, scale) {
+            (PreferenceFunction::Categorical(_), Some(_)) => Err(
+                "categorical criteria take their labels from their table, not from a scale"
+                    .to_string(),
+            ),
+            (PreferenceFunction::Categorical(f), None) => Ok(Some(f.scale())),
+            (_, Some(scale)) => scale.validate().map(|_| Some(scale)),
+            (_, None) => Ok(None),
+        }
+    }
 }
 
 impl<T> ComparisonFunction<T> for PreferenceFunction
 where
     T: From<f64>
         + Neg<Output = T>
+        + Add<Output = T>
         + Sub<Output = T>
         + Div<Output = T>
         + Mul<Output = T>
@@ -53,112 +466,362 @@ where
         + PartialOrd
         + std::marker::Copy,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
+    fn preference(&self, difference: T, lower: T) -> T {
+        match self {
+            PreferenceFunction::Usual(f) => f.preference(difference, lower),
+            PreferenceFunction::Quasi(f) => f.preference(difference, lower),
+            PreferenceFunction::Linear(f) => f.preference(difference, lower),
+            PreferenceFunction::Level(f) => f.preference(difference, lower),
+            PreferenceFunction::LinearWithIndeference(f) => f.preference(difference, lower),
+            PreferenceFunction::Gaussian(f) => f.preference(difference, lower),
+            PreferenceFunction::PiecewiseLinear(f) => f.preference(difference, lower),
+            PreferenceFunction::Expression(f) => f.preference(difference, lower),
+            PreferenceFunction::Logistic(f) => f.preference(difference, lower),
+            PreferenceFunction::Power(f) => f.preference(difference, lower),
+            PreferenceFunction::Exponential(f) => f.preference(difference, lower),
+            PreferenceFunction::MultiLevel(f) => f.preference(difference, lower),
+            PreferenceFunction::GaussianWithIndifference(f) => f.preference(difference, lower),
+            PreferenceFunction::Categorical(f) => f.preference(difference, lower),
+        }
+    }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        match self {
+            PreferenceFunction::Usual(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Quasi(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Linear(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Level(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::LinearWithIndeference(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Gaussian(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::PiecewiseLinear(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Expression(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Logistic(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Power(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Exponential(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::MultiLevel(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::GaussianWithIndifference(f) => ComparisonFunction::<T>::segments(f),
+            PreferenceFunction::Categorical(f) => ComparisonFunction::<T>::segments(f),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            PreferenceFunction::Usual(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Quasi(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Linear(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Level(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::LinearWithIndeference(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Gaussian(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::PiecewiseLinear(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Expression(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Logistic(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Power(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Exponential(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::MultiLevel(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::GaussianWithIndifference(f) => ComparisonFunction::<T>::validate(f),
+            PreferenceFunction::Categorical(f) => ComparisonFunction::<T>::validate(f),
+        }
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        match self {
+            PreferenceFunction::Usual(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Quasi(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Linear(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Level(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::LinearWithIndeference(f) => {
+                ComparisonFunction::<T>::resolve(f, actions)
+            }
+            PreferenceFunction::Gaussian(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::PiecewiseLinear(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Expression(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Logistic(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Power(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::Exponential(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::MultiLevel(f) => ComparisonFunction::<T>::resolve(f, actions),
+            PreferenceFunction::GaussianWithIndifference(f) => {
+                ComparisonFunction::<T>::resolve(f, actions)
+            }
+            PreferenceFunction::Categorical(f) => ComparisonFunction::<T>::resolve(f, actions),
+        }
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
         match self {
-            PreferenceFunction::Usual(f) => f.compare(arg1, arg2),
-            PreferenceFunction::Quasi(f) => f.compare(arg1, arg2),
-            PreferenceFunction::Linear(f) => f.compare(arg1, arg2),
-            PreferenceFunction::Level(f) => f.compare(arg1, arg2),
-            PreferenceFunction::LinearWithIndeference(f) => f.compare(arg1, arg2),
-            PreferenceFunction::Gaussian(f) => f.compare(arg1, arg2),
+            PreferenceFunction::Usual(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Quasi(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Linear(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Level(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::LinearWithIndeference(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Gaussian(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::PiecewiseLinear(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Expression(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Logistic(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Power(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::Exponential(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::MultiLevel(f) => ComparisonFunction::<T>::thresholds(f),
+            PreferenceFunction::GaussianWithIndifference(f) => {
+                ComparisonFunction::<T>::thresholds(f)
+            }
+            PreferenceFunction::Categorical(f) => ComparisonFunction::<T>::thresholds(f),
         }
     }
+
+    fn check_resolved(&self) -> Result<(), String> {
+        match self {
+            PreferenceFunction::Usual(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Quasi(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Linear(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Level(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::LinearWithIndeference(f) => {
+                ComparisonFunction::<T>::check_resolved(f)
+            }
+            PreferenceFunction::Gaussian(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::PiecewiseLinear(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Expression(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Logistic(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Power(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::Exponential(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::MultiLevel(f) => ComparisonFunction::<T>::check_resolved(f),
+            PreferenceFunction::GaussianWithIndifference(f) => {
+                ComparisonFunction::<T>::check_resolved(f)
+            }
+            PreferenceFunction::Categorical(f) => ComparisonFunction::<T>::check_resolved(f),
+        }
+    }
+
+    fn compare_at(&self, arg1: T, arg2: T, lower: T) -> T
+    where
+        T: Sub<Output = T> + PartialOrd + Copy,
+    {
+        match self {
+            PreferenceFunction::Categorical(f) => f.compare_at(arg1, arg2, lower),
+            f => f.preference(arg1 - arg2, lower),
+        }
+    }
+
+    fn compares_actions(&self) -> bool {
+        matches!(self, PreferenceFunction::Categorical(_))
+    }
 }
 
 impl<T> ComparisonFunction<T> for UsualFunction
 where
     T: From<f64> + Sub<Output = T> + PartialOrd,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, _lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
         T::from(1.0)
     }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        Some(vec![
+            Segment::new(0.0, false, f64::INFINITY, false).constant(1.0)
+        ])
+    }
 }
 
 impl<T> ComparisonFunction<T> for QuasiFunction
 where
-    T: From<f64> + Sub<Output = T> + PartialOrd,
+    T: From<f64> + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + PartialOrd,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
-        if diff < T::from(self.l) {
+        if diff < self.l.at(lower) {
             return zero;
         }
         T::from(1.0)
     }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        Some(positive_part(vec![Segment::new(
+            self.l.fixed()?,
+            true,
+            f64::INFINITY,
+            false,
+        )
+        .constant(1.0)]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.l.check("l", false)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("l", &mut self.l)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("l", &self.l)]
+    }
 }
 
 impl<T> ComparisonFunction<T> for LinearFunction
 where
-    T: From<f64> + Sub<Output = T> + PartialOrd + Div<Output = T>,
+    T: From<f64>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + PartialOrd
+        + Div<Output = T>
+        + Copy,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
-        let p = T::from(self.m);
+        let p = self.m.at(lower);
         if diff < p {
             return diff / p;
         }
         T::from(1.0)
     }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        let m = self.m.fixed()?;
+        Some(positive_part(vec![
+            Segment::new(0.0, false, m, false).linear(0.0, 1.0 / m),
+            Segment::new(m, true, f64::INFINITY, false).constant(1.0),
+        ]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.m.check("m", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("m", &mut self.m)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("m", &self.m)]
+    }
 }
 
 impl<T> ComparisonFunction<T> for LevelFunction
 where
-    T: From<f64> + Sub<Output = T> + PartialOrd,
+    T: From<f64> + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + PartialOrd + Copy,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
-        if diff <= T::from(self.weak_treshold) {
+        let weak_treshold = self.weak_treshold.at(lower);
+        if diff <= weak_treshold {
             return zero;
         }
-        if diff <= T::from(self.weak_treshold + self.weak_area) {
+        if diff <= weak_treshold + self.weak_area.at(lower) {
             return T::from(0.5);
         }
         T::from(1.0)
     }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        let weak_treshold = self.weak_treshold.fixed()?;
+        let strict = weak_treshold + self.weak_area.fixed()?;
+        Some(positive_part(vec![
+            Segment::new(weak_treshold, false, strict, true).constant(0.5),
+            Segment::new(strict, false, f64::INFINITY, false).constant(1.0),
+        ]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.weak_treshold.check("weak_treshold", false)?;
+        self.weak_area.check("weak_area", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(
+            actions,
+            vec![
+                ("weak_treshold", &mut self.weak_treshold),
+                ("weak_area", &mut self.weak_area),
+            ],
+        )
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![
+            ("weak_treshold", &self.weak_treshold),
+            ("weak_area", &self.weak_area),
+        ]
+    }
 }
 
 impl<T> ComparisonFunction<T> for LinearWithIndeferenceFunction
 where
-    T: From<f64> + Sub<Output = T> + PartialOrd + Div<Output = T>,
+    T: From<f64>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Div<Output = T>
+        + PartialOrd
+        + Copy,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
 
-        if diff <= T::from(self.indiference_threshold) {
+        let q = self.indiference_threshold.at(lower);
+        let linear_area = self.linear_area.at(lower);
+        if diff <= q {
             return zero;
         }
 
-        if diff <= T::from(self.indiference_threshold + self.linear_area) {
-            return (diff - T::from(self.indiference_threshold)) / T::from(self.linear_area);
+        if diff <= q + linear_area {
+            return (diff - q) / linear_area;
         }
         T::from(1.0)
     }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        let q = self.indiference_threshold.fixed()?;
+        let linear_area = self.linear_area.fixed()?;
+        let p = q + linear_area;
+        Some(positive_part(vec![
+            Segment::new(q, false, p, true).linear(-q / linear_area, 1.0 / linear_area),
+            Segment::new(p, false, f64::INFINITY, false).constant(1.0),
+        ]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.indiference_threshold
+            .check("indiference_threshold", false)?;
+        self.linear_area.check("linear_area", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(
+            actions,
+            vec![
+                ("indiference_threshold", &mut self.indiference_threshold),
+                ("linear_area", &mut self.linear_area),
+            ],
+        )
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![
+            ("indiference_threshold", &self.indiference_threshold),
+            ("linear_area", &self.linear_area),
+        ]
+    }
 }
 
 impl<T> ComparisonFunction<T> for GaussianFunction
 where
     T: From<f64>
+        + Add<Output = T>
         + Sub<Output = T>
         + Neg<Output = T>
         + PartialOrd
@@ -167,19 +830,302 @@ where
         + Pow<T, Output = T>
         + Copy,
 {
-    fn compare(&self, arg1: T, arg2: T) -> T {
-        let diff = arg1 - arg2;
+    fn preference(&self, diff: T, lower: T) -> T {
         let zero = T::from(0.0);
         if diff <= zero {
             return zero;
         }
 
+        let std_dev = self.std_dev.at(lower);
+        T::from(1.0)
+            - T::pow(
+                T::from(consts::E),
+                -(diff * diff) / (T::from(2.0) * std_dev * std_dev),
+            )
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.std_dev.check("std_dev", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("std_dev", &mut self.std_dev)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("std_dev", &self.std_dev)]
+    }
+}
+
+impl<T> ComparisonFunction<T> for GaussianWithIndifferenceFunction
+where
+    T: From<f64>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Neg<Output = T>
+        + PartialOrd
+        + Mul<Output = T>
+        + Div<Output = T>
+        + Pow<T, Output = T>
+        + Copy,
+{
+    fn preference(&self, diff: T, lower: T) -> T {
+        let q = self.q.at(lower);
+        if diff <= q || diff <= T::from(0.0) {
+            return T::from(0.0);
+        }
+
+        let beyond = diff - q;
+        let s = self.s.at(lower);
         T::from(1.0)
             - T::pow(
                 T::from(consts::E),
-                -(diff * diff) / T::from(2.0 * self.std_dev * self.std_dev),
+                -(beyond * beyond) / (T::from(2.0) * s * s),
             )
     }
+
+    fn validate(&self) -> Result<(), String> {
+        self.q.check("q", false)?;
+        self.s.check("s", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("q", &mut self.q), ("s", &mut self.s)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("q", &self.q), ("s", &self.s)]
+    }
+}
+
+impl<T> ComparisonFunction<T> for PiecewiseLinearFunction
+where
+    T: From<f64> + Sub<Output = T> + Mul<Output = T> + Div<Output = T> + PartialOrd + Copy,
+{
+    fn preference(&self, diff: T, _lower: T) -> T {
+        let zero = T::from(0.0);
+        if diff <= zero {
+            return zero;
+        }
+
+        let mut previous = Breakpoint::from((0.0, 0.0));
+        for breakpoint in self.breakpoints.iter() {
+            if diff <= T::from(breakpoint.difference) {
+                let width = breakpoint.difference - previous.difference;
+                let rise = breakpoint.preference - previous.preference;
+                return T::from(previous.preference)
+                    - (T::from(previous.difference) - diff) / T::from(width) * T::from(rise);
+            }
+            previous = *breakpoint;
+        }
+        T::from(previous.preference)
+    }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        let mut segments = vec![];
+        let mut previous = Breakpoint::from((0.0, 0.0));
+        for breakpoint in self.breakpoints.iter() {
+            // A breakpoint at zero only sets where the next piece starts
+            if breakpoint.difference > previous.difference {
+                let slope = (breakpoint.preference - previous.preference)
+                    / (breakpoint.difference - previous.difference);
+                segments.push(
+                    Segment::new(previous.difference, false, breakpoint.difference, true)
+                        .linear(previous.preference - slope * previous.difference, slope),
+                );
+            }
+            previous = *breakpoint;
+        }
+        segments.push(
+            Segment::new(previous.difference, false, f64::INFINITY, false)
+                .constant(previous.preference),
+        );
+        Some(positive_part(
+            segments
+                .into_iter()
+                .filter(|segment| segment.intercept != 0.0 || segment.slope != 0.0)
+                .collect(),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        monotone("breakpoints", &self.breakpoints)
+    }
+}
+
+impl LogisticFunction {
+    /// Checks `p` stays above `q` for every action that is not negative. A
+    /// threshold relative to the actions is checked once resolved.
+    fn check_anchors(&self) -> Result<(), String> {
+        match (self.q.line(), self.p.line()) {
+            (Some((0.0, q)), Some((0.0, p))) => positive("p - q", p - q),
+            (Some((alpha_q, beta_q)), Some((alpha_p, beta_p))) => {
+                positive("p - q intercept", beta_p - beta_q)?;
+                non_negative("p - q slope", alpha_p - alpha_q)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T> ComparisonFunction<T> for LogisticFunction
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + Copy,
+{
+    fn preference(&self, diff: T, lower: T) -> T {
+        let zero = T::from(0.0);
+        let (q, p) = (self.q.at(lower), self.p.at(lower));
+        if diff <= q {
+            return zero;
+        }
+        if diff >= p {
+            return T::from(1.0);
+        }
+
+        // Sigmoid reaching 1% and 99% at the anchors, stretched to 0 and 1
+        let steepness = T::from(2.0 * 99f64.ln()) / (p - q);
+        let center = (q + p) / T::from(2.0);
+        let sigmoid = T::from(1.0)
+            / (T::from(1.0) + T::pow(T::from(consts::E), -(steepness * (diff - center))));
+        (sigmoid - T::from(0.01)) / T::from(0.98)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.q.check("q", false)?;
+        self.p.check("p", true)?;
+        self.check_anchors()
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("q", &mut self.q), ("p", &mut self.p)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("q", &self.q), ("p", &self.p)]
+    }
+
+    fn check_resolved(&self) -> Result<(), String> {
+        resolved(&ComparisonFunction::<T>::thresholds(self))?;
+        self.check_anchors()
+    }
+}
+
+impl<T> ComparisonFunction<T> for PowerFunction
+where
+    T: From<f64>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Div<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + Copy,
+{
+    fn preference(&self, diff: T, lower: T) -> T {
+        let zero = T::from(0.0);
+        if diff <= zero {
+            return zero;
+        }
+        let p = self.p.at(lower);
+        if diff < p {
+            return T::pow(diff / p, T::from(self.exponent));
+        }
+        T::from(1.0)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.p.check("p", true)?;
+        positive("exponent", self.exponent)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("p", &mut self.p)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("p", &self.p)]
+    }
+}
+
+impl<T> ComparisonFunction<T> for ExponentialFunction
+where
+    T: From<f64>
+        + Add<Output = T>
+        + Neg<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Div<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + Copy,
+{
+    fn preference(&self, diff: T, lower: T) -> T {
+        let zero = T::from(0.0);
+        if diff <= zero {
+            return zero;
+        }
+        T::from(1.0) - T::pow(T::from(consts::E), -(diff / self.scale.at(lower)))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.scale.check("scale", true)
+    }
+
+    fn resolve(&mut self, actions: &Spread) -> Vec<Resolved> {
+        resolve_all(actions, vec![("scale", &mut self.scale)])
+    }
+
+    fn thresholds(&self) -> Vec<(&'static str, &Threshold)> {
+        vec![("scale", &self.scale)]
+    }
+}
+
+impl<T> ComparisonFunction<T> for MultiLevelFunction
+where
+    T: From<f64> + PartialOrd,
+{
+    fn preference(&self, diff: T, _lower: T) -> T {
+        let mut preference = 0.0;
+        for step in self.steps.iter() {
+            if diff <= T::from(step.difference) {
+                break;
+            }
+            preference = step.preference;
+        }
+        T::from(preference)
+    }
+
+    fn segments(&self) -> Option<Vec<Segment>> {
+        let ends = self
+            .steps
+            .iter()
+            .skip(1)
+            .map(|step| step.difference)
+            .chain(std::iter::once(f64::INFINITY));
+        Some(positive_part(
+            self.steps
+                .iter()
+                .zip(ends)
+                .filter(|(step, _)| step.preference != 0.0)
+                .map(|(step, end)| {
+                    Segment::new(step.difference, false, end, end.is_finite())
+                        .constant(step.preference)
+                })
+                .collect(),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        monotone("steps", &self.steps)
+    }
 }
 
 #[cfg(test)]
@@ -203,7 +1149,7 @@ mod tests {
 
     #[test]
     fn quasi_function() {
-        let a = QuasiFunction { l: 4.0 };
+        let a = QuasiFunction { l: 4.0.into() };
         assert_eq!(0.00, a.compare(0.0, 0.0));
         assert_eq!(0.00, a.compare(1.0, 0.0));
         assert_eq!(0.00, a.compare(2.0, 0.0));
@@ -214,7 +1160,7 @@ mod tests {
 
     #[test]
     fn linear_function() {
-        let a = LinearFunction { m: 4.0 };
+        let a = LinearFunction { m: 4.0.into() };
         assert_eq!(0.00, a.compare(0.0, 0.0));
         assert_eq!(0.25, a.compare(1.0, 0.0));
         assert_eq!(0.50, a.compare(2.0, 0.0));
@@ -226,8 +1172,8 @@ mod tests {
     #[test]
     fn level_function() {
         let a = LevelFunction {
-            weak_treshold: 2.0,
-            weak_area: 2.0,
+            weak_treshold: 2.0.into(),
+            weak_area: 2.0.into(),
         };
         assert_eq!(0.0, a.compare(0.0, 0.0));
         assert_eq!(0.0, a.compare(1.0, 0.0));
@@ -240,8 +1186,8 @@ mod tests {
     #[test]
     fn linear_with_indiference_function() {
         let a = LinearWithIndeferenceFunction {
-            indiference_threshold: 2.0,
-            linear_area: 2.0,
+            indiference_threshold: 2.0.into(),
+            linear_area: 2.0.into(),
         };
         assert_eq!(0.0, a.compare(0.0, 0.0));
         assert_eq!(0.0, a.compare(1.0, 0.0));
@@ -253,10 +1199,333 @@ mod tests {
 
     #[test]
     fn gaussian_function() {
-        let a = GaussianFunction { std_dev: 1.0 };
+        let a = GaussianFunction {
+            std_dev: 1.0.into(),
+        };
         assert_eq!(0.0, a.compare(0.0, 0.0));
         assert_eq_float(0.117503097, a.compare(0.5, 0.0), 1e-9);
         assert_eq_float(0.393469340, a.compare(1.0, 0.0), 1e-9);
         assert_eq_float(0.864664716, a.compare(2.0, 0.0), 1e-9);
     }
+
+    #[test]
+    fn validates_parameters() {
+        let validate = |f: PreferenceFunction| ComparisonFunction::<f64>::validate(&f);
+
+        assert!(validate(PreferenceFunction::Quasi(QuasiFunction { l: 0.0.into() })).is_ok());
+        assert_eq!(
+            Err("l must be finite and non-negative, not -1".to_string()),
+            validate(PreferenceFunction::Quasi(QuasiFunction {
+                l: (-1.0).into()
+            }))
+        );
+        assert_eq!(
+            Err("m must be finite and positive, not inf".to_string()),
+            validate(PreferenceFunction::Linear(LinearFunction {
+                m: f64::INFINITY.into()
+            }))
+        );
+        assert_eq!(
+            Err("weak_area must be finite and positive, not 0".to_string()),
+            validate(PreferenceFunction::Level(LevelFunction {
+                weak_treshold: 2.0.into(),
+                weak_area: 0.0.into(),
+            }))
+        );
+        assert_eq!(
+            Err("indiference_threshold must be finite and non-negative, not NaN".to_string()),
+            validate(PreferenceFunction::LinearWithIndeference(
+                LinearWithIndeferenceFunction {
+                    indiference_threshold: f64::NAN.into(),
+                    linear_area: 2.0.into(),
+                }
+            ))
+        );
+        assert_eq!(
+            Err("std_dev must be finite and positive, not -2".to_string()),
+            validate(PreferenceFunction::Gaussian(GaussianFunction {
+                std_dev: (-2.0).into()
+            }))
+        );
+    }
+
+    #[test]
+    fn piecewise_linear_function() {
+        let a = "piecewise-linear 0:0 2:0.3 5:0.9 8:1"
+            .parse::<PreferenceFunction>()
+            .unwrap();
+        assert_eq!(0.0, a.compare(0.0, 1.0));
+        assert_eq!(0.0, a.compare(0.0, 0.0));
+        assert_eq_float(0.15, a.compare(1.0, 0.0), 1e-12);
+        assert_eq_float(0.3, a.compare(2.0, 0.0), 1e-12);
+        assert_eq_float(0.7, a.compare(4.0, 0.0), 1e-12);
+        assert_eq_float(0.9, a.compare(5.0, 0.0), 1e-12);
+        assert_eq_float(0.9 + 0.1 / 3.0, a.compare(6.0, 0.0), 1e-12);
+        assert_eq!(1.0, a.compare(8.0, 0.0));
+        assert_eq!(1.0, a.compare(20.0, 0.0));
+        assert!(ComparisonFunction::<f64>::validate(&a).is_ok());
+
+        // Starts with a jump, as the preference at zero itself is none
+        let b = PiecewiseLinearFunction {
+            breakpoints: vec![Breakpoint::from((0.0, 0.25)), Breakpoint::from((4.0, 0.75))],
+        };
+        assert_eq!(0.0, b.compare(0.0, 0.0));
+        assert_eq!(0.375, b.compare(1.0, 0.0));
+        assert_eq!(0.75, b.compare(5.0, 0.0));
+        assert_eq!(
+            Some(vec![
+                Segment::new(0.0, false, 4.0, true).linear(0.25, 0.125),
+                Segment::new(4.0, false, f64::INFINITY, false).constant(0.75),
+            ]),
+            ComparisonFunction::<f64>::segments(&b)
+        );
+    }
+
+    #[test]
+    fn validates_breakpoints() {
+        let validate = |breakpoints: &str| {
+            let f = format!("piecewise-linear {}", breakpoints)
+                .parse::<PreferenceFunction>()
+                .unwrap();
+            ComparisonFunction::<f64>::validate(&f)
+        };
+
+        assert_eq!(
+            Err("preference must be between 0 and 1, not 1.5 at 8".to_string()),
+            validate("2:0.3 8:1.5")
+        );
+        assert_eq!(
+            Err("differences must increase, but 2 comes after 5".to_string()),
+            validate("5:0.3 2:0.9")
+        );
+        assert_eq!(
+            Err("preferences can not decrease, but 0.5 at 5 comes after 0.9 at 2".to_string()),
+            validate("2:0.9 5:0.5")
+        );
+        let negative = PiecewiseLinearFunction {
+            breakpoints: vec![Breakpoint::from((-1.0, 0.0)), Breakpoint::from((2.0, 1.0))],
+        };
+        assert_eq!(
+            Err("difference must be finite and non-negative, not -1".to_string()),
+            ComparisonFunction::<f64>::validate(&negative)
+        );
+        assert!("piecewise-linear".parse::<PreferenceFunction>().is_err());
+        assert_eq!(
+            "2-0.3 is not a breakpoint, write it as difference:preference",
+            "2-0.3".parse::<Breakpoint>().unwrap_err()
+        );
+    }
+
+    #[test]
+    fn logistic_function() {
+        let a = LogisticFunction {
+            q: 2.0.into(),
+            p: 6.0.into(),
+        };
+        assert_eq!(0.0, a.compare(0.0, 0.0));
+        assert_eq!(0.0, a.compare(2.0, 0.0));
+        assert_eq_float(0.5, a.compare(4.0, 0.0), 1e-12);
+        assert_eq_float(1.0, a.compare(3.0, 0.0) + a.compare(5.0, 0.0), 1e-12);
+        assert!(a.compare(2.1, 0.0) < 0.01);
+        assert!(a.compare(5.9, 0.0) > 0.99);
+        assert_eq!(1.0, a.compare(6.0, 0.0));
+        assert_eq!(1.0, a.compare(7.0, 0.0));
+    }
+
+    #[test]
+    fn power_function() {
+        let convex = PowerFunction {
+            p: 4.0.into(),
+            exponent: 2.0,
+        };
+        assert_eq!(0.0, convex.compare(0.0, 0.0));
+        assert_eq!(0.0625, convex.compare(1.0, 0.0));
+        assert_eq!(0.25, convex.compare(2.0, 0.0));
+        assert_eq!(1.0, convex.compare(4.0, 0.0));
+        assert_eq!(1.0, convex.compare(5.0, 0.0));
+
+        let concave = PowerFunction {
+            p: 4.0.into(),
+            exponent: 0.5,
+        };
+        assert_eq!(0.5, concave.compare(1.0, 0.0));
+        assert_eq!(0.0, concave.compare(0.0, 1.0));
+    }
+
+    #[test]
+    fn exponential_function() {
+        let a = ExponentialFunction { scale: 2.0.into() };
+        assert_eq!(0.0, a.compare(0.0, 0.0));
+        assert_eq!(0.0, a.compare(0.0, 1.0));
+        assert_eq_float(0.393469340, a.compare(1.0, 0.0), 1e-9);
+        assert_eq_float(0.632120559, a.compare(2.0, 0.0), 1e-9);
+        assert_eq_float(0.981684361, a.compare(8.0, 0.0), 1e-9);
+    }
+
+    #[test]
+    fn multi_level_function() {
+        let a = "multi-level 1:0.25 3:0.5 4:1"
+            .parse::<PreferenceFunction>()
+            .unwrap();
+        assert_eq!(0.0, a.compare(0.0, 0.0));
+        assert_eq!(0.0, a.compare(1.0, 0.0));
+        assert_eq!(0.25, a.compare(2.0, 0.0));
+        assert_eq!(0.25, a.compare(3.0, 0.0));
+        assert_eq!(0.5, a.compare(4.0, 0.0));
+        assert_eq!(1.0, a.compare(5.0, 0.0));
+
+        // Generalizes the level function
+        let level = LevelFunction {
+            weak_treshold: 2.0.into(),
+            weak_area: 2.0.into(),
+        };
+        let levels = MultiLevelFunction {
+            steps: vec![Breakpoint::from((2.0, 0.5)), Breakpoint::from((4.0, 1.0))],
+        };
+        for diff in 0..7 {
+            assert_eq!(
+                level.compare(diff as f64, 0.0),
+                levels.compare(diff as f64, 0.0)
+            );
+        }
+        assert_eq!(
+            ComparisonFunction::<f64>::segments(&level),
+            ComparisonFunction::<f64>::segments(&levels)
+        );
+    }
+
+    #[test]
+    fn validates_new_shapes() {
+        let validate = |f: PreferenceFunction| ComparisonFunction::<f64>::validate(&f);
+
+        assert_eq!(
+            Err("p - q must be finite and positive, not 0".to_string()),
+            validate(PreferenceFunction::Logistic(LogisticFunction {
+                q: 3.0.into(),
+                p: 3.0.into()
+            }))
+        );
+        assert_eq!(
+            Err("exponent must be finite and positive, not 0".to_string()),
+            validate(PreferenceFunction::Power(PowerFunction {
+                p: 3.0.into(),
+                exponent: 0.0,
+            }))
+        );
+        assert_eq!(
+            Err("scale must be finite and positive, not -1".to_string()),
+            validate(PreferenceFunction::Exponential(ExponentialFunction {
+                scale: (-1.0).into(),
+            }))
+        );
+        assert_eq!(
+            Err("differences must increase, but 2 comes after 2".to_string()),
+            validate("multi-level 2:0.5 2:1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn gaussian_with_indifference_function() {
+        let a = GaussianWithIndifferenceFunction {
+            q: 1.0.into(),
+            s: 1.0.into(),
+        };
+        assert_eq!(0.0, a.compare(0.0, 0.0));
+        assert_eq!(0.0, a.compare(1.0, 0.0));
+        assert_eq!(0.0, a.compare(0.0, 2.0));
+        assert_eq_float(0.117503097, a.compare(1.5, 0.0), 1e-9);
+        assert_eq_float(0.393469340, a.compare(2.0, 0.0), 1e-9);
+        assert_eq_float(0.864664716, a.compare(3.0, 0.0), 1e-9);
+
+        // Without indifference it is the plain Gaussian
+        let plain = GaussianFunction {
+            std_dev: 1.5.into(),
+        };
+        let shifted = GaussianWithIndifferenceFunction {
+            q: 0.0.into(),
+            s: 1.5.into(),
+        };
+        for diff in &[-1.0, 0.0, 0.5, 1.0, 4.0] {
+            assert_eq!(plain.compare(*diff, 0.0), shifted.compare(*diff, 0.0));
+        }
+
+        assert_eq!(
+            Err("s must be finite and positive, not 0".to_string()),
+            ComparisonFunction::<f64>::validate(&GaussianWithIndifferenceFunction {
+                q: 1.0.into(),
+                s: 0.0.into()
+            })
+        );
+    }
+
+    #[test]
+    fn variable_thresholds() {
+        let a = LinearFunction {
+            m: "0.1x+5".parse().unwrap(),
+        };
+        // Evaluated at the lesser action of the pair, in either direction
+        assert_eq!(1.0, a.compare(30.0, 20.0));
+        assert_eq!(0.0, a.compare(20.0, 30.0));
+        assert_eq!(0.5, a.compare(160.0, 150.0));
+        assert_eq!(0.0, a.compare(150.0, 160.0));
+        assert_eq!(None, ComparisonFunction::<f64>::segments(&a));
+
+        let a = LevelFunction {
+            weak_treshold: "0.5x".parse().unwrap(),
+            weak_area: 2.0.into(),
+        };
+        assert_eq!(0.0, a.compare(14.0, 10.0));
+        assert_eq!(0.5, a.compare(16.0, 10.0));
+        assert_eq!(1.0, a.compare(18.0, 10.0));
+        assert_eq!(1.0, a.compare(4.0, 0.0));
+
+        let crossing = LogisticFunction {
+            q: "0.2x+2".parse().unwrap(),
+            p: "0.1x+3".parse().unwrap(),
+        };
+        assert_eq!(
+            Err("p - q slope must be finite and non-negative, not -0.1".to_string()),
+            ComparisonFunction::<f64>::validate(&crossing)
+        );
+        let validate = |q: &str, p: &str| {
+            ComparisonFunction::<f64>::validate(&LogisticFunction {
+                q: q.parse().unwrap(),
+                p: p.parse().unwrap(),
+            })
+        };
+        assert_eq!(
+            Err("p - q intercept must be finite and positive, not -2".to_string()),
+            validate("5", "0.1x+3")
+        );
+        assert_eq!(
+            Err("p - q slope must be finite and non-negative, not -0.1".to_string()),
+            validate("0.1x+1", "5")
+        );
+        assert_eq!(Ok(()), validate("2", "0.1x+3"));
+    }
+
+    #[test]
+    fn tells_relative_functions() {
+        let relative = |words: &str| words.parse::<PreferenceFunction>().unwrap().is_relative();
+        assert!(relative("linear 10%"));
+        assert!(relative("logistic 1 q90"));
+        assert!(!relative("linear 10"));
+        assert!(!relative("power 0.1x+5 2"));
+        assert!(!relative("usual"));
+    }
+
+    #[test]
+    fn checks_logistic_anchors_once_resolved() {
+        let mut a = LogisticFunction {
+            q: "50%".parse().unwrap(),
+            p: 4.0.into(),
+        };
+        assert_eq!(Ok(()), ComparisonFunction::<f64>::validate(&a));
+        // q is 5, half the range
+        ComparisonFunction::<f64>::resolve(&mut a, &Spread::new(vec![0.0, 10.0]));
+        assert_eq!(
+            Err("p - q must be finite and positive, not -1".to_string()),
+            ComparisonFunction::<f64>::check_resolved(&a)
+        );
+    }
 }
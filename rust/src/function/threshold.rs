@@ -0,0 +1,415 @@
+use std::{
+    fmt::{self, Display},
+    ops::{Add, Mul},
+    str::FromStr,
+};
+
+/// Threshold of a preference function, given as a number or relative to the
+/// actions of its criteria. Relative thresholds are resolved against the
+/// actions into absolute ones before ranking.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Threshold {
+    Absolute(f64),
+    /// Percentage of the range of the actions, written `10%`
+    Range(f64),
+    /// Multiple of the standard deviation of the actions, written `1.5sd`
+    StdDev(f64),
+    /// Percentile of the differences between every pair of actions, written
+    /// `q90`
+    Quantile(f64),
+    /// Grows with the lesser action of each pair `x`, as read whatever the
+    /// goal, as `alpha * x + beta`, written `0.1x+5`
+    Variable {
+        alpha: f64,
+        beta: f64,
+    },
+}
+
+impl From<f64> for Threshold {
+    fn from(value: f64) -> Self {
+        Threshold::Absolute(value)
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let number = |number: &str| {
+            number.trim().parse::<f64>().map_err(|_| {
+                format!(
+                    "{} is not a threshold, write a number, a percentage of the range \
+                     such as 10%, standard deviations such as 1.5sd, a percentile of \
+                     the differences such as q90 or a line such as 0.1x+5",
+                    text
+                )
+            })
+        };
+        if let Some((alpha, beta)) = text.split_once('x') {
+            return Ok(Threshold::Variable {
+                alpha: if alpha.is_empty() {
+                    1.0
+                } else {
+                    number(alpha)?
+                },
+                beta: if beta.is_empty() { 0.0 } else { number(beta)? },
+            });
+        }
+        if let Some(percentage) = text.strip_suffix('%') {
+            return Ok(Threshold::Range(number(percentage)?));
+        }
+        if let Some(deviations) = text.strip_suffix("sd") {
+            return Ok(Threshold::StdDev(number(deviations)?));
+        }
+        if let Some(percentile) = text.strip_prefix('q') {
+            return Ok(Threshold::Quantile(number(percentile)?));
+        }
+        Ok(Threshold::Absolute(number(text)?))
+    }
+}
+
+impl Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Threshold::Absolute(value) => write!(f, "{}", value),
+            Threshold::Range(percentage) => write!(f, "{}%", percentage),
+            Threshold::StdDev(deviations) => write!(f, "{}sd", deviations),
+            Threshold::Quantile(percentile) => write!(f, "q{}", percentile),
+            Threshold::Variable { alpha, beta } => write!(f, "{}x{:+}", alpha, beta),
+        }
+    }
+}
+
+/// Absolute thresholds are saved as numbers and relative ones as text.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Threshold {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Threshold::Absolute(value) => serializer.serialize_f64(*value),
+            relative => serializer.collect_str(relative),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Threshold {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Number(f64),
+            Text(String),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::Number(value) => Ok(Threshold::Absolute(value)),
+            Written::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Threshold {
+    /// Value of the threshold for a pair whose lesser action is `lower`.
+    /// Relative thresholds must have been resolved, which the engines check
+    /// before ranking.
+    pub(crate) fn at<T>(&self, lower: T) -> T
+    where
+        T: From<f64> + Add<Output = T> + Mul<Output = T>,
+    {
+        match self {
+            Threshold::Absolute(value) => T::from(*value),
+            Threshold::Variable { alpha, beta } => T::from(*alpha) * lower + T::from(*beta),
+            relative => panic!(
+                "threshold {} was not resolved against the actions",
+                relative
+            ),
+        }
+    }
+
+    /// Value of the threshold when it is the same for every pair, which
+    /// relative thresholds are not until resolved.
+    pub(crate) fn fixed(&self) -> Option<f64> {
+        match self {
+            Threshold::Absolute(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Whether the threshold grows with the lesser action of each pair.
+    pub(crate) fn is_variable(&self) -> bool {
+        matches!(self, Threshold::Variable { .. })
+    }
+
+    /// Slope and intercept of the threshold over the lesser action of each
+    /// pair, which relative thresholds have only once resolved.
+    pub(crate) fn line(&self) -> Option<(f64, f64)> {
+        match self {
+            Threshold::Absolute(value) => Some((0.0, *value)),
+            Threshold::Variable { alpha, beta } => Some((*alpha, *beta)),
+            _ => None,
+        }
+    }
+
+    /// Whether the threshold is relative to the actions, and so must be
+    /// resolved before ranking.
+    pub(crate) fn is_relative(&self) -> bool {
+        matches!(
+            self,
+            Threshold::Range(_) | Threshold::StdDev(_) | Threshold::Quantile(_)
+        )
+    }
+
+    /// Checks the threshold `name` is finite and not negative, or positive
+    /// when `positive`. Relative thresholds are checked again once resolved.
+    pub(crate) fn check(&self, name: &str, positive: bool) -> Result<(), String> {
+        let (value, what) = match self {
+            Threshold::Absolute(value) => (*value, ""),
+            Threshold::Range(percentage) => (*percentage, " percentage of the range"),
+            Threshold::StdDev(deviations) => (*deviations, " standard deviations"),
+            Threshold::Variable { alpha, beta } => {
+                // Meant for actions that are not negative, such as costs
+                if !(alpha.is_finite() && *alpha >= 0.0) {
+                    return Err(format!(
+                        "{} slope must be finite and non-negative, not {}",
+                        name, alpha
+                    ));
+                }
+                (*beta, " intercept")
+            }
+            Threshold::Quantile(percentile) => {
+                if !(0.0..=100.0).contains(percentile) {
+                    return Err(format!(
+                        "{} percentile must be between 0 and 100, not {}",
+                        name, percentile
+                    ));
+                }
+                return Ok(());
+            }
+        };
+        if !value.is_finite() || value < 0.0 || (positive && value == 0.0) {
+            let sign = if positive { "positive" } else { "non-negative" };
+            return Err(format!(
+                "{}{} must be finite and {}, not {}",
+                name, what, sign, value
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replaces a relative threshold by its value on `actions`, telling what
+    /// it was resolved to.
+    pub(crate) fn resolve(&mut self, name: &'static str, actions: &Spread) -> Option<Resolved> {
+        let value = match *self {
+            Threshold::Absolute(_) | Threshold::Variable { .. } => return None,
+            Threshold::Range(percentage) => percentage / 100.0 * actions.range(),
+            Threshold::StdDev(deviations) => deviations * actions.std_dev(),
+            Threshold::Quantile(percentile) => actions.difference_percentile(percentile),
+        };
+        let resolved = Resolved {
+            parameter: name.to_string(),
+            threshold: *self,
+            value,
+        };
+        *self = Threshold::Absolute(value);
+        Some(resolved)
+    }
+}
+
+/// Relative threshold of a preference function and the value it was given.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Resolved {
+    pub(crate) parameter: String,
+    pub(crate) threshold: Threshold,
+    pub(crate) value: f64,
+}
+
+impl Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = ", self.parameter)?;
+        match self.threshold {
+            Threshold::Absolute(_) | Threshold::Variable { .. } => {}
+            Threshold::Range(percentage) => write!(f, "{}% of the range = ", percentage)?,
+            Threshold::StdDev(deviations) => write!(f, "{} standard deviations = ", deviations)?,
+            Threshold::Quantile(percentile) => {
+                write!(f, "percentile {} of the differences = ", percentile)?
+            }
+        }
+        write!(f, "{}", self.value)
+    }
+}
+
+/// Actions of a criteria sorted, leaving the missing ones out, to measure
+/// how spread they are.
+pub(crate) struct Spread {
+    sorted: Vec<f64>,
+}
+
+impl Spread {
+    pub(crate) fn new<I: IntoIterator<Item = f64>>(actions: I) -> Self {
+        let mut sorted = actions
+            .into_iter()
+            .filter(|action| !action.is_nan())
+            .collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Self { sorted }
+    }
+
+    fn range(&self) -> f64 {
+        match (self.sorted.first(), self.sorted.last()) {
+            (Some(min), Some(max)) => max - min,
+            _ => f64::NAN,
+        }
+    }
+
+    /// Population standard deviation.
+    fn std_dev(&self) -> f64 {
+        let n = self.sorted.len() as f64;
+        let mean = self.sorted.iter().sum::<f64>() / n;
+        let variance = self
+            .sorted
+            .iter()
+            .map(|action| (action - mean) * (action - mean))
+            .sum::<f64>()
+            / n;
+        variance.sqrt()
+    }
+
+    /// Pairs of actions at most `difference` apart.
+    fn pairs_within(&self, difference: f64) -> usize {
+        let mut pairs = 0;
+        let mut first = 0;
+        for (last, action) in self.sorted.iter().enumerate() {
+            while action - self.sorted[first] > difference {
+                first += 1;
+            }
+            pairs += last - first;
+        }
+        pairs
+    }
+
+    /// Nearest rank `percentile` of the differences between every pair of
+    /// actions, found without listing the pairs: the bits of non-negative
+    /// floats sort as the floats do, so they are searched as integers.
+    fn difference_percentile(&self, percentile: f64) -> f64 {
+        let n = self.sorted.len();
+        let pairs = n * n.saturating_sub(1) / 2;
+        if pairs == 0 {
+            return f64::NAN;
+        }
+        let rank = ((percentile / 100.0 * pairs as f64).ceil() as usize).clamp(1, pairs);
+
+        let (mut low, mut high) = (0u64, self.range().to_bits());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.pairs_within(f64::from_bits(middle)) >= rank {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        f64::from_bits(low)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_thresholds() {
+        assert_eq!(Ok(Threshold::Absolute(2.5)), "2.5".parse());
+        assert_eq!(Ok(Threshold::Range(10.0)), "10%".parse());
+        assert_eq!(Ok(Threshold::StdDev(1.5)), "1.5sd".parse());
+        assert_eq!(Ok(Threshold::Quantile(90.0)), "q90".parse());
+        assert!("ten%".parse::<Threshold>().is_err());
+        assert_eq!(
+            Ok(Threshold::Variable {
+                alpha: 0.1,
+                beta: -5.0
+            }),
+            "0.1x-5".parse()
+        );
+        assert_eq!(
+            Ok(Threshold::Variable {
+                alpha: 1.0,
+                beta: 0.0
+            }),
+            "x".parse()
+        );
+        assert!("0.1x+five".parse::<Threshold>().is_err());
+        for text in &["2.5", "10%", "1.5sd", "q90", "0.1x+5"] {
+            assert_eq!(*text, text.parse::<Threshold>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn resolves_against_actions() {
+        let resolve = |threshold: &str, actions: &[f64]| {
+            let mut threshold = threshold.parse::<Threshold>().unwrap();
+            let resolved = threshold.resolve("m", &Spread::new(actions.iter().copied()));
+            (
+                threshold.fixed().unwrap(),
+                resolved.map(|resolved| resolved.to_string()),
+            )
+        };
+        let actions = [4.0, f64::NAN, 1.0, 2.0, 9.0];
+
+        assert_eq!((3.0, None), resolve("3", &actions));
+        assert_eq!(
+            (4.0, Some("m = 50% of the range = 4".to_string())),
+            resolve("50%", &actions)
+        );
+        assert_eq!(
+            (6.0, Some("m = 2 standard deviations = 6".to_string())),
+            resolve("2sd", &[1.0, 7.0, 1.0, 7.0])
+        );
+        // Differences are 1, 2, 3, 3, 5, 7 and 8
+        assert_eq!(
+            (
+                3.0,
+                Some("m = percentile 50 of the differences = 3".to_string())
+            ),
+            resolve("q50", &actions)
+        );
+        assert_eq!(1.0, resolve("q0", &actions).0);
+        assert_eq!(5.0, resolve("q60", &actions).0);
+        assert_eq!(8.0, resolve("q100", &actions).0);
+    }
+
+    #[test]
+    fn checks_relative_thresholds() {
+        assert_eq!(
+            Err("m percentage of the range must be finite and positive, not 0".to_string()),
+            Threshold::Range(0.0).check("m", true)
+        );
+        assert_eq!(
+            Err("l standard deviations must be finite and non-negative, not -1".to_string()),
+            Threshold::StdDev(-1.0).check("l", false)
+        );
+        assert_eq!(
+            Err("m percentile must be between 0 and 100, not 101".to_string()),
+            Threshold::Quantile(101.0).check("m", true)
+        );
+        assert!(Threshold::Quantile(0.0).check("m", true).is_ok());
+        assert_eq!(
+            Err("m intercept must be finite and positive, not 0".to_string()),
+            "0.1x".parse::<Threshold>().unwrap().check("m", true)
+        );
+        assert_eq!(
+            Err("q slope must be finite and non-negative, not -0.1".to_string()),
+            "-0.1x+1".parse::<Threshold>().unwrap().check("q", false)
+        );
+    }
+
+    #[test]
+    fn varies_with_the_lesser_action() {
+        let threshold = "0.1x+5".parse::<Threshold>().unwrap();
+        assert_eq!(None, threshold.fixed());
+        assert_eq!(5.0, threshold.at(0.0));
+        assert_eq!(15.0, threshold.at(100.0));
+        assert_eq!(Some(2.5), Threshold::Absolute(2.5).fixed());
+        assert_eq!(2.5, Threshold::Absolute(2.5).at(100.0));
+    }
+}
@@ -1,35 +1,103 @@
+mod external_sort;
 mod function;
+mod matrix;
+#[cfg(feature = "serde")]
+mod problem;
 mod promethee;
+mod raster;
+mod xmcda;
 
 use crate::promethee::*;
-use clap::Clap;
+use clap::{AppSettings, Clap};
+use itertools::Itertools;
+use std::path::{Path, PathBuf};
 
 #[derive(Clap, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 enum PrometheeImplementation {
     #[clap(alias = "van")]
+    #[cfg_attr(feature = "serde", serde(alias = "van"))]
     Vanilla,
     #[clap(alias = "ff")]
+    #[cfg_attr(feature = "serde", serde(alias = "ff"))]
     Fast,
+    #[clap(alias = "um")]
+    #[cfg_attr(feature = "serde", serde(alias = "um"))]
+    Umbu,
 }
 
-#[derive(Clap, Debug)]
-enum PreferenceFunction {
-    #[clap()]
-    Usual(UsualFunction),
-    #[clap()]
-    Quasi(QuasiFunction),
-    #[clap()]
-    Linear(LinearFunction),
-    #[clap()]
-    Level(LevelFunction),
-    #[clap()]
-    LinearWithIndeference(LinearWithIndeferenceFunction),
-    #[clap()]
-    Gaussian(GaussianFunction),
+impl PrometheeImplementation {
+    /// Ranks criteria read only once each with this implementation. Only
+    /// the vanilla version lets criteria interact.
+    fn rank_streamed<S, E>(
+        &self,
+        sentinel: f64,
+        threads: usize,
+        criteria: Vec<StreamedCriteria<S, PreferenceFunction>>,
+        interactions: Vec<Interaction>,
+        temp_dir: &Path,
+    ) -> Result<Streamed, E>
+    where
+        S: IntoIterator<Item = Result<f64, E>>,
+        E: From<std::io::Error> + From<PrometheeError>,
+    {
+        if !interactions.is_empty() && !matches!(self, PrometheeImplementation::Vanilla) {
+            return Err(PrometheeError::InvalidInteraction(
+                "only the vanilla version ranks interacting criteria".to_string(),
+            )
+            .into());
+        }
+        match self {
+            PrometheeImplementation::Vanilla => promethee::vanilla::Vanilla::new(true)
+                .sentinel(sentinel)
+                .with_threads(threads)
+                .with_interactions(interactions)
+                .rank_streamed(criteria, temp_dir),
+            PrometheeImplementation::Fast => promethee::fast::Fast::new(true)
+                .sentinel(sentinel)
+                .rank_streamed(criteria, temp_dir),
+            PrometheeImplementation::Umbu => promethee::umbu::Umbu::new(true)
+                .sentinel(sentinel)
+                .rank_streamed(criteria, temp_dir),
+        }
+    }
 }
 
+/// Actions of a criteria, read only once.
+type Actions = Box<dyn Iterator<Item = Result<f64, raster::RasterError>>>;
+
 #[derive(Clap, Debug)]
+// Weights and thresholds may be negative numbers, to be told what is wrong
+// with them rather than taken for flags
+#[clap(global_setting = AppSettings::AllowNegativeNumbers)]
 struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    #[clap(about = "Rank the alternatives of a criteria")]
+    Rank(RankOpts),
+    #[clap(about = "Weighted net flow of a criteria TIFF bigger than memory")]
+    Fast(FastOpts),
+    #[clap(about = "Add up per criteria flow TIFFs into a single one")]
+    Merge(MergeOpts),
+    #[clap(about = "Rank the decision matrix of a CSV file")]
+    Csv(CsvOpts),
+    #[clap(about = "Rank the alternatives of XMCDA files and write their flows as XMCDA")]
+    Xmcda(XmcdaOpts),
+    #[cfg(feature = "serde")]
+    #[clap(about = "Rank the criteria of a TOML or JSON problem file")]
+    Run(RunOpts),
+}
+
+#[derive(Clap, Debug)]
+struct RankOpts {
     #[clap(long, short = 'w', about = "Weight of criteria", required = true)]
     weight: f64,
     #[clap(
@@ -39,29 +107,585 @@ struct Opts {
         required = true
     )]
     version: PrometheeImplementation,
+    #[clap(long, about = "Criteria is directly proportional to values")]
+    max: bool,
+    #[clap(
+        long,
+        conflicts_with = "max",
+        allow_hyphen_values = true,
+        about = "Value that actions are better the closer they are to"
+    )]
+    target: Option<f64>,
+    #[clap(
+        long,
+        requires = "target",
+        use_delimiter = true,
+        number_of_values = 1,
+        about = "Comma separated tolerance under and over the target, or one for both"
+    )]
+    tolerance: Vec<f64>,
+    #[clap(
+        long,
+        about = "Difference beyond which the worse alternative can not outrank the other"
+    )]
+    veto: Option<f64>,
+    #[clap(
+        long,
+        default_value = "-1",
+        about = "Normalized flow of alternatives left out of the study area"
+    )]
+    sentinel: f64,
+    #[clap(
+        long,
+        short = 'i',
+        about = "Single band float TIFF with the criteria, or - to read numbers from stdin"
+    )]
+    input: Option<String>,
+    #[clap(
+        long,
+        short = 'o',
+        requires = "input",
+        about = "Prefix of the flow TIFFs to write instead of printing them"
+    )]
+    output: Option<String>,
+    #[clap(
+        long,
+        default_value = "1024",
+        about = "Memory in MB used to sort a TIFF with the fast version"
+    )]
+    mem: usize,
+    #[clap(
+        long,
+        default_value = "1",
+        about = "Threads comparing alternatives with the vanilla version, or sorting with the fast one"
+    )]
+    threads: usize,
+    #[clap(
+        long,
+        about = "Directory for temporary files, such as the fast version sorting [default: system's]"
+    )]
+    temp_dir: Option<String>,
+    #[clap(long, default_value = "criteria", about = "Name of the criteria")]
+    name: String,
+    #[clap(long, about = "Unit the criteria is measured in")]
+    unit: Option<String>,
+    #[clap(long, about = "What the criteria measures")]
+    description: Option<String>,
+    #[clap(
+        long,
+        about = "File with the ID of each alternative, one per line [default: raster cells or positions]"
+    )]
+    ids: Option<String>,
+    #[clap(
+        long,
+        use_delimiter = true,
+        about = "Comma separated IDs of the only alternatives to print"
+    )]
+    show: Vec<String>,
+    #[clap(subcommand)]
+    function: PreferenceFunction,
+}
+
+#[derive(Clap, Debug)]
+struct FastOpts {
+    #[clap(about = "Single band float TIFF with the criteria")]
+    criterion: String,
+    #[clap(about = "Weight of criteria")]
+    weight: f64,
+    #[clap(long, about = "Criteria is directly proportional to values")]
+    max: bool,
+    #[clap(
+        long,
+        conflicts_with = "max",
+        allow_hyphen_values = true,
+        about = "Value that actions are better the closer they are to"
+    )]
+    target: Option<f64>,
+    #[clap(
+        long,
+        requires = "target",
+        use_delimiter = true,
+        number_of_values = 1,
+        about = "Comma separated tolerance under and over the target, or one for both"
+    )]
+    tolerance: Vec<f64>,
+    #[clap(long, default_value = "1024", about = "Memory in MB used to sort")]
+    mem: usize,
+    #[clap(long, default_value = "1", about = "Threads sorting at the same time")]
+    threads: usize,
+    #[clap(long, about = "Directory to sort in [default: system's]")]
+    temp_dir: Option<String>,
+    #[clap(
+        long,
+        short = 'o',
+        about = "TIFF to write the net flow to [default: result_<criterion> next to it]"
+    )]
+    output: Option<String>,
     #[clap(subcommand)]
     function: PreferenceFunction,
 }
 
+#[derive(Clap, Debug)]
+struct MergeOpts {
+    #[clap(required = true, min_values = 2, about = "Flow TIFFs to add up")]
+    inputs: Vec<String>,
+    #[clap(long, short = 'o', required = true, about = "TIFF to write the sum to")]
+    output: String,
+    #[clap(
+        long,
+        short = 'w',
+        use_delimiter = true,
+        about = "Comma separated weight of each input, in the same order"
+    )]
+    weights: Vec<f64>,
+}
+
+#[derive(Clap, Debug)]
+struct CsvOpts {
+    #[clap(about = "Decision matrix, one row per alternative and one column per criteria")]
+    matrix: String,
+    #[clap(
+        long,
+        about = "CSV with the weight, goal and function of each criterion [default: header rows]"
+    )]
+    criteria: Option<String>,
+    #[clap(
+        long,
+        arg_enum,
+        about = "Implementation of Promethee to use",
+        required = true
+    )]
+    version: PrometheeImplementation,
+    #[clap(
+        long,
+        default_value = "-1",
+        about = "Normalized flow of alternatives left out of the study area"
+    )]
+    sentinel: f64,
+    #[clap(
+        long,
+        default_value = "1",
+        about = "Threads comparing alternatives with the vanilla version"
+    )]
+    threads: usize,
+    #[clap(long, about = "Also write the net flow on each criteria alone")]
+    unicriterion: bool,
+    #[clap(
+        long,
+        short = 'o',
+        about = "CSV to write the results to [default: printed]"
+    )]
+    output: Option<String>,
+}
+
+#[derive(Clap, Debug)]
+struct XmcdaOpts {
+    #[clap(
+        required = true,
+        about = "XMCDA files with the alternatives, criteria, performance table, weights and criteriaParameters"
+    )]
+    inputs: Vec<String>,
+    #[clap(
+        long,
+        arg_enum,
+        about = "Implementation of Promethee to use",
+        required = true
+    )]
+    version: PrometheeImplementation,
+    #[clap(
+        long,
+        default_value = "-1",
+        about = "Normalized flow of alternatives left out of the study area"
+    )]
+    sentinel: f64,
+    #[clap(
+        long,
+        default_value = "1",
+        about = "Threads comparing alternatives with the vanilla version"
+    )]
+    threads: usize,
+    #[clap(
+        long,
+        short = 'o',
+        about = "XMCDA file to write the flows and ranks to [default: printed]"
+    )]
+    output: Option<String>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Clap, Debug)]
+struct RunOpts {
+    #[clap(about = "Problem file, JSON when named *.json and TOML otherwise")]
+    problem: String,
+}
+
 fn main() {
-    let args = Opts::parse();
-    // println!("{:#?}", args);
+    match Opts::parse().command {
+        Command::Rank(args) => rank(args),
+        Command::Fast(args) => fast(args),
+        Command::Merge(args) => merge(args),
+        Command::Csv(args) => csv(args),
+        Command::Xmcda(args) => xmcda(args),
+        #[cfg(feature = "serde")]
+        Command::Run(args) => {
+            if let Err(err) = problem::run(Path::new(&args.problem)) {
+                eprintln!("Could not run {}: {}", args.problem, err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn rank(args: RankOpts) {
+    if args.input.as_deref() == Some("-") && args.output.is_some() {
+        eprintln!("Flows of criteria read from stdin can only be printed");
+        std::process::exit(1);
+    }
+
+    // Relative thresholds are resolved from every action, and only
+    // piecewise-linear functions are sorted out of core, so other criteria
+    // are ranked as read
+    if let (PrometheeImplementation::Fast, Some(input), Some(prefix), false, true, None) = (
+        &args.version,
+        &args.input,
+        &args.output,
+        args.function.is_relative(),
+        ComparisonFunction::<f64>::segments(&args.function).is_some(),
+        args.veto,
+    ) {
+        // Sorted out of core, so the criteria never has to fit in memory
+        let sorting = sorting(args.mem, &args.temp_dir).threads(args.threads);
+        let goal = goal(args.max, args.target, &args.tolerance);
+        // A single criteria gets all the weight
+        let ranked = promethee::fast::Fast::new(true)
+            .sentinel(args.sentinel)
+            .rank_raster(input, prefix, &args.function, &goal, 1.0, &sorting);
+        if let Err(err) = ranked {
+            eprintln!("Could not rank criteria: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let goal = goal(args.max, args.target, &args.tolerance);
+    let about = About {
+        name: args.name.clone(),
+        unit: args.unit.clone(),
+        description: args.description.clone(),
+    };
+    let input = match args.input.as_deref() {
+        Some(input) => input,
+        None => {
+            // Demo criteria, small enough to rank in memory
+            let mut criteria = vec![Criteria {
+                actions: (0..81)
+                    .step_by(10)
+                    .map(|x| x as f64)
+                    .collect_vec()
+                    .into_iter(),
+                weight: args.weight,
+                function: args.function,
+                goal,
+                veto: args.veto,
+                about,
+            }];
+            let alternatives = alternatives(&args.ids, 9, None);
+            let thresholds = promethee::resolve_thresholds(&mut criteria);
+            let ranking = match args.version {
+                PrometheeImplementation::Vanilla => promethee::vanilla::Vanilla::new(true)
+                    .sentinel(args.sentinel)
+                    .with_threads(args.threads)
+                    .rank_labeled(criteria, alternatives),
+                PrometheeImplementation::Fast => promethee::fast::Fast::new(true)
+                    .sentinel(args.sentinel)
+                    .rank_labeled(criteria, alternatives),
+                PrometheeImplementation::Umbu => promethee::umbu::Umbu::new(true)
+                    .sentinel(args.sentinel)
+                    .rank_labeled(criteria, alternatives),
+            };
+            let ranking = ranking.unwrap_or_else(|err| {
+                eprintln!("Could not rank criteria: {}", err);
+                std::process::exit(1);
+            });
+            print_table(&ranking.with_thresholds(thresholds), &args.show);
+            return;
+        }
+    };
+
+    // Read a single time, so the criteria is never held twice in memory
+    let (actions, info): (Actions, _) = match input {
+        "-" => {
+            let stdin = promethee::stream::TextActions::new(std::io::stdin().lock());
+            (Box::new(stdin.map(|action| Ok(action?))), None)
+        }
+        path => {
+            let cells = raster::Cells::open(path).unwrap_or_else(|err| {
+                eprintln!("Could not read criteria: {}", err);
+                std::process::exit(1);
+            });
+            let info = cells.info().clone();
+            (Box::new(cells), Some(info))
+        }
+    };
 
-    let criteria = Criteria {
-        actions: (0..81).step_by(10).map(|x| x as f64),
+    let criteria = vec![StreamedCriteria {
+        actions,
         weight: args.weight,
         function: args.function,
-        goal: Goal::Min,
+        goal,
+        veto: args.veto,
+        about: about.clone(),
+    }];
+
+    let temp_dir = temp_dir(&args.temp_dir);
+    let (flow, rank, thresholds, vetoes) = args
+        .version
+        .rank_streamed(args.sentinel, args.threads, criteria, vec![], &temp_dir)
+        .unwrap_or_else(|err| {
+            eprintln!("Could not rank criteria: {}", err);
+            std::process::exit(1);
+        });
+
+    match (&args.output, info) {
+        (Some(prefix), Some(info)) => {
+            let criteria = [about];
+            report_thresholds(&criteria, &thresholds);
+            report_vetoes(&criteria, &vetoes);
+            if let Err(err) = raster::write_flow(prefix, &info, &flow, args.sentinel) {
+                eprintln!("Could not write flows: {}", err);
+                std::process::exit(1);
+            }
+        }
+        (_, info) => {
+            let alternatives = alternatives(&args.ids, rank.len(), info.as_ref());
+            let ranking = Ranking::new(vec![about], alternatives, (flow, rank))
+                .with_thresholds(thresholds)
+                .with_vetoes(vetoes);
+            print_table(&ranking, &args.show);
+        }
+    }
+}
+
+/// Goal of a criteria given on the command line.
+fn goal(max: bool, target: Option<f64>, tolerance: &[f64]) -> Goal {
+    let target = match (max, target) {
+        (true, _) => return Goal::Max,
+        (false, None) => return Goal::Min,
+        (false, Some(target)) => target,
+    };
+    let words = std::iter::once(target)
+        .chain(tolerance.iter().copied())
+        .join(" ");
+    format!("target {}", words).parse().unwrap_or_else(|err| {
+        eprintln!("Could not rank criteria: {}", err);
+        std::process::exit(1);
+    })
+}
+
+/// IDs of the `n` alternatives, read from `ids` or else the cells of the
+/// raster or their positions.
+fn alternatives(
+    ids: &Option<String>,
+    n: usize,
+    info: Option<&raster::RasterInfo>,
+) -> Vec<Alternative> {
+    let alternatives = match (ids, info) {
+        (Some(ids), _) => match std::fs::read_to_string(ids) {
+            Ok(ids) => ids
+                .lines()
+                .map(|id| Alternative::Id(id.trim().to_string()))
+                .collect(),
+            Err(err) => {
+                eprintln!("Could not read IDs: {}: {}", ids, err);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(info)) => Alternative::cells(info.width, info.height),
+        (None, None) => Alternative::numbered(n),
     };
+    if alternatives.len() != n {
+        eprintln!("{} IDs given for {} alternatives", alternatives.len(), n);
+        std::process::exit(1);
+    }
+    alternatives
+}
 
-    let flow = match args.version {
-        PrometheeImplementation::Vanilla => {
-            promethee::vanilla::Vanilla::new(true).rank(vec![criteria])
+/// Tells on stderr what relative thresholds were resolved to, for outputs
+/// with no room for comments.
+fn report_thresholds(criteria: &[About], thresholds: &[Vec<Resolved>]) {
+    for (about, thresholds) in criteria.iter().zip(thresholds.iter()) {
+        for resolved in thresholds.iter() {
+            eprintln!("{}: {}", about.name, resolved);
         }
-        PrometheeImplementation::Fast => {
-            unimplemented!("Fast 'n Furious version wasn't implemented yet.");
+    }
+}
+
+/// Tells on stderr how many pairs each criteria vetoed, for outputs with no
+/// room for it.
+fn report_vetoes(criteria: &[About], vetoes: &[usize]) {
+    for (about, count) in criteria.iter().zip(vetoes.iter()) {
+        if *count > 0 {
+            eprintln!("{}: {} pairs vetoed", about.name, count);
         }
+    }
+}
+
+/// Prints the flows of the alternatives labeled `show`, or else of every one.
+fn print_table(ranking: &Ranking<f64>, show: &[String]) {
+    let outcomes = if show.is_empty() {
+        ranking.outcomes().collect_vec()
+    } else {
+        show.iter()
+            .map(|label| {
+                ranking.get(label).unwrap_or_else(|| {
+                    eprintln!("No alternative labeled {}", label);
+                    std::process::exit(1);
+                })
+            })
+            .collect()
     };
+    if let Err(err) = ranking.write_table(std::io::stdout().lock(), outcomes) {
+        eprintln!("Could not print flows: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn fast(args: FastOpts) {
+    if args.function.is_relative() {
+        eprintln!(
+            "Could not rank criteria: relative thresholds need every action, rank it with the rank command"
+        );
+        std::process::exit(1);
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        let criterion = Path::new(&args.criterion);
+        let name = criterion.file_name().unwrap_or_default().to_string_lossy();
+        criterion
+            .with_file_name(format!("result_{}", name))
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let ranked = promethee::fast::Fast::new(true).net_flow_raster(
+        &args.criterion,
+        &output,
+        &args.function,
+        &goal(args.max, args.target, &args.tolerance),
+        args.weight,
+        &sorting(args.mem, &args.temp_dir).threads(args.threads),
+    );
+    if let Err(err) = ranked {
+        eprintln!("Could not rank criteria: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn sorting(mem: usize, temp_dir: &Option<String>) -> external_sort::ExternalSort {
+    external_sort::ExternalSort::new(mem << 20, self::temp_dir(temp_dir))
+}
+
+fn temp_dir(temp_dir: &Option<String>) -> PathBuf {
+    match temp_dir {
+        Some(temp_dir) => PathBuf::from(temp_dir),
+        None => std::env::temp_dir(),
+    }
+}
+
+fn csv(args: CsvOpts) {
+    let matrix = matrix::DecisionMatrix::read(
+        Path::new(&args.matrix),
+        args.criteria.as_deref().map(Path::new),
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", args.matrix, err);
+        std::process::exit(1);
+    });
+
+    let (ranking, unicriterion) = match args.version {
+        PrometheeImplementation::Vanilla => matrix::rank(
+            promethee::vanilla::Vanilla::new(true)
+                .sentinel(args.sentinel)
+                .with_threads(args.threads),
+            matrix,
+            args.unicriterion,
+        ),
+        PrometheeImplementation::Fast => matrix::rank(
+            promethee::fast::Fast::new(true).sentinel(args.sentinel),
+            matrix,
+            args.unicriterion,
+        ),
+        PrometheeImplementation::Umbu => matrix::rank(
+            promethee::umbu::Umbu::new(true).sentinel(args.sentinel),
+            matrix,
+            args.unicriterion,
+        ),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Could not rank {}: {}", args.matrix, err);
+        std::process::exit(1);
+    });
+
+    report_thresholds(&ranking.criteria, &ranking.thresholds);
+    report_vetoes(&ranking.criteria, &ranking.vetoes);
+    let written = match &args.output {
+        Some(output) => std::fs::File::create(output)
+            .map_err(matrix::MatrixError::from)
+            .and_then(|file| matrix::write_results(file, &ranking, unicriterion.as_deref())),
+        None => matrix::write_results(std::io::stdout().lock(), &ranking, unicriterion.as_deref()),
+    };
+    if let Err(err) = written {
+        eprintln!("Could not write results: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn xmcda(args: XmcdaOpts) {
+    let matrix = xmcda::read(&args.inputs).unwrap_or_else(|err| {
+        eprintln!("Could not read XMCDA: {}", err);
+        std::process::exit(1);
+    });
+
+    let (ranking, _) = match args.version {
+        PrometheeImplementation::Vanilla => matrix::rank(
+            promethee::vanilla::Vanilla::new(true)
+                .sentinel(args.sentinel)
+                .with_threads(args.threads),
+            matrix,
+            false,
+        ),
+        PrometheeImplementation::Fast => matrix::rank(
+            promethee::fast::Fast::new(true).sentinel(args.sentinel),
+            matrix,
+            false,
+        ),
+        PrometheeImplementation::Umbu => matrix::rank(
+            promethee::umbu::Umbu::new(true).sentinel(args.sentinel),
+            matrix,
+            false,
+        ),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Could not rank criteria: {}", err);
+        std::process::exit(1);
+    });
+
+    report_thresholds(&ranking.criteria, &ranking.thresholds);
+    report_vetoes(&ranking.criteria, &ranking.vetoes);
+    let written = match &args.output {
+        Some(output) => std::fs::File::create(output)
+            .and_then(|file| xmcda::write_flows(std::io::BufWriter::new(file), &ranking)),
+        None => xmcda::write_flows(std::io::stdout().lock(), &ranking),
+    };
+    if let Err(err) = written {
+        eprintln!("Could not write flows: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    println!("{:#?}", flow);
+fn merge(args: MergeOpts) {
+    if let Err(err) = raster::merge::merge(&args.inputs, &args.weights, &args.output) {
+        eprintln!("Could not merge: {}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,554 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use itertools::Itertools;
+
+use crate::{
+    promethee::{
+        resolve_thresholds, About, Alternative, Criteria, Goal, Promethee, PrometheeError, Ranking,
+    },
+    PreferenceFunction, Scale,
+};
+
+/// Criteria of a decision matrix, one column of actions each.
+pub(crate) type MatrixCriteria = Criteria<f64, std::vec::IntoIter<f64>, PreferenceFunction>;
+
+/// Net flows of the alternatives on each criteria alone, criteria by criteria.
+pub(crate) type Unicriterion = Vec<Vec<f64>>;
+
+#[derive(Debug)]
+pub(crate) enum MatrixError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::Io(err) => write!(f, "{}", err),
+            MatrixError::Csv(err) => write!(f, "{}", err),
+            MatrixError::Invalid(reason) => write!(f, "invalid decision matrix: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+impl From<io::Error> for MatrixError {
+    fn from(err: io::Error) -> Self {
+        MatrixError::Io(err)
+    }
+}
+
+impl From<csv::Error> for MatrixError {
+    fn from(err: csv::Error) -> Self {
+        MatrixError::Csv(err)
+    }
+}
+
+/// Alternatives and criteria of a spreadsheet study.
+#[derive(Debug)]
+pub(crate) struct DecisionMatrix {
+    pub(crate) alternatives: Vec<Alternative>,
+    pub(crate) criteria: Vec<MatrixCriteria>,
+}
+
+/// What the header rows or the sidecar say about a criteria.
+#[derive(Default)]
+struct Definition {
+    weight: Option<f64>,
+    goal: Option<Goal>,
+    veto: Option<f64>,
+    function: Option<PreferenceFunction>,
+    scale: Option<Scale>,
+    unit: Option<String>,
+    description: Option<String>,
+}
+
+impl Definition {
+    /// Sets the `key` row or column of the definition from `cell`.
+    fn set(&mut self, key: &str, cell: &str, line: u64, name: &str) -> Result<(), MatrixError> {
+        if cell.is_empty() {
+            return Ok(());
+        }
+        let invalid = |reason: String| {
+            MatrixError::Invalid(format!("line {}, {} of {}: {}", line, key, name, reason))
+        };
+        match key {
+            "weight" => {
+                self.weight = Some(
+                    cell.parse()
+                        .map_err(|_| invalid(format!("{} is not a number", cell)))?,
+                )
+            }
+            "goal" => self.goal = Some(cell.parse().map_err(invalid)?),
+            "veto" => {
+                self.veto = Some(
+                    cell.parse()
+                        .map_err(|_| invalid(format!("{} is not a number", cell)))?,
+                )
+            }
+            "function" => self.function = Some(cell.parse().map_err(invalid)?),
+            "scale" => self.scale = Some(cell.parse().map_err(invalid)?),
+            "unit" => self.unit = Some(cell.to_string()),
+            "description" => self.description = Some(cell.to_string()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Rows or sidecar columns defining the criteria instead of alternatives.
+const KEYS: [&str; 7] = [
+    "weight",
+    "goal",
+    "veto",
+    "function",
+    "scale",
+    "unit",
+    "description",
+];
+
+/// Cell of an action with its line, read once the criteria is defined.
+type Cell = (String, u64);
+
+impl DecisionMatrix {
+    /// Reads a matrix with one row per alternative and one column per
+    /// criteria, named in the first row. The first column holds the IDs of
+    /// the alternatives when its name is empty or `id`, and then rows named
+    /// `weight`, `goal`, `veto`, `function` (as in the command line,
+    /// `linear 100`), `scale`, `unit` or `description` right after the names
+    /// define the criteria. A `sidecar` CSV with a `criterion` column and a column for
+    /// each of those keys can define them instead. Actions of categorical
+    /// criteria or criteria with a scale are written as labels.
+    pub(crate) fn read(path: &Path, sidecar: Option<&Path>) -> Result<Self, MatrixError> {
+        let open = |path: &Path| {
+            File::open(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+        };
+        let sidecar = match sidecar {
+            Some(sidecar) => Some(open(sidecar)?),
+            None => None,
+        };
+        Self::from_readers(open(path)?, sidecar)
+    }
+
+    fn from_readers<R: Read, S: Read>(matrix: R, sidecar: Option<S>) -> Result<Self, MatrixError> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(matrix);
+        let headers = reader.headers()?.clone();
+        let with_ids = headers
+            .get(0)
+            .is_some_and(|id| id.is_empty() || id.eq_ignore_ascii_case("id"));
+        let names = headers
+            .iter()
+            .skip(if with_ids { 1 } else { 0 })
+            .map(String::from)
+            .collect_vec();
+        if names.is_empty() {
+            return Err(MatrixError::Invalid("no criteria columns".to_string()));
+        }
+
+        let mut definitions = names.iter().map(|_| Definition::default()).collect_vec();
+        let mut ids = vec![];
+        let mut columns = vec![vec![]; names.len()];
+        for record in reader.records() {
+            let record = record?;
+            let line = line(&record);
+            let mut cells = record.iter();
+            let id = if with_ids { cells.next() } else { None };
+
+            let key = id.map(str::to_lowercase).unwrap_or_default();
+            if ids.is_empty() && KEYS.contains(&key.as_str()) {
+                for ((cell, definition), name) in cells.zip(definitions.iter_mut()).zip(&names) {
+                    definition.set(&key, cell, line, name)?;
+                }
+                continue;
+            }
+
+            ids.push(match id {
+                Some(id) => Alternative::Id(id.to_string()),
+                None => Alternative::Id(ids.len().to_string()),
+            });
+            for (cell, column) in cells.zip(columns.iter_mut()) {
+                column.push((cell.to_string(), line));
+            }
+        }
+
+        if let Some(sidecar) = sidecar {
+            define(sidecar, &names, &mut definitions)?;
+        }
+
+        let criteria = criteria(names, definitions, columns)?;
+        Ok(Self {
+            alternatives: ids,
+            criteria,
+        })
+    }
+}
+
+fn line(record: &StringRecord) -> u64 {
+    record.position().map_or(0, |position| position.line())
+}
+
+/// Action in a cell, missing (NaN) when empty, read as a label of the
+/// `scale` if there is one.
+fn action(cell: &str, line: u64, name: &str, scale: Option<&Scale>) -> Result<f64, MatrixError> {
+    if cell.is_empty() {
+        return Ok(f64::NAN);
+    }
+    let invalid =
+        |reason: String| MatrixError::Invalid(format!("line {}, {}: {}", line, name, reason));
+    match scale {
+        Some(scale) => scale.score(cell).ok_or_else(|| {
+            invalid(format!(
+                "{} is not one of the labels {}",
+                cell,
+                scale.labels()
+            ))
+        }),
+        None => cell
+            .parse()
+            .map_err(|_| invalid(format!("{} is not a number", cell))),
+    }
+}
+
+/// Reads the sidecar definitions of the criteria `names`.
+fn define<S: Read>(
+    sidecar: S,
+    names: &[String],
+    definitions: &mut [Definition],
+) -> Result<(), MatrixError> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(sidecar);
+    let headers = reader
+        .headers()?
+        .iter()
+        .map(str::to_lowercase)
+        .collect_vec();
+    let criterion = headers
+        .iter()
+        .position(|header| header == "criterion")
+        .ok_or_else(|| MatrixError::Invalid("the sidecar has no criterion column".to_string()))?;
+
+    for record in reader.records() {
+        let record = record?;
+        let line = line(&record);
+        let name = &record[criterion];
+        let definition = match names.iter().position(|other| other == name) {
+            Some(index) => &mut definitions[index],
+            None => {
+                return Err(MatrixError::Invalid(format!(
+                    "line {} of the sidecar: no criteria named {} in the matrix",
+                    line, name
+                )))
+            }
+        };
+        for (key, cell) in headers.iter().zip(record.iter()) {
+            definition.set(key, cell, line, name)?;
+        }
+    }
+    Ok(())
+}
+
+fn criteria(
+    names: Vec<String>,
+    definitions: Vec<Definition>,
+    columns: Vec<Vec<Cell>>,
+) -> Result<Vec<MatrixCriteria>, MatrixError> {
+    names
+        .into_iter()
+        .zip(definitions)
+        .zip(columns)
+        .map(|((name, definition), cells)| {
+            let missing = |key: &str| {
+                MatrixError::Invalid(format!(
+                    "{} has no {}, give it in a {} row or in a sidecar",
+                    name, key, key
+                ))
+            };
+            let function = definition.function.ok_or_else(|| missing("function"))?;
+            let scale = function
+                .labels(definition.scale)
+                .map_err(|reason| MatrixError::Invalid(format!("{}: {}", name, reason)))?;
+            let actions = cells
+                .iter()
+                .map(|(cell, line)| action(cell, *line, &name, scale.as_ref()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Criteria {
+                actions: actions.into_iter(),
+                weight: definition.weight.ok_or_else(|| missing("weight"))?,
+                function,
+                goal: definition.goal.ok_or_else(|| missing("goal"))?,
+                veto: definition.veto,
+                about: About {
+                    name,
+                    unit: definition.unit,
+                    description: definition.description,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Ranks a decision matrix, and if asked for the net flow of the
+/// alternatives on each criteria alone. Relative thresholds are resolved
+/// first, and told by the ranking.
+pub(crate) fn rank<P: Promethee>(
+    mut promethee: P,
+    mut matrix: DecisionMatrix,
+    unicriterion: bool,
+) -> Result<(Ranking<f64>, Option<Unicriterion>), PrometheeError> {
+    let thresholds = resolve_thresholds(&mut matrix.criteria);
+    let unicriterion = if unicriterion {
+        Some(promethee.unicriterion_flows(&matrix.criteria)?)
+    } else {
+        None
+    };
+    let ranking = promethee
+        .rank_labeled(matrix.criteria, matrix.alternatives)?
+        .with_thresholds(thresholds);
+    Ok((ranking, unicriterion))
+}
+
+/// Writes a row per alternative, in the order they were read, with its flows
+/// and place in the rank. Flows of alternatives out of the study area are
+/// left empty.
+pub(crate) fn write_results<W: Write>(
+    writer: W,
+    ranking: &Ranking<f64>,
+    unicriterion: Option<&[Vec<f64>]>,
+) -> Result<(), MatrixError> {
+    let mut writer = WriterBuilder::new().from_writer(writer);
+
+    let mut header = vec![
+        "alternative".to_string(),
+        "rank".to_string(),
+        "positive_flow".to_string(),
+        "negative_flow".to_string(),
+        "net_flow".to_string(),
+        "normalized_flow".to_string(),
+    ];
+    if unicriterion.is_some() {
+        for about in ranking.criteria.iter() {
+            header.push(format!("net_flow_{}", about.name));
+        }
+    }
+    writer.write_record(&header)?;
+
+    let mut places = vec![0; ranking.rank.len()];
+    for (place, index) in ranking.rank.iter().enumerate() {
+        places[*index] = place + 1;
+    }
+    let flow = &ranking.flow;
+    for (index, alternative) in ranking.alternatives.iter().enumerate() {
+        let mut row = vec![alternative.to_string(), places[index].to_string()];
+        // The normalized flow of alternatives out of the study area is the
+        // sentinel, not a flow
+        let valid = !flow.net_flow[index].is_nan();
+        let mut flows = vec![
+            flow.positive_flow[index],
+            flow.negative_flow[index],
+            flow.net_flow[index],
+            if valid {
+                flow.normalized_flow[index]
+            } else {
+                f64::NAN
+            },
+        ];
+        if let Some(unicriterion) = unicriterion {
+            flows.extend(unicriterion.iter().map(|net_flow| net_flow[index]));
+        }
+        row.extend(flows.into_iter().map(|flow| {
+            if flow.is_nan() {
+                String::new()
+            } else {
+                flow.to_string()
+            }
+        }));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::vanilla::Vanilla;
+
+    const MATRIX: &str = "\
+id,price,rooms
+weight,0.35,0.25
+goal,min,max
+function,linear 100,usual
+unit,R$,
+Recife,250,2
+Natal,200,2
+Patos,,1
+Sousa,300,3
+";
+
+    fn read(matrix: &str, sidecar: Option<&str>) -> Result<DecisionMatrix, MatrixError> {
+        DecisionMatrix::from_readers(matrix.as_bytes(), sidecar.map(str::as_bytes))
+    }
+
+    #[test]
+    fn reads_header_rows() {
+        let matrix = read(MATRIX, None).unwrap();
+
+        assert_eq!(4, matrix.alternatives.len());
+        assert_eq!(Alternative::Id("Patos".to_string()), matrix.alternatives[2]);
+        let price = &matrix.criteria[0];
+        assert_eq!("price", price.about.name);
+        assert_eq!(Some("R$".to_string()), price.about.unit);
+        assert_eq!(0.35, price.weight);
+        assert_eq!(Goal::Min, price.goal);
+        assert!(price.actions.clone().nth(2).unwrap().is_nan());
+        assert_eq!(Goal::Max, matrix.criteria[1].goal);
+    }
+
+    #[test]
+    fn reads_sidecar() {
+        let from_sidecar = read(
+            "price,rooms\n250,2\n200,2\n,1\n300,3\n",
+            Some(
+                "criterion,weight,goal,function\nrooms,0.25,max,usual\nprice,0.35,min,linear 100\n",
+            ),
+        )
+        .unwrap();
+        let from_rows = read(MATRIX, None).unwrap();
+
+        assert_eq!(Alternative::numbered(4), from_sidecar.alternatives);
+        let (want, _) = rank(Vanilla::new(true), from_rows, false).unwrap();
+        let (got, _) = rank(Vanilla::new(true), from_sidecar, false).unwrap();
+        assert_eq!(want.rank, got.rank);
+        assert_eq!(
+            format!("{:?}", want.flow.net_flow),
+            format!("{:?}", got.flow.net_flow)
+        );
+    }
+
+    #[test]
+    fn tells_where_the_matrix_is_wrong() {
+        let err = read(&MATRIX.replace("Natal,200", "Natal,2OO"), None).unwrap_err();
+        assert_eq!(
+            "invalid decision matrix: line 7, price: 2OO is not a number",
+            err.to_string()
+        );
+
+        let err = read(&MATRIX.replace("linear 100", "linear"), None).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid decision matrix: line 4, function of price:"));
+
+        let err = read(&MATRIX.replace("goal,min,max\n", ""), None).unwrap_err();
+        assert_eq!(
+            "invalid decision matrix: price has no goal, give it in a goal row or in a sidecar",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn resolves_relative_thresholds() {
+        let absolute = read(&MATRIX.replace("linear 100", "linear 50"), None).unwrap();
+        let relative = read(&MATRIX.replace("linear 100", "linear 50%"), None).unwrap();
+
+        let (want, _) = rank(Vanilla::new(true), absolute, false).unwrap();
+        let (got, _) = rank(Vanilla::new(true), relative, false).unwrap();
+        assert_eq!(want.rank, got.rank);
+        assert_eq!(
+            format!("{:?}", want.flow.net_flow),
+            format!("{:?}", got.flow.net_flow)
+        );
+        let resolved = got
+            .resolved()
+            .map(|(about, resolved)| format!("{}: {}", about.name, resolved))
+            .collect_vec();
+        assert_eq!(vec!["price: m = 50% of the range = 50"], resolved);
+    }
+
+    #[test]
+    fn reads_vetoes() {
+        let matrix = read(
+            &MATRIX.replace("goal,min,max\n", "goal,min,max\nveto,60,\n"),
+            None,
+        );
+        let matrix = matrix.unwrap();
+        assert_eq!(Some(60.0), matrix.criteria[0].veto);
+        assert_eq!(None, matrix.criteria[1].veto);
+
+        let (ranking, _) = rank(Vanilla::new(true), matrix, false).unwrap();
+        let vetoes = ranking
+            .vetoed()
+            .map(|(about, count)| format!("{}: {}", about.name, count))
+            .collect_vec();
+        assert_eq!(vec!["price: 1"], vetoes);
+    }
+
+    #[test]
+    fn reads_labels() {
+        let matrix = "\
+id,soil,state
+weight,1,1
+goal,max,max
+function,\"categorical sandy:0,0.2,0.5 clay:0.6,0,0.1 loam:1,0.8,0\",linear 4
+scale,,poor:1 fair:2 good:5
+A,sandy,good
+B,clay,poor
+C,loam,fair
+";
+        let (ranking, unicriterion) =
+            rank(Vanilla::new(true), read(matrix, None).unwrap(), true).unwrap();
+        let unicriterion = unicriterion
+            .unwrap()
+            .into_iter()
+            .map(|flows| {
+                flows
+                    .into_iter()
+                    .map(|flow| (flow * 1e6).round() / 1e6)
+                    .collect_vec()
+            })
+            .collect_vec();
+        // A is preferred 0.2 to clay and 0.5 to loam, both preferred to it
+        // 0.6 and 1
+        assert_eq!(vec![-0.45, -0.15, 0.6], unicriterion[0]);
+        assert_eq!(vec![0.875, -0.625, -0.25], unicriterion[1]);
+        assert_eq!(vec![0, 2, 1], ranking.rank);
+
+        let err = read(&matrix.replace("B,clay", "B,silt"), None).unwrap_err();
+        assert_eq!(
+            "invalid decision matrix: line 7, soil: silt is not one of the labels sandy, clay, loam",
+            err.to_string()
+        );
+        let err = read(&matrix.replace("scale,,", "scale,poor fair,"), None).unwrap_err();
+        assert_eq!(
+            "invalid decision matrix: soil: categorical criteria take their labels from their table, not from a scale",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn writes_results() {
+        let matrix = read(MATRIX, None).unwrap();
+        let (ranking, unicriterion) = rank(Vanilla::new(true), matrix, true).unwrap();
+
+        let mut results = vec![];
+        write_results(&mut results, &ranking, unicriterion.as_deref()).unwrap();
+        let results = String::from_utf8(results).unwrap();
+        let lines = results.lines().collect_vec();
+
+        assert_eq!(
+            "alternative,rank,positive_flow,negative_flow,net_flow,normalized_flow,net_flow_price,net_flow_rooms",
+            lines[0]
+        );
+        assert_eq!("Patos,4,,,,,,", lines[3]);
+        // Sousa has the most rooms and Natal the lowest price
+        assert!(lines[4].ends_with(",-0.75,1"));
+        assert!(lines[2].ends_with(",0.75,-0.5"));
+    }
+}
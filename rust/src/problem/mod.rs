@@ -0,0 +1,581 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    promethee::{
+        stream::TextActions, About, Alternative, Effect, Goal, Interaction, Ranking,
+        StreamedCriteria,
+    },
+    raster::{self, RasterError, RasterInfo},
+    Actions, PreferenceFunction, PrometheeImplementation, Scale,
+};
+
+#[derive(Debug)]
+pub(crate) enum ProblemError {
+    Io(io::Error),
+    /// The file is not a problem, the message tells the line where.
+    Syntax(String),
+    Invalid(String),
+    Raster(RasterError),
+}
+
+impl fmt::Display for ProblemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemError::Io(err) => write!(f, "{}", err),
+            ProblemError::Syntax(reason) => write!(f, "{}", reason),
+            ProblemError::Invalid(reason) => write!(f, "invalid problem: {}", reason),
+            ProblemError::Raster(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProblemError {}
+
+impl From<io::Error> for ProblemError {
+    fn from(err: io::Error) -> Self {
+        ProblemError::Io(err)
+    }
+}
+
+impl From<RasterError> for ProblemError {
+    fn from(err: RasterError) -> Self {
+        ProblemError::Raster(err)
+    }
+}
+
+/// Everything a ranking needs, as written in a problem file. Paths are
+/// relative to the directory of the file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Problem {
+    engine: Engine,
+    criteria: Vec<CriteriaFile>,
+    /// Pairs of criteria that interact
+    #[serde(default)]
+    interaction: Vec<InteractionFile>,
+    /// IDs of the alternatives [default: raster cells or positions]
+    alternatives: Option<Vec<String>>,
+    #[serde(default)]
+    output: Output,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Engine {
+    version: PrometheeImplementation,
+    #[serde(default = "Engine::default_threads")]
+    threads: usize,
+    #[serde(default = "Engine::default_sentinel")]
+    sentinel: f64,
+    temp_dir: Option<PathBuf>,
+}
+
+impl Engine {
+    fn default_threads() -> usize {
+        1
+    }
+
+    fn default_sentinel() -> f64 {
+        -1.0
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CriteriaFile {
+    name: String,
+    unit: Option<String>,
+    description: Option<String>,
+    source: Source,
+    weight: f64,
+    goal: Goal,
+    /// Difference beyond which the worse alternative can not outrank the
+    /// other
+    veto: Option<f64>,
+    function: PreferenceFunction,
+    /// Scores of the labels of an ordinal criteria
+    scale: Option<Scale>,
+}
+
+/// Interaction of two criteria, named as in their `name`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct InteractionFile {
+    criteria: (String, String),
+    effect: Effect,
+    coefficient: f64,
+}
+
+/// Where the actions of a criteria are read from.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Source {
+    /// Actions written in the problem, `nan` (TOML) or `null` (JSON) when
+    /// missing.
+    Inline(Vec<Option<f64>>),
+    /// Labels of categories written in the problem, read by the scale or
+    /// the categorical function of the criteria.
+    Labels(Vec<Option<String>>),
+    /// A single band float TIFF (`*.tif` or `*.tiff`), or else a text file
+    /// with numbers separated by whitespace.
+    File(PathBuf),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Output {
+    /// Tab separated table of the ranking [default: printed]
+    table: Option<PathBuf>,
+    /// Ranking as JSON
+    json: Option<PathBuf>,
+    /// Prefix of the flow TIFFs, when every source is a raster
+    rasters: Option<String>,
+}
+
+impl Problem {
+    /// Reads a problem, as JSON when `path` ends in `.json` and TOML otherwise.
+    pub(crate) fn read(path: &Path) -> Result<Self, ProblemError> {
+        let text = std::fs::read_to_string(path)?;
+        let json = path.extension().is_some_and(|ext| ext == "json");
+        Self::parse(&text, json)
+    }
+
+    fn parse(text: &str, json: bool) -> Result<Self, ProblemError> {
+        let problem: Self = if json {
+            serde_json::from_str(text).map_err(|err| ProblemError::Syntax(err.to_string()))?
+        } else {
+            toml::from_str(text).map_err(|err| ProblemError::Syntax(err.to_string()))?
+        };
+        if problem.criteria.is_empty() {
+            return Err(ProblemError::Invalid("no criteria to rank".to_string()));
+        }
+        Ok(problem)
+    }
+}
+
+/// Ranks the problem in `path` and writes its outputs.
+pub(crate) fn run(path: &Path) -> Result<(), ProblemError> {
+    let problem = Problem::read(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    solve(problem, dir)
+}
+
+fn solve(problem: Problem, dir: &Path) -> Result<(), ProblemError> {
+    let interactions = interactions(&problem)?;
+    let mut about = vec![];
+    let mut info: Option<RasterInfo> = None;
+    let mut rasters_only = true;
+    let mut criteria = vec![];
+    for criteria_file in problem.criteria.into_iter() {
+        let name = criteria_file.name;
+        let scale = criteria_file
+            .function
+            .labels(criteria_file.scale)
+            .map_err(|reason| ProblemError::Invalid(format!("{}: {}", name, reason)))?;
+        let actions: Actions = match criteria_file.source {
+            Source::Inline(actions) => {
+                rasters_only = false;
+                Box::new(
+                    actions
+                        .into_iter()
+                        .map(|action| Ok(action.unwrap_or(f64::NAN))),
+                )
+            }
+            Source::Labels(labels) => {
+                rasters_only = false;
+                let scale = scale.ok_or_else(|| {
+                    ProblemError::Invalid(format!(
+                        "{} has labels for actions, but neither a scale nor a categorical function",
+                        name
+                    ))
+                })?;
+                let actions = labels
+                    .iter()
+                    .map(|label| match label {
+                        Some(label) => scale.score(label).ok_or_else(|| {
+                            ProblemError::Invalid(format!(
+                                "{}: {} is not one of the labels {}",
+                                name,
+                                label,
+                                scale.labels()
+                            ))
+                        }),
+                        None => Ok(f64::NAN),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Box::new(actions.into_iter().map(Ok))
+            }
+            Source::File(source) => {
+                let source = dir.join(source);
+                if is_raster(&source) {
+                    let cells = raster::Cells::open(&source)?;
+                    match &info {
+                        Some(info)
+                            if info.width != cells.info().width
+                                || info.height != cells.info().height =>
+                        {
+                            return Err(ProblemError::Invalid(format!(
+                                "{} is {}x{}, other criteria are {}x{}",
+                                source.display(),
+                                cells.info().width,
+                                cells.info().height,
+                                info.width,
+                                info.height
+                            )));
+                        }
+                        Some(_) => {}
+                        None => info = Some(cells.info().clone()),
+                    }
+                    Box::new(cells)
+                } else {
+                    rasters_only = false;
+                    let file = BufReader::new(File::open(&source).map_err(|err| {
+                        io::Error::new(err.kind(), format!("{}: {}", source.display(), err))
+                    })?);
+                    Box::new(TextActions::new(file).map(|action| Ok(action?)))
+                }
+            }
+        };
+        let criteria_about = About {
+            name,
+            unit: criteria_file.unit,
+            description: criteria_file.description,
+        };
+        about.push(criteria_about.clone());
+        criteria.push(StreamedCriteria {
+            actions,
+            weight: criteria_file.weight,
+            function: criteria_file.function,
+            goal: criteria_file.goal,
+            veto: criteria_file.veto,
+            about: criteria_about,
+        });
+    }
+
+    // Checked before ranking, which is long for big rasters
+    let output = problem.output;
+    let raster_info = match (&output.rasters, &info) {
+        (Some(_), Some(info)) if rasters_only => Some(info),
+        (Some(_), _) => {
+            return Err(ProblemError::Invalid(
+                "flow rasters need every source to be a raster".to_string(),
+            ))
+        }
+        (None, _) => None,
+    };
+
+    let engine = problem.engine;
+    let temp_dir = match engine.temp_dir {
+        Some(temp_dir) => dir.join(temp_dir),
+        None => std::env::temp_dir(),
+    };
+    let (flow, rank, thresholds, vetoes) = engine.version.rank_streamed(
+        engine.sentinel,
+        engine.threads,
+        criteria,
+        interactions,
+        &temp_dir,
+    )?;
+
+    let alternatives = match (problem.alternatives, &info) {
+        (Some(ids), _) => ids.into_iter().map(Alternative::Id).collect(),
+        (None, Some(info)) if rasters_only => Alternative::cells(info.width, info.height),
+        (None, _) => Alternative::numbered(rank.len()),
+    };
+    if alternatives.len() != rank.len() {
+        return Err(ProblemError::Invalid(format!(
+            "{} alternatives named, criteria have {}",
+            alternatives.len(),
+            rank.len()
+        )));
+    }
+    let ranking = Ranking::new(about, alternatives, (flow, rank))
+        .with_thresholds(thresholds)
+        .with_vetoes(vetoes);
+
+    if let (Some(prefix), Some(info)) = (&output.rasters, raster_info) {
+        raster::write_flow(
+            &dir.join(prefix).to_string_lossy(),
+            info,
+            &ranking.flow,
+            engine.sentinel,
+        )?;
+    }
+    if let Some(json) = &output.json {
+        let writer = BufWriter::new(File::create(dir.join(json))?);
+        serde_json::to_writer_pretty(writer, &ranking).map_err(io::Error::from)?;
+    }
+    match &output.table {
+        Some(table) => {
+            let mut writer = BufWriter::new(File::create(dir.join(table))?);
+            ranking.write_table(&mut writer, ranking.outcomes())?;
+            writer.flush()?;
+        }
+        None => ranking.write_table(io::stdout().lock(), ranking.outcomes())?,
+    }
+    Ok(())
+}
+
+/// Interactions of the problem, with their criteria found by name.
+fn interactions(problem: &Problem) -> Result<Vec<Interaction>, ProblemError> {
+    let index = |name: &str| {
+        problem
+            .criteria
+            .iter()
+            .position(|criteria| criteria.name == name)
+            .ok_or_else(|| {
+                ProblemError::Invalid(format!("interaction of {}, which is not a criteria", name))
+            })
+    };
+    problem
+        .interaction
+        .iter()
+        .map(|interaction| {
+            Ok(Interaction {
+                first: index(&interaction.criteria.0)?,
+                second: index(&interaction.criteria.1)?,
+                effect: interaction.effect,
+                coefficient: interaction.coefficient,
+            })
+        })
+        .collect()
+}
+
+fn is_raster(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::Threshold;
+
+    const PROBLEM: &str = r#"
+alternatives = ["Recife", "Natal", "Patos", "Sousa"]
+
+[engine]
+version = "vanilla"
+
+[[criteria]]
+name = "price"
+unit = "R$"
+source = [250, 200, nan, 300]
+weight = 0.35
+goal = "min"
+function = { type = "linear", m = 100 }
+
+[[criteria]]
+name = "rooms"
+source = "rooms.txt"
+weight = 0.25
+goal = "max"
+function = { type = "usual" }
+
+[output]
+table = "ranking.tsv"
+json = "ranking.json"
+"#;
+
+    #[test]
+    fn runs_problem() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("problem.toml"), PROBLEM).unwrap();
+        std::fs::write(dir.path().join("rooms.txt"), "2\n2\n1\n3\n").unwrap();
+
+        run(&dir.path().join("problem.toml")).unwrap();
+
+        let table = std::fs::read_to_string(dir.path().join("ranking.tsv")).unwrap();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!("# price (R$)", lines[0]);
+        assert_eq!("# rooms", lines[1]);
+        assert!(lines[3].starts_with("1\tNatal\t"));
+        assert!(lines[6].starts_with("4\tPatos\tNaN\t"));
+
+        let json = std::fs::read_to_string(dir.path().join("ranking.json")).unwrap();
+        let ranking: Ranking<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![1, 3, 0, 2], ranking.rank);
+    }
+
+    #[test]
+    fn rejects_flow_rasters_before_ranking() {
+        let dir = tempfile::tempdir().unwrap();
+        let problem = PROBLEM.replace("[output]", "[output]\nrasters = \"flow_\"");
+        std::fs::write(dir.path().join("problem.toml"), problem).unwrap();
+        // Ranking would fail on the rooms read while ranking
+        std::fs::write(dir.path().join("rooms.txt"), "2\nmany\n1\n3\n").unwrap();
+        assert_eq!(
+            "invalid problem: flow rasters need every source to be a raster",
+            run(&dir.path().join("problem.toml"))
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn reads_json() {
+        let problem = Problem::parse(
+            r#"{
+                "engine": { "version": "fast", "threads": 2 },
+                "criteria": [{
+                    "name": "price",
+                    "source": [250, null, 300],
+                    "weight": 1,
+                    "goal": "min",
+                    "function": { "type": "level", "weak_treshold": 10, "weak_area": 20 }
+                }]
+            }"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(2, problem.engine.threads);
+        assert_eq!(1, problem.criteria.len());
+    }
+
+    #[test]
+    fn reads_breakpoints() {
+        let problem = Problem::parse(
+            &PROBLEM.replace(
+                "{ type = \"usual\" }",
+                "{ type = \"piecewise-linear\", breakpoints = [[0, 0], [1, 0.3], [2, 1]] }",
+            ),
+            false,
+        )
+        .unwrap();
+        match &problem.criteria[1].function {
+            PreferenceFunction::PiecewiseLinear(f) => {
+                assert_eq!(3, f.breakpoints.len());
+                assert_eq!(0.3, f.breakpoints[1].preference);
+            }
+            other => panic!("read as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_relative_thresholds() {
+        let problem = Problem::parse(&PROBLEM.replace("m = 100", "m = \"q90\""), false).unwrap();
+        match &problem.criteria[0].function {
+            PreferenceFunction::Linear(f) => assert_eq!(Threshold::Quantile(90.0), f.m),
+            other => panic!("read as {:?}", other),
+        }
+
+        let bad = PROBLEM.replace("m = 100", "m = \"ten%\"");
+        let err = Problem::parse(&bad, false).unwrap_err().to_string();
+        assert!(err.contains("ten% is not a threshold"), "{}", err);
+    }
+
+    #[test]
+    fn reads_labels() {
+        let labeled = PROBLEM.replace(
+            "[output]",
+            r#"[[criteria]]
+name = "state"
+source = ["good", "poor", "fair", "poor"]
+scale = ["poor", "fair", "good"]
+weight = 0.2
+goal = "max"
+function = { type = "linear", m = 2 }
+
+[[criteria]]
+name = "district"
+source = ["center", "beach", "center", "outskirts"]
+weight = 0.2
+goal = "max"
+function = { type = "categorical", categories = [
+    { label = "center", preferences = [0, 0.5, 1] },
+    { label = "beach", preferences = [0.3, 0, 1] },
+    { label = "outskirts", preferences = [0, 0, 0] },
+] }
+
+[output]"#,
+        );
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("problem.toml"), &labeled).unwrap();
+        std::fs::write(dir.path().join("rooms.txt"), "2\n2\n1\n3\n").unwrap();
+        run(&dir.path().join("problem.toml")).unwrap();
+        let json = std::fs::read_to_string(dir.path().join("ranking.json")).unwrap();
+        let ranking: Ranking<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![0, 1, 3, 2], ranking.rank);
+
+        let bad = labeled.replace("\"fair\", \"poor\"]", "\"fair\", \"bad\"]");
+        std::fs::write(dir.path().join("problem.toml"), bad).unwrap();
+        let err = run(&dir.path().join("problem.toml")).unwrap_err();
+        assert_eq!(
+            "invalid problem: state: bad is not one of the labels poor, fair, good",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn reads_targets() {
+        let problem = Problem::parse(
+            &PROBLEM.replace("goal = \"max\"", "goal = \"target 2 0 1\""),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            Goal::Target {
+                value: 2.0,
+                below: 0.0,
+                above: 1.0
+            },
+            problem.criteria[1].goal
+        );
+
+        let bad = PROBLEM.replace("goal = \"max\"", "goal = \"target two\"");
+        let err = Problem::parse(&bad, false).unwrap_err().to_string();
+        assert!(err.contains("target two is not a goal"), "{}", err);
+    }
+
+    #[test]
+    fn reads_interactions() {
+        let interacting = PROBLEM.replace(
+            "[output]",
+            r#"[[interaction]]
+criteria = ["rooms", "price"]
+effect = "weakening"
+coefficient = 0.2
+
+[output]"#,
+        );
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rooms.txt"), "2\n2\n1\n3\n").unwrap();
+        let run = |problem: &str| {
+            std::fs::write(dir.path().join("problem.toml"), problem).unwrap();
+            run(&dir.path().join("problem.toml"))
+        };
+        run(&interacting).unwrap();
+        let json = std::fs::read_to_string(dir.path().join("ranking.json")).unwrap();
+        let ranking: Ranking<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![1, 3, 0, 2], ranking.rank);
+
+        let err = run(&interacting.replace("\"rooms\", \"price\"", "\"rooms\", \"size\""));
+        assert_eq!(
+            "invalid problem: interaction of size, which is not a criteria",
+            err.unwrap_err().to_string()
+        );
+        let err = run(&interacting.replace("\"vanilla\"", "\"fast\""));
+        assert_eq!(
+            "invalid interaction: only the vanilla version ranks interacting criteria",
+            err.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn points_at_bad_line() {
+        let bad = PROBLEM.replace("goal = \"max\"", "goal = \"most\"");
+        let err = Problem::parse(&bad, false).unwrap_err().to_string();
+        assert!(err.contains("line 19"), "{}", err);
+
+        let bad = PROBLEM.replace("weight = 0.35", "wieght = 0.35");
+        let err = Problem::parse(&bad, false).unwrap_err().to_string();
+        assert!(err.contains("wieght") && err.contains("line 11"), "{}", err);
+    }
+}
@@ -0,0 +1,167 @@
+use std::ops::{Div, Mul, Neg, Sub};
+
+use num_traits::Pow;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{About, ComparisonFunction, Criteria, Goal};
+
+/// Criteria as saved, with its actions in a list.
+#[derive(Deserialize)]
+struct Definition<T, F> {
+    #[serde(default)]
+    about: About,
+    weight: T,
+    goal: Goal,
+    #[serde(default = "Option::default")]
+    veto: Option<T>,
+    function: F,
+    actions: Vec<Option<T>>,
+}
+
+impl<T, I, F> Serialize for Criteria<T, I, F>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy
+        + Serialize,
+    I: ExactSizeIterator<Item = T> + Clone,
+    F: ComparisonFunction<T> + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Criteria", 6)?;
+        state.serialize_field("about", &self.about)?;
+        state.serialize_field("weight", &self.weight)?;
+        state.serialize_field("goal", &self.goal)?;
+        match &self.veto {
+            Some(veto) => state.serialize_field("veto", veto)?,
+            None => state.skip_field("veto")?,
+        }
+        state.serialize_field("function", &self.function)?;
+        // Missing actions are saved as null, as JSON has no NaN
+        let actions = self
+            .actions
+            .clone()
+            .map(|action| action.partial_cmp(&action).map(|_| action))
+            .collect::<Vec<_>>();
+        state.serialize_field("actions", &actions)?;
+        state.end()
+    }
+}
+
+/// Criteria are read back with their actions in memory.
+impl<'de, T, F> Deserialize<'de> for Criteria<T, std::vec::IntoIter<T>, F>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy
+        + Deserialize<'de>,
+    F: ComparisonFunction<T> + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let definition = Definition::<T, F>::deserialize(deserializer)?;
+        Ok(Criteria {
+            actions: definition
+                .actions
+                .into_iter()
+                .map(|action| action.unwrap_or_else(|| T::from(f64::NAN)))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            weight: definition.weight,
+            function: definition.function,
+            goal: definition.goal,
+            veto: definition.veto,
+            about: definition.about,
+        })
+    }
+}
+
+/// Flows with NaN for the alternatives out of the study area, saved as null.
+pub(super) mod missing {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(in crate::promethee) fn serialize<U, S>(
+        values: &[U],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        U: PartialOrd + Serialize,
+        S: Serializer,
+    {
+        values
+            .iter()
+            .map(|value| value.partial_cmp(value).map(|_| value))
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub(in crate::promethee) fn deserialize<'de, U, D>(deserializer: D) -> Result<Vec<U>, D::Error>
+    where
+        U: From<f64> + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<Option<U>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|value| value.unwrap_or_else(|| U::from(f64::NAN)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::PreferenceFunction;
+
+    #[test]
+    fn saves_and_reads_back() {
+        let criteria = Criteria {
+            actions: vec![250.0, f64::NAN, 300.0].into_iter(),
+            weight: 0.35,
+            function: PreferenceFunction::Linear(LinearFunction { m: 100.0.into() }),
+            goal: Goal::Min,
+            veto: None,
+            about: About {
+                name: "price".to_string(),
+                unit: Some("R$".to_string()),
+                description: None,
+            },
+        };
+
+        let saved = serde_json::to_string(&criteria).unwrap();
+        assert_eq!(
+            r#"{"about":{"name":"price","unit":"R$","description":null},"weight":0.35,"goal":"min","function":{"type":"linear","m":100.0},"actions":[250.0,null,300.0]}"#,
+            saved
+        );
+
+        let read: Criteria<f64, std::vec::IntoIter<f64>, PreferenceFunction> =
+            serde_json::from_str(&saved).unwrap();
+        assert_eq!(criteria.about, read.about);
+        assert_eq!(criteria.goal, read.goal);
+        assert_eq!(
+            format!("{:?}", criteria.actions),
+            format!("{:?}", read.actions)
+        );
+
+        let (flow, rank) = vanilla::Vanilla::new(true).rank(vec![read]).unwrap();
+        let ranking = Ranking::new(
+            vec![criteria.about.clone()],
+            Alternative::numbered(3),
+            (flow, rank),
+        );
+        let saved = serde_json::to_string(&ranking).unwrap();
+        let read: Ranking<f64> = serde_json::from_str(&saved).unwrap();
+        assert_eq!(ranking.rank, read.rank);
+        assert_eq!(ranking.alternatives, read.alternatives);
+        assert!(read.flow.net_flow[1].is_nan());
+        assert_eq!(ranking.flow.net_flow[0], read.flow.net_flow[0]);
+    }
+}
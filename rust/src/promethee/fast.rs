@@ -0,0 +1,988 @@
+use std::{
+    cmp::Ordering,
+    fmt::Debug,
+    io,
+    iter::Peekable,
+    mem::swap,
+    ops::{Add, Div, Mul, Neg, Sub},
+    path::Path,
+};
+
+use super::umbu::{self, Distinct, Umbu};
+use super::vanilla::Vanilla;
+use super::*;
+use crate::external_sort::{ExternalSort, Record, Sorted, SortedIter};
+use crate::raster::{self, LineReader, RasterError, RasterInfo};
+use itertools::izip;
+use num_traits::Pow;
+
+/// Sort based Promethee from "PROMETHEE is not quadratic: An O(qnlog(n))
+/// algorithm". Preferences are summed a segment of the preference function
+/// at a time, from the count and sum of the actions falling in it.
+///
+/// Criteria with few distinct values are left to `Umbu`, and the ones whose
+/// function is not piecewise-linear are compared pair by pair, as in `Vanilla`.
+pub(crate) struct Fast {
+    divide_by_alternatives: bool,
+    sentinel: f64,
+}
+
+/// Selects the actions lower than `action + offset`, or equal to it when
+/// `inclusive`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Probe {
+    offset: f64,
+    inclusive: bool,
+}
+
+/// Probes needed to sum the preferences given by every segment of a function.
+struct Plan {
+    segments: Vec<Segment>,
+    probes: Vec<Probe>,
+    /// Actions an action is preferred to: the ones selected by the first
+    /// probe minus the ones selected by the second (none when unbounded)
+    positive: Vec<(usize, Option<usize>)>,
+    /// Actions preferred to an action: the ones selected by the first probe
+    /// (all when unbounded) minus the ones selected by the second
+    negative: Vec<(Option<usize>, usize)>,
+}
+
+impl Plan {
+    fn new(segments: Vec<Segment>) -> Self {
+        let mut plan = Self {
+            segments: vec![],
+            probes: vec![],
+            positive: vec![],
+            negative: vec![],
+        };
+
+        for segment in segments {
+            let bounded = segment.to.is_finite();
+
+            let from = plan.probe(-segment.from, segment.from_closed);
+            let to = if bounded {
+                Some(plan.probe(-segment.to, !segment.to_closed))
+            } else {
+                None
+            };
+            plan.positive.push((from, to));
+
+            let to = if bounded {
+                Some(plan.probe(segment.to, segment.to_closed))
+            } else {
+                None
+            };
+            let from = plan.probe(segment.from, !segment.from_closed);
+            plan.negative.push((to, from));
+
+            plan.segments.push(segment);
+        }
+        plan
+    }
+
+    fn probe(&mut self, offset: f64, inclusive: bool) -> usize {
+        let probe = Probe { offset, inclusive };
+        match self.probes.iter().position(|other| *other == probe) {
+            Some(position) => position,
+            None => {
+                self.probes.push(probe);
+                self.probes.len() - 1
+            }
+        }
+    }
+
+    /// Positive and negative preference sums of `action`, given the count and
+    /// sum of the actions selected by each probe and of all actions.
+    fn flows<T>(&self, action: T, selected: &[(T, T)], all: (T, T)) -> (T, T)
+    where
+        T: From<f64> + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + std::marker::Copy,
+    {
+        let none = (T::from(0.0), T::from(0.0));
+        let mut positive = T::from(0.0);
+        let mut negative = T::from(0.0);
+
+        for (segment, (from, to), (negative_to, negative_from)) in izip!(
+            self.segments.iter(),
+            self.positive.iter(),
+            self.negative.iter()
+        ) {
+            let intercept = T::from(segment.intercept);
+            let slope = T::from(segment.slope);
+
+            let upper = selected[*from];
+            let lower = to.map(|to| selected[to]).unwrap_or(none);
+            let (count, sum) = (upper.0 - lower.0, upper.1 - lower.1);
+            positive = positive + intercept * count + slope * (action * count - sum);
+
+            let upper = negative_to.map(|to| selected[to]).unwrap_or(all);
+            let lower = selected[*negative_from];
+            let (count, sum) = (upper.0 - lower.0, upper.1 - lower.1);
+            negative = negative + intercept * count + slope * (sum - action * count);
+        }
+        (positive, negative)
+    }
+}
+
+/// Valid cell of a criteria raster, sorted by value.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cell {
+    pub(crate) value: f64,
+    pub(crate) position: u64,
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Cell {}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value
+            .total_cmp(&other.value)
+            .then(self.position.cmp(&other.position))
+    }
+}
+
+impl Record for Cell {
+    const SIZE: usize = 16;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.position.to_le_bytes());
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        let mut value = [0; 8];
+        let mut position = [0; 8];
+        value.copy_from_slice(&bytes[..8]);
+        position.copy_from_slice(&bytes[8..]);
+        Self {
+            value: f64::from_le_bytes(value),
+            position: u64::from_le_bytes(position),
+        }
+    }
+}
+
+/// Flows of a cell, sorted back by position.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Scattered {
+    pub(crate) position: u64,
+    pub(crate) positive: f64,
+    pub(crate) negative: f64,
+}
+
+impl PartialEq for Scattered {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position
+    }
+}
+
+impl Eq for Scattered {}
+
+impl PartialOrd for Scattered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scattered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.position.cmp(&other.position)
+    }
+}
+
+impl Record for Scattered {
+    const SIZE: usize = 24;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[..8].copy_from_slice(&self.position.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.positive.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.negative.to_le_bytes());
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        let mut position = [0; 8];
+        let mut positive = [0; 8];
+        let mut negative = [0; 8];
+        position.copy_from_slice(&bytes[..8]);
+        positive.copy_from_slice(&bytes[8..16]);
+        negative.copy_from_slice(&bytes[16..]);
+        Self {
+            position: u64::from_le_bytes(position),
+            positive: f64::from_le_bytes(positive),
+            negative: f64::from_le_bytes(negative),
+        }
+    }
+}
+
+/// Flows of a criteria raster sorted by position, with the range of the net
+/// flows.
+struct RasterFlows {
+    info: RasterInfo,
+    flows: Sorted<Scattered>,
+    min: f64,
+    max: f64,
+}
+
+/// Walks sorted cells keeping the count and sum of the ones already passed.
+struct Cursor<'a> {
+    cells: Peekable<SortedIter<'a, Cell>>,
+    count: f64,
+    sum: f64,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(sorted: &'a Sorted<Cell>) -> io::Result<Self> {
+        Ok(Self {
+            cells: sorted.iter()?.peekable(),
+            count: 0.0,
+            sum: 0.0,
+        })
+    }
+
+    /// Count and sum of the cells lower than `threshold`, or equal to it when
+    /// `inclusive`. Thresholds must never decrease between calls.
+    fn below(&mut self, threshold: f64, inclusive: bool) -> io::Result<(f64, f64)> {
+        loop {
+            match self.cells.peek() {
+                Some(Ok(cell))
+                    if cell.value < threshold || (inclusive && cell.value == threshold) =>
+                {
+                    self.count += 1.0;
+                    self.sum += cell.value;
+                    self.cells.next();
+                }
+                Some(Err(_)) => {
+                    if let Some(Err(err)) = self.cells.next() {
+                        return Err(err);
+                    }
+                }
+                _ => return Ok((self.count, self.sum)),
+            }
+        }
+    }
+}
+
+impl Fast {
+    pub fn new(divide_by_alternatives: bool) -> Self {
+        Self {
+            divide_by_alternatives,
+            sentinel: -1.0,
+        }
+    }
+
+    /// Sets the normalized flow given to excluded alternatives (-1 by default).
+    pub fn sentinel(mut self, sentinel: f64) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
+
+    /// Flows of a single criteria whose valid actions are given sorted, which
+    /// may be more than fit in memory. `emit` gets the position, positive and
+    /// negative flow of every action, in the sorted order.
+    pub(crate) fn sorted_flow<F, E>(
+        &self,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        sorted: &Sorted<Cell>,
+        mut emit: E,
+    ) -> io::Result<()>
+    where
+        F: ComparisonFunction<f64>,
+        E: FnMut(u64, f64, f64) -> io::Result<()>,
+    {
+        let plan = match function.segments() {
+            Some(segments) => Plan::new(segments),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only piecewise-linear functions are computed out of core",
+                ))
+            }
+        };
+
+        let mut sum = 0.0;
+        for cell in sorted.iter()? {
+            sum += cell?.value;
+        }
+        let all = (sorted.len() as f64, sum);
+        let weight = if self.divide_by_alternatives {
+            weight / (sorted.len() as f64 - 1.0)
+        } else {
+            weight
+        };
+
+        // One cursor per probe, as the four pointers of the C++ version
+        let mut cursors = plan
+            .probes
+            .iter()
+            .map(|_| Cursor::new(sorted))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut selected = vec![(0.0, 0.0); plan.probes.len()];
+
+        for cell in sorted.iter()? {
+            let cell = cell?;
+            for (probe, cursor, selected) in
+                izip!(plan.probes.iter(), cursors.iter_mut(), selected.iter_mut())
+            {
+                *selected = cursor.below(cell.value + probe.offset, probe.inclusive)?;
+            }
+
+            let (mut positive, mut negative) = plan.flows(cell.value, &selected, all);
+            if goal.minimized() {
+                swap(&mut positive, &mut negative);
+            }
+            emit(cell.position, weight * positive, weight * negative)?;
+        }
+        Ok(())
+    }
+
+    /// Flows of the valid cells of a single criteria raster, computed without
+    /// holding it in memory: cells are sorted by value, their flows computed in
+    /// that order and sorted back by position.
+    fn raster_flows<F>(
+        &self,
+        input: &str,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        sorting: &ExternalSort,
+    ) -> Result<RasterFlows, RasterError>
+    where
+        F: ComparisonFunction<f64>,
+    {
+        let mut reader = LineReader::open(input)?;
+        let info = RasterInfo {
+            nodata: Some(f64::NAN),
+            ..reader.info.clone()
+        };
+
+        let mut sorter = sorting.sorter();
+        let mut line = vec![0.0; info.width as usize];
+        let mut position = 0;
+        for _ in 0..info.height {
+            reader.read_line(&mut line)?;
+            for value in line.iter() {
+                if !value.is_nan() {
+                    sorter.push(Cell {
+                        value: goal.aim(*value),
+                        position,
+                    })?;
+                }
+                position += 1;
+            }
+        }
+        // The cells are walked by a cursor per probe and one more while the
+        // flows are sorted back, each holding an equal share of the memory
+        let readers = function
+            .segments()
+            .map_or(1, |segments| Plan::new(segments).probes.len() + 1);
+        let cells = sorter.finish()?;
+        check_alternative_count(cells.len() as usize)?;
+        let cells = sorting.spill(cells, readers)?;
+
+        let mut sorter = sorting.share(readers + 1).sorter();
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        self.sorted_flow(
+            function,
+            goal,
+            weight,
+            &cells,
+            |position, positive, negative| {
+                min = min.min(positive - negative);
+                max = max.max(positive - negative);
+                sorter.push(Scattered {
+                    position,
+                    positive,
+                    negative,
+                })
+            },
+        )?;
+        drop(cells);
+
+        Ok(RasterFlows {
+            info,
+            flows: sorter.finish()?,
+            min,
+            max,
+        })
+    }
+
+    /// Ranks a single criteria raster without holding it in memory, writing
+    /// the flows as `<prefix>*flow.tif`.
+    pub(crate) fn rank_raster<F>(
+        &self,
+        input: &str,
+        prefix: &str,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        sorting: &ExternalSort,
+    ) -> Result<(), RasterError>
+    where
+        F: ComparisonFunction<f64>,
+    {
+        check_criteria(0, &About::default(), weight, function)?;
+        check_resolved(0, &About::default(), function)?;
+        check_goal(0, &About::default(), goal, None, function)?;
+        if let Some(distinct) = Distinct::of_raster(input)? {
+            return Umbu::new(self.divide_by_alternatives)
+                .sentinel(self.sentinel)
+                .rank_raster(input, prefix, function, goal, weight, &distinct);
+        }
+
+        let RasterFlows {
+            info,
+            flows,
+            min,
+            max,
+        } = self.raster_flows(input, function, goal, weight, sorting)?;
+        let paths = ["positiveflow", "negativeflow", "netflow", "normalizedflow"]
+            .iter()
+            .map(|flow| format!("{}{}.tif", prefix, flow))
+            .collect::<Vec<_>>();
+
+        let written = (|| {
+            write_scattered(&paths[0], &info, &flows, |flow| flow.positive)?;
+            write_scattered(&paths[1], &info, &flows, |flow| flow.negative)?;
+            write_scattered(&paths[2], &info, &flows, |flow| {
+                flow.positive - flow.negative
+            })?;
+
+            // Same scaling as `normalize`
+            let info = RasterInfo {
+                nodata: Some(self.sentinel),
+                ..info.clone()
+            };
+            write_scattered(&paths[3], &info, &flows, |flow| {
+                if max > min {
+                    (flow.positive - flow.negative - min) / (max - min)
+                } else {
+                    0.0
+                }
+            })
+        })();
+        raster::remove_on_error(&paths, written)
+    }
+
+    /// Writes only the weighted net flow of a single criteria raster, as the
+    /// C++ fast version did, ready to be merged with the other criteria.
+    pub(crate) fn net_flow_raster<F>(
+        &self,
+        input: &str,
+        output: &str,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        sorting: &ExternalSort,
+    ) -> Result<(), RasterError>
+    where
+        F: ComparisonFunction<f64>,
+    {
+        check_criteria(0, &About::default(), weight, function)?;
+        check_resolved(0, &About::default(), function)?;
+        check_goal(0, &About::default(), goal, None, function)?;
+        if let Some(distinct) = Distinct::of_raster(input)? {
+            return Umbu::new(self.divide_by_alternatives)
+                .sentinel(self.sentinel)
+                .net_flow_raster(input, output, function, goal, weight, &distinct);
+        }
+
+        let RasterFlows { info, flows, .. } =
+            self.raster_flows(input, function, goal, weight, sorting)?;
+        let written = write_scattered(output, &info, &flows, |flow| flow.positive - flow.negative);
+        raster::remove_on_error(&[output], written)
+    }
+}
+
+/// Writes the value of flows sorted by position as a raster, cells without
+/// flows get the nodata value of `info`.
+fn write_scattered<P, V>(
+    path: P,
+    info: &RasterInfo,
+    flows: &Sorted<Scattered>,
+    value: V,
+) -> Result<(), RasterError>
+where
+    P: AsRef<Path>,
+    V: Fn(&Scattered) -> f64,
+{
+    let missing = info.nodata.unwrap_or(f64::NAN);
+    let mut flows = flows.iter()?.peekable();
+    let mut position = 0;
+    raster::write_lines(path, info, |line| {
+        for cell in line.iter_mut() {
+            *cell = match flows.peek() {
+                Some(Ok(flow)) if flow.position == position => value(&flows.next().unwrap()?),
+                Some(Err(_)) => return Err(flows.next().unwrap().unwrap_err().into()),
+                _ => missing,
+            };
+            position += 1;
+        }
+        Ok(())
+    })
+}
+
+impl Promethee for Fast {
+    fn options(&self) -> (bool, f64) {
+        (self.divide_by_alternatives, self.sentinel)
+    }
+
+    fn flow<T, I, F>(
+        &mut self,
+        criteria: &Criteria<T, I, F>,
+        valid: &[bool],
+        mut flow: Flow<T>,
+    ) -> Flow<T>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy
+            + Send
+            + Sync,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug + Sync,
+    {
+        let actions = criteria.actions.clone().collect::<Vec<_>>();
+        let weight = criteria.weight;
+
+        let mut sorted = actions
+            .iter()
+            .zip(valid.iter())
+            .filter(|(_, valid)| **valid)
+            .map(|(action, _)| *action)
+            .collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let distinct = sorted.windows(2).filter(|pair| pair[0] != pair[1]).count() + 1;
+        if umbu::low_cardinality(distinct, sorted.len()) {
+            let distinct = Distinct::from_sorted(&sorted);
+            return Umbu::distinct_flow(criteria, &distinct, valid, flow);
+        }
+
+        let plan = match criteria.function.segments() {
+            Some(segments) => Plan::new(segments),
+            None => return Vanilla::new(self.divide_by_alternatives).flow(criteria, valid, flow),
+        };
+
+        // Targets are ranked by the distances of the actions to them
+        let (actions, sorted) = match criteria.goal {
+            Goal::Target { .. } => {
+                let actions = actions
+                    .into_iter()
+                    .map(|action| criteria.goal.aim(action))
+                    .collect::<Vec<_>>();
+                let mut sorted = actions
+                    .iter()
+                    .zip(valid.iter())
+                    .filter(|(_, valid)| **valid)
+                    .map(|(action, _)| *action)
+                    .collect::<Vec<_>>();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                (actions, sorted)
+            }
+            Goal::Max | Goal::Min => (actions, sorted),
+        };
+
+        let mut prefix = Vec::with_capacity(sorted.len() + 1);
+        prefix.push(T::from(0.0));
+        for action in sorted.iter() {
+            prefix.push(prefix[prefix.len() - 1] + *action);
+        }
+        let all = (T::from(sorted.len() as f64), prefix[sorted.len()]);
+
+        let mut selected = vec![(T::from(0.0), T::from(0.0)); plan.probes.len()];
+        for (action, positive_flow, negative_flow, _) in izip!(
+            actions.iter(),
+            flow.positive_flow.iter_mut(),
+            flow.negative_flow.iter_mut(),
+            valid.iter()
+        )
+        .filter(|(_, _, _, valid)| **valid)
+        {
+            for (probe, selected) in plan.probes.iter().zip(selected.iter_mut()) {
+                let threshold = *action + T::from(probe.offset);
+                let index = if probe.inclusive {
+                    sorted.partition_point(|other| *other <= threshold)
+                } else {
+                    sorted.partition_point(|other| *other < threshold)
+                };
+                *selected = (T::from(index as f64), prefix[index]);
+            }
+
+            let (mut positive, mut negative) = plan.flows(*action, &selected, all);
+            if criteria.goal.minimized() {
+                swap(&mut positive, &mut negative);
+            }
+
+            *positive_flow = *positive_flow + (weight * positive);
+            *negative_flow = *negative_flow + (weight * negative);
+        }
+
+        flow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::vanilla::tests::assert_approx_eq;
+    use crate::PreferenceFunction;
+
+    type Problem = Vec<Criteria<f64, std::vec::IntoIter<f64>, PreferenceFunction>>;
+
+    fn criteria(actions: Vec<f64>, function: PreferenceFunction, goal: Goal) -> Problem {
+        vec![Criteria {
+            actions: actions.into_iter(),
+            weight: 1.0,
+            function,
+            goal,
+            veto: None,
+            about: About::default(),
+        }]
+    }
+
+    fn functions() -> Vec<PreferenceFunction> {
+        vec![
+            PreferenceFunction::Usual(UsualFunction {}),
+            PreferenceFunction::Quasi(QuasiFunction { l: 2.0.into() }),
+            PreferenceFunction::Linear(LinearFunction { m: 3.0.into() }),
+            PreferenceFunction::Level(LevelFunction {
+                weak_treshold: 1.0.into(),
+                weak_area: 2.0.into(),
+            }),
+            PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
+                indiference_threshold: 1.0.into(),
+                linear_area: 3.0.into(),
+            }),
+            PreferenceFunction::Gaussian(GaussianFunction {
+                std_dev: 2.0.into(),
+            }),
+            PreferenceFunction::PiecewiseLinear(PiecewiseLinearFunction {
+                breakpoints: vec![(0.0, 0.0), (2.0, 0.3), (5.0, 0.9), (8.0, 1.0)]
+                    .into_iter()
+                    .map(Breakpoint::from)
+                    .collect(),
+            }),
+            PreferenceFunction::PiecewiseLinear(PiecewiseLinearFunction {
+                breakpoints: vec![Breakpoint::from((0.0, 0.2)), Breakpoint::from((3.0, 0.6))],
+            }),
+            PreferenceFunction::MultiLevel(MultiLevelFunction {
+                steps: vec![Breakpoint::from((0.0, 0.25)), Breakpoint::from((2.0, 1.0))],
+            }),
+            PreferenceFunction::Power(PowerFunction {
+                p: 4.0.into(),
+                exponent: 2.0,
+            }),
+            PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
+                indiference_threshold: "0.25x".parse().unwrap(),
+                linear_area: "0.5x+1".parse().unwrap(),
+            }),
+        ]
+    }
+
+    // Integers with repetitions, so that many differences hit the thresholds
+    const ACTIONS: [f64; 12] = [3.0, 0.0, 7.0, 1.0, 3.0, 4.0, 9.0, 2.0, 5.0, 3.0, 8.0, 6.0];
+
+    #[test]
+    fn same_as_vanilla() {
+        let target = Goal::Target {
+            value: 4.5,
+            below: 1.0,
+            above: 0.0,
+        };
+        for goal in &[Goal::Max, Goal::Min, target] {
+            for (want, got) in functions().into_iter().zip(functions()) {
+                let goal = || goal.clone();
+                let (want_flow, want_rank) = Vanilla::new(true)
+                    .rank(criteria(ACTIONS.to_vec(), want, goal()))
+                    .unwrap();
+                let (got_flow, got_rank) = Fast::new(true)
+                    .rank(criteria(ACTIONS.to_vec(), got, goal()))
+                    .unwrap();
+
+                assert_eq!(want_rank, got_rank);
+                assert_approx_eq(want_flow, got_flow, 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn several_criteria_with_missing_actions() {
+        let problem = || {
+            vec![
+                Criteria {
+                    actions: vec![250.0, 200.0, f64::NAN, 300.0, 275.0, 200.0].into_iter(),
+                    weight: 0.35,
+                    function: PreferenceFunction::Linear(LinearFunction { m: 100.0.into() }),
+                    goal: Goal::Min,
+                    veto: None,
+                    about: About::default(),
+                },
+                Criteria {
+                    actions: vec![16.0, 16.0, 8.0, 32.0, 32.0, 8.0].into_iter(),
+                    weight: 0.25,
+                    function: PreferenceFunction::Level(LevelFunction {
+                        weak_treshold: 8.0.into(),
+                        weak_area: 8.0.into(),
+                    }),
+                    goal: Goal::Max,
+                    veto: None,
+                    about: About::default(),
+                },
+                Criteria {
+                    actions: vec![5.0, 3.0, 4.0, 2.0, 1.0, 3.0].into_iter(),
+                    weight: 0.15,
+                    function: PreferenceFunction::Gaussian(GaussianFunction {
+                        std_dev: 1.0.into(),
+                    }),
+                    goal: Goal::Max,
+                    veto: None,
+                    about: About::default(),
+                },
+            ]
+        };
+
+        let (want_flow, want_rank) = Vanilla::new(true).rank(problem()).unwrap();
+        let (got_flow, got_rank) = Fast::new(true).rank(problem()).unwrap();
+
+        assert_eq!(want_rank, got_rank);
+        assert!(got_flow.net_flow[2].is_nan());
+        let valid = [0, 1, 3, 4, 5];
+        let pick = |flow: &Flow<f64>| Flow {
+            positive_flow: valid.iter().map(|i| flow.positive_flow[*i]).collect(),
+            negative_flow: valid.iter().map(|i| flow.negative_flow[*i]).collect(),
+            net_flow: valid.iter().map(|i| flow.net_flow[*i]).collect(),
+            normalized_flow: valid.iter().map(|i| flow.normalized_flow[*i]).collect(),
+        };
+        assert_approx_eq(pick(&want_flow), pick(&got_flow), 1e-12);
+    }
+
+    #[test]
+    fn sorted_flow_out_of_core() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Small enough to spill a run every 3 cells
+        let sorting = ExternalSort::new(48, temp_dir.path());
+
+        for function in functions().into_iter().take(5) {
+            let mut sorter = sorting.sorter();
+            for (position, value) in ACTIONS.iter().enumerate() {
+                sorter
+                    .push(Cell {
+                        value: *value,
+                        position: position as u64,
+                    })
+                    .unwrap();
+            }
+            let cells = sorter.finish().unwrap();
+
+            let mut got = vec![(0.0, 0.0); ACTIONS.len()];
+            Fast::new(true)
+                .sorted_flow(
+                    &function,
+                    &Goal::Max,
+                    1.0,
+                    &cells,
+                    |position, positive, negative| {
+                        got[position as usize] = (positive, negative);
+                        Ok(())
+                    },
+                )
+                .unwrap();
+
+            let (want, _) = Vanilla::new(true)
+                .rank(criteria(ACTIONS.to_vec(), function, Goal::Max))
+                .unwrap();
+            for (position, (positive, negative)) in got.into_iter().enumerate() {
+                assert!((want.positive_flow[position] - positive).abs() < 1e-12);
+                assert!((want.negative_flow[position] - negative).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn rank_raster_out_of_core() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("criteria.tif");
+        let prefix = temp_dir.path().join("out_").to_string_lossy().to_string();
+
+        let mut actions = ACTIONS.to_vec();
+        actions[4] = -9999.0;
+        let info = RasterInfo {
+            width: 4,
+            height: 3,
+            nodata: Some(-9999.0),
+            geo: Default::default(),
+        };
+        raster::write(&input, &info, &actions).unwrap();
+
+        let sort_dir = tempfile::tempdir().unwrap();
+        let function = PreferenceFunction::Linear(LinearFunction { m: 3.0.into() });
+        Fast::new(true)
+            .sentinel(-2.0)
+            .rank_raster(
+                &input.to_string_lossy(),
+                &prefix,
+                &function,
+                &Goal::Min,
+                1.0,
+                &ExternalSort::new(48, sort_dir.path()),
+            )
+            .unwrap();
+        assert_eq!(0, std::fs::read_dir(sort_dir.path()).unwrap().count());
+
+        actions[4] = f64::NAN;
+        let (want, _) = Vanilla::new(true)
+            .sentinel(-2.0)
+            .rank(criteria(actions.clone(), function, Goal::Min))
+            .unwrap();
+        let got = Flow {
+            positive_flow: raster::read(format!("{}positiveflow.tif", prefix))
+                .unwrap()
+                .values,
+            negative_flow: raster::read(format!("{}negativeflow.tif", prefix))
+                .unwrap()
+                .values,
+            net_flow: raster::read(format!("{}netflow.tif", prefix))
+                .unwrap()
+                .values,
+            normalized_flow: raster::read(format!("{}normalizedflow.tif", prefix))
+                .unwrap()
+                .values,
+        };
+
+        assert!(got.positive_flow[4].is_nan());
+        assert!(got.net_flow[4].is_nan());
+        // The sentinel is the nodata of the normalized flow
+        assert!(got.normalized_flow[4].is_nan());
+        let valid = (0..actions.len()).filter(|i| *i != 4).collect::<Vec<_>>();
+        let pick = |flow: &Flow<f64>| Flow {
+            positive_flow: valid.iter().map(|i| flow.positive_flow[*i]).collect(),
+            negative_flow: valid.iter().map(|i| flow.negative_flow[*i]).collect(),
+            net_flow: valid.iter().map(|i| flow.net_flow[*i]).collect(),
+            normalized_flow: valid.iter().map(|i| flow.normalized_flow[*i]).collect(),
+        };
+        assert_approx_eq(pick(&want), pick(&got), 1e-12);
+    }
+
+    #[test]
+    fn net_flow_raster_is_weighted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("criteria.tif");
+        let output = temp_dir.path().join("result_criteria.tif");
+        let info = RasterInfo {
+            width: 4,
+            height: 3,
+            nodata: None,
+            geo: Default::default(),
+        };
+        raster::write(&input, &info, &ACTIONS).unwrap();
+
+        let sorting = ExternalSort::new(48, temp_dir.path()).threads(2);
+        let function = PreferenceFunction::Quasi(QuasiFunction { l: 2.0.into() });
+        Fast::new(true)
+            .net_flow_raster(
+                &input.to_string_lossy(),
+                &output.to_string_lossy(),
+                &function,
+                &Goal::Max,
+                0.5,
+                &sorting,
+            )
+            .unwrap();
+
+        let (want, _) = Vanilla::new(true)
+            .rank(criteria(ACTIONS.to_vec(), function, Goal::Max))
+            .unwrap();
+        let got = raster::read(&output).unwrap().values;
+        for (want, got) in want.net_flow.iter().zip(got.iter()) {
+            assert!((0.5 * want - got).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn rasters_need_two_alternatives() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("criteria.tif");
+        let prefix = temp_dir.path().join("out_").to_string_lossy().to_string();
+        let info = RasterInfo {
+            width: 2,
+            height: 2,
+            nodata: Some(-9999.0),
+            geo: Default::default(),
+        };
+        let function = PreferenceFunction::Linear(LinearFunction { m: 3.0.into() });
+
+        for (actions, alternatives) in &[
+            (vec![-9999.0; 4], 0),
+            (vec![-9999.0, 5.0, -9999.0, -9999.0], 1),
+        ] {
+            raster::write(&input, &info, actions).unwrap();
+            let ranked = Fast::new(true).rank_raster(
+                &input.to_string_lossy(),
+                &prefix,
+                &function,
+                &Goal::Max,
+                1.0,
+                &ExternalSort::new(48, temp_dir.path()),
+            );
+            assert!(matches!(
+                ranked,
+                Err(RasterError::Promethee(PrometheeError::TooFewAlternatives(n))) if n == *alternatives
+            ));
+            assert_eq!(1, std::fs::read_dir(temp_dir.path()).unwrap().count());
+        }
+    }
+
+    #[test]
+    fn failed_ranking_leaves_no_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("criteria.tif");
+        let output = temp_dir.path().join("result_criteria.tif");
+        let info = RasterInfo {
+            width: 4,
+            height: 3,
+            nodata: None,
+            geo: Default::default(),
+        };
+        raster::write(&input, &info, &ACTIONS).unwrap();
+
+        // Not piecewise-linear, so it can not be ranked out of core
+        let function = PreferenceFunction::Gaussian(GaussianFunction {
+            std_dev: 1.0.into(),
+        });
+        let ranked = Fast::new(true).net_flow_raster(
+            &input.to_string_lossy(),
+            &output.to_string_lossy(),
+            &function,
+            &Goal::Max,
+            1.0,
+            &ExternalSort::new(48, temp_dir.path()),
+        );
+
+        assert!(ranked.is_err());
+        let mut left = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(vec!["criteria.tif"], left);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+use super::*;
+
+/// How a pair of criteria interact, following PROMETHEE with interacting
+/// criteria (Corrente, Figueira & Greco). Interactions are only read from
+/// problem files.
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub(crate) enum Effect {
+    /// Preferring an alternative on both criteria counts for more than the
+    /// sum of their weights.
+    Strengthening,
+    /// Preferring an alternative on both criteria counts for less, as with
+    /// correlated criteria that would otherwise be counted twice.
+    Weakening,
+    /// Preferring an alternative on the first criteria counts for less when
+    /// the other alternative is preferred on the second one.
+    Antagonism,
+}
+
+/// Interaction of the criteria at `first` and `second`. Its coefficient is
+/// in the same units as the weights, and is always given as positive: the
+/// effect tells whether it adds or takes away.
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct Interaction {
+    pub(crate) first: usize,
+    pub(crate) second: usize,
+    pub(crate) effect: Effect,
+    pub(crate) coefficient: f64,
+}
+
+/// Checks the interactions of the `criteria`, given by their `About` and
+/// weight: each links two distinct criteria once, and no criteria is weakened
+/// or antagonized by more than its own weight, so preferring an alternative on
+/// a criteria never makes it worse.
+pub(crate) fn check_interactions<'a, T, C>(
+    interactions: &[Interaction],
+    criteria: C,
+) -> Result<(), PrometheeError>
+where
+    T: From<f64> + Add<Output = T> + Sub<Output = T> + PartialOrd + Copy + 'a,
+    C: IntoIterator<Item = (&'a About, T)>,
+{
+    if interactions.is_empty() {
+        return Ok(());
+    }
+    let criteria = criteria.into_iter().collect_vec();
+    let invalid = |reason: String| Err(PrometheeError::InvalidInteraction(reason));
+
+    let mut left = criteria.iter().map(|(_, weight)| *weight).collect_vec();
+    let mut total = left
+        .iter()
+        .fold(T::from(0.0), |total, weight| total + *weight);
+    let mut pairs = BTreeMap::new();
+    for interaction in interactions {
+        let (first, second) = (interaction.first, interaction.second);
+        if let Some(index) = [first, second]
+            .iter()
+            .find(|index| **index >= criteria.len())
+        {
+            return invalid(format!("criteria {} does not exist", index + 1));
+        }
+        let name = |index: usize| label(index, criteria[index].0);
+        if first == second {
+            return invalid(format!("{} can not interact with itself", name(first)));
+        }
+        let coefficient = interaction.coefficient;
+        if !(coefficient > 0.0 && coefficient.is_finite()) {
+            return invalid(format!(
+                "coefficient of {} and {} must be finite and positive, not {}",
+                name(first),
+                name(second),
+                coefficient
+            ));
+        }
+        // Mutual effects go both ways, antagonism has a direction, and a pair
+        // can not be both
+        let mutual = interaction.effect != Effect::Antagonism;
+        let key = (first.min(second), first.max(second));
+        let directions = pairs.entry(key).or_insert((false, false, false));
+        let taken = match (mutual, first < second) {
+            (true, _) => directions.0 || directions.1 || directions.2,
+            (false, true) => directions.0 || directions.1,
+            (false, false) => directions.0 || directions.2,
+        };
+        if taken {
+            return invalid(format!(
+                "{} and {} interact more than once",
+                name(first),
+                name(second)
+            ));
+        }
+        match (mutual, first < second) {
+            (true, _) => directions.0 = true,
+            (false, true) => directions.1 = true,
+            (false, false) => directions.2 = true,
+        }
+
+        let coefficient = T::from(coefficient);
+        match interaction.effect {
+            Effect::Strengthening => total = total + coefficient,
+            Effect::Weakening => {
+                left[first] = left[first] - coefficient;
+                left[second] = left[second] - coefficient;
+                total = total - coefficient;
+            }
+            Effect::Antagonism => {
+                left[first] = left[first] - coefficient;
+                total = total - coefficient;
+            }
+        }
+    }
+
+    if let Some(index) = left.iter().position(|left| *left < T::from(0.0)) {
+        return invalid(format!(
+            "{} is weakened or antagonized by more than its weight",
+            label(index, criteria[index].0)
+        ));
+    }
+    if total.partial_cmp(&T::from(0.0)) != Some(Ordering::Greater) {
+        return invalid("weights and interactions sum to zero".to_string());
+    }
+    Ok(())
+}
+
+/// Actions of the criteria that interact, kept criteria by criteria along
+/// with the flows, as the interactions depend on more than one criteria. Once
+/// every criteria is, the interactions of each pair are computed from the
+/// actions and added to the flows, which are normalized again. Only the
+/// actions are kept, not the preferences of every pair.
+pub(crate) struct Interacting<T, F> {
+    /// Interactions with their coefficient over the total weight, negative
+    /// when it weakens or antagonizes.
+    interactions: Vec<(usize, usize, Effect, T)>,
+    /// Criteria that interact, by index.
+    kept: BTreeMap<usize, Kept<T, F>>,
+    n: usize,
+    /// Weights and coefficients added up, over the total weight.
+    total: T,
+}
+
+/// What is kept of a criteria to compare its alternatives again.
+struct Kept<T, F> {
+    function: F,
+    /// Actions aimed at the goal
+    actions: Vec<T>,
+    /// Actions as read, where variable thresholds are evaluated
+    read: Vec<T>,
+    minimized: bool,
+}
+
+impl<T, F> Interacting<T, F>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy,
+    F: ComparisonFunction<T>,
+{
+    pub(crate) fn new(interactions: &[Interaction], total_weight: T, n: usize) -> Self {
+        let mut total = T::from(1.0);
+        let interactions = interactions
+            .iter()
+            .map(|interaction| {
+                let coefficient = T::from(interaction.coefficient) / total_weight;
+                let coefficient = match interaction.effect {
+                    Effect::Strengthening => coefficient,
+                    Effect::Weakening | Effect::Antagonism => -coefficient,
+                };
+                total = total + coefficient;
+                (
+                    interaction.first,
+                    interaction.second,
+                    interaction.effect,
+                    coefficient,
+                )
+            })
+            .collect();
+        Self {
+            interactions,
+            kept: BTreeMap::new(),
+            n,
+            total,
+        }
+    }
+
+    /// Keeps the actions and function of the criteria at `index`, if it
+    /// interacts with any other.
+    pub(crate) fn add<I>(&mut self, index: usize, criteria: Criteria<T, I, F>)
+    where
+        I: ExactSizeIterator<Item = T> + Clone,
+    {
+        let interacts = self
+            .interactions
+            .iter()
+            .any(|(first, second, _, _)| *first == index || *second == index);
+        if !interacts {
+            return;
+        }
+        let Criteria {
+            actions,
+            function,
+            goal,
+            ..
+        } = criteria;
+        let read = actions.collect_vec();
+        let actions = read.iter().map(|action| goal.aim(*action)).collect_vec();
+        self.kept.insert(
+            index,
+            Kept {
+                function,
+                actions,
+                read,
+                minimized: goal.minimized(),
+            },
+        );
+    }
+
+    /// What the interactions add to the preference of `a` over `b`.
+    fn interaction(&self, a: usize, b: usize) -> T {
+        let preference = |criteria: usize, a: usize, b: usize| match self.kept.get(&criteria) {
+            Some(kept) => {
+                let lower = if kept.read[a] < kept.read[b] {
+                    kept.read[a]
+                } else {
+                    kept.read[b]
+                };
+                let (better, worse) = if kept.minimized { (b, a) } else { (a, b) };
+                kept.function
+                    .compare_at(kept.actions[better], kept.actions[worse], lower)
+            }
+            None => T::from(0.0),
+        };
+        self.interactions
+            .iter()
+            .fold(T::from(0.0), |sum, (first, second, effect, coefficient)| {
+                let other = match effect {
+                    Effect::Antagonism => preference(*second, b, a),
+                    _ => preference(*second, a, b),
+                };
+                sum + *coefficient * preference(*first, a, b) * other
+            })
+    }
+
+    /// Preference of `a` over `b` once the interactions are added to the
+    /// `additive` one, the weighted sum over the criteria.
+    pub(crate) fn aggregate(&self, a: usize, b: usize, additive: T) -> T {
+        if self.interactions.is_empty() {
+            return additive;
+        }
+        (additive + self.interaction(a, b)) / self.total
+    }
+
+    /// Adds the interactions of every pair of `valid` alternatives to the
+    /// summed flows, normalized so that the weights and coefficients add up
+    /// to one.
+    pub(crate) fn interact(&self, flow: &mut Flow<T>, valid: &[bool]) {
+        if self.interactions.is_empty() {
+            return;
+        }
+        for a in (0..self.n).filter(|a| valid[*a]) {
+            for b in (0..self.n).filter(|b| valid[*b] && *b != a) {
+                let interaction = self.interaction(a, b);
+                flow.positive_flow[a] = flow.positive_flow[a] + interaction;
+                flow.negative_flow[b] = flow.negative_flow[b] + interaction;
+            }
+        }
+        for (positive, negative) in flow
+            .positive_flow
+            .iter_mut()
+            .zip(flow.negative_flow.iter_mut())
+        {
+            *positive = *positive / self.total;
+            *negative = *negative / self.total;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::vanilla::tests::assert_approx_eq;
+    use crate::promethee::vanilla::Vanilla;
+
+    fn criteria(
+        name: &str,
+        actions: Vec<f64>,
+    ) -> Criteria<f64, std::vec::IntoIter<f64>, UsualFunction> {
+        Criteria {
+            actions: actions.into_iter(),
+            weight: 1.0,
+            function: UsualFunction {},
+            goal: Goal::Max,
+            veto: None,
+            about: About {
+                name: name.to_string(),
+                ..About::default()
+            },
+        }
+    }
+
+    fn interaction(first: usize, second: usize, effect: Effect, coefficient: f64) -> Interaction {
+        Interaction {
+            first,
+            second,
+            effect,
+            coefficient,
+        }
+    }
+
+    #[test]
+    fn weakens_correlated_criteria() {
+        let problem = || {
+            vec![
+                criteria("rainfall", vec![3.0, 2.0, 1.0]),
+                criteria("vegetation", vec![3.0, 1.0, 2.0]),
+            ]
+        };
+        let (want, _) = Vanilla::new(true).rank(problem()).unwrap();
+        let (got, _) = Vanilla::new(true)
+            .with_interactions(vec![])
+            .rank(problem())
+            .unwrap();
+        assert_approx_eq(want, got, 1e-12);
+
+        // The first alternative is preferred on both criteria, which now
+        // count as 0.5 + 0.5 - 0.2 out of 0.8, and the others on one each,
+        // 0.5 out of 0.8
+        let (flow, rank) = Vanilla::new(true)
+            .with_interactions(vec![interaction(0, 1, Effect::Weakening, 0.4)])
+            .rank(problem())
+            .unwrap();
+        let want = Flow {
+            positive_flow: vec![1.0, 0.3125, 0.3125],
+            negative_flow: vec![0.0, 0.8125, 0.8125],
+            net_flow: vec![1.0, -0.5, -0.5],
+            normalized_flow: vec![1.0, 0.0, 0.0],
+        };
+        assert_approx_eq(want, flow, 1e-12);
+        assert_eq!(0, rank[0]);
+    }
+
+    #[test]
+    fn antagonizes_a_single_way() {
+        let problem = || {
+            vec![
+                criteria("yield", vec![2.0, 1.0]),
+                criteria("erosion", vec![2.0, 1.0]),
+            ]
+        };
+        // The second alternative erodes less, so the first is preferred less
+        // on yield, and the other way around nothing changes
+        let (flow, _) = Vanilla::new(true)
+            .with_interactions(vec![interaction(0, 1, Effect::Antagonism, 0.5)])
+            .rank(vec![
+                criteria("yield", vec![2.0, 1.0]),
+                Criteria {
+                    goal: Goal::Min,
+                    ..criteria("erosion", vec![2.0, 1.0])
+                },
+            ])
+            .unwrap();
+        // 0.5 - 0.25 out of 0.75 for the first, 0.5 out of 0.75 for the second
+        assert!((flow.positive_flow[0] - 1.0 / 3.0).abs() < 1e-12);
+        assert!((flow.positive_flow[1] - 2.0 / 3.0).abs() < 1e-12);
+
+        let (flow, _) = Vanilla::new(true)
+            .with_interactions(vec![interaction(0, 1, Effect::Strengthening, 1.0)])
+            .rank(problem())
+            .unwrap();
+        assert!((flow.positive_flow[0] - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_bad_interactions() {
+        let rank = |interactions| {
+            Vanilla::new(true)
+                .with_interactions(interactions)
+                .rank(vec![
+                    criteria("rainfall", vec![3.0, 2.0, 1.0]),
+                    criteria("vegetation", vec![3.0, 1.0, 2.0]),
+                ])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            "invalid interaction: rainfall can not interact with itself",
+            rank(vec![interaction(0, 0, Effect::Weakening, 0.5)])
+        );
+        assert_eq!(
+            "invalid interaction: criteria 3 does not exist",
+            rank(vec![interaction(0, 2, Effect::Weakening, 0.5)])
+        );
+        assert_eq!(
+            "invalid interaction: coefficient of rainfall and vegetation must be finite and positive, not -0.5",
+            rank(vec![interaction(0, 1, Effect::Weakening, -0.5)])
+        );
+        assert_eq!(
+            "invalid interaction: vegetation and rainfall interact more than once",
+            rank(vec![
+                interaction(0, 1, Effect::Antagonism, 0.5),
+                interaction(1, 0, Effect::Weakening, 0.5),
+            ])
+        );
+        assert_eq!(
+            "invalid interaction: rainfall is weakened or antagonized by more than its weight",
+            rank(vec![interaction(0, 1, Effect::Weakening, 1.5)])
+        );
+    }
+}
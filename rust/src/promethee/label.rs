@@ -0,0 +1,290 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+};
+
+use super::{Flow, Resolved};
+
+/// What a criteria measures, so results can be told apart by name.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub(crate) struct About {
+    pub(crate) name: String,
+    pub(crate) unit: Option<String>,
+    pub(crate) description: Option<String>,
+}
+
+impl Display for About {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(unit) = &self.unit {
+            write!(f, " ({})", unit)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, ": {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies an alternative, by a given ID or by its cell in a raster.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(untagged)
+)]
+pub(crate) enum Alternative {
+    Id(String),
+    Cell { line: u32, column: u32 },
+}
+
+impl Alternative {
+    /// Alternatives known only by their position, numbered from 0.
+    pub(crate) fn numbered(n: usize) -> Vec<Self> {
+        (0..n).map(|i| Alternative::Id(i.to_string())).collect()
+    }
+
+    /// Cells of a `width` by `height` raster, line by line.
+    pub(crate) fn cells(width: u32, height: u32) -> Vec<Self> {
+        (0..height)
+            .flat_map(|line| (0..width).map(move |column| Alternative::Cell { line, column }))
+            .collect()
+    }
+}
+
+impl Display for Alternative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alternative::Id(id) => write!(f, "{}", id),
+            Alternative::Cell { line, column } => write!(f, "{},{}", line, column),
+        }
+    }
+}
+
+/// Flows and rank of labeled alternatives.
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "U: PartialOrd + serde::Serialize",
+        deserialize = "U: From<f64> + serde::Deserialize<'de>"
+    ))
+)]
+pub(crate) struct Ranking<U> {
+    pub(crate) criteria: Vec<About>,
+    pub(crate) alternatives: Vec<Alternative>,
+    pub(crate) flow: Flow<U>,
+    pub(crate) rank: Vec<usize>,
+    /// Thresholds of each criteria resolved from their actions, empty when
+    /// none was relative.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) thresholds: Vec<Vec<Resolved>>,
+    /// Number of pairs of alternatives where one can never outrank the
+    /// other by the veto of each criteria, empty when none has a veto.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) vetoes: Vec<usize>,
+}
+
+/// Everything the ranking says about a single alternative.
+#[derive(PartialEq, Debug)]
+pub(crate) struct Outcome<'a, U> {
+    pub(crate) alternative: &'a Alternative,
+    /// Position in the rank, the best alternative being the first.
+    pub(crate) place: usize,
+    pub(crate) positive_flow: U,
+    pub(crate) negative_flow: U,
+    pub(crate) net_flow: U,
+    pub(crate) normalized_flow: U,
+}
+
+impl<U> Ranking<U> {
+    /// Labels the result of ranking the alternatives on the given criteria.
+    pub(crate) fn new(
+        criteria: Vec<About>,
+        alternatives: Vec<Alternative>,
+        (flow, rank): (Flow<U>, Vec<usize>),
+    ) -> Self {
+        assert_eq!(
+            alternatives.len(),
+            rank.len(),
+            "every alternative must have an ID"
+        );
+        Self {
+            criteria,
+            alternatives,
+            flow,
+            rank,
+            thresholds: vec![],
+            vetoes: vec![],
+        }
+    }
+
+    /// Tells what the relative thresholds of each criteria were resolved to.
+    pub(crate) fn with_thresholds(mut self, thresholds: Vec<Vec<Resolved>>) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Tells how many pairs of alternatives each criteria vetoed.
+    pub(crate) fn with_vetoes(mut self, vetoes: Vec<usize>) -> Self {
+        self.vetoes = vetoes;
+        self
+    }
+
+    /// Resolved thresholds, along with the criteria they belong to.
+    pub(crate) fn resolved(&self) -> impl Iterator<Item = (&About, &Resolved)> {
+        self.criteria
+            .iter()
+            .zip(self.thresholds.iter())
+            .flat_map(|(about, thresholds)| {
+                thresholds.iter().map(move |resolved| (about, resolved))
+            })
+    }
+
+    /// Criteria that vetoed any pair, along with how many they vetoed.
+    pub(crate) fn vetoed(&self) -> impl Iterator<Item = (&About, usize)> {
+        self.criteria
+            .iter()
+            .zip(self.vetoes.iter())
+            .filter(|(_, count)| **count > 0)
+            .map(|(about, count)| (about, *count))
+    }
+}
+
+impl<U: Copy> Ranking<U> {
+    fn outcome(&self, place: usize) -> Outcome<'_, U> {
+        let index = self.rank[place];
+        Outcome {
+            alternative: &self.alternatives[index],
+            place: place + 1,
+            positive_flow: self.flow.positive_flow[index],
+            negative_flow: self.flow.negative_flow[index],
+            net_flow: self.flow.net_flow[index],
+            normalized_flow: self.flow.normalized_flow[index],
+        }
+    }
+
+    /// Outcome of the alternative labeled `label`, as an ID or as `line,column`.
+    pub(crate) fn get(&self, label: &str) -> Option<Outcome<'_, U>> {
+        let place = self
+            .rank
+            .iter()
+            .position(|index| self.alternatives[*index].to_string() == label)?;
+        Some(self.outcome(place))
+    }
+
+    /// Outcomes from the best alternative to the worst.
+    pub(crate) fn outcomes(&self) -> impl Iterator<Item = Outcome<'_, U>> {
+        (0..self.rank.len()).map(move |place| self.outcome(place))
+    }
+}
+
+impl<U: Copy + Display> Ranking<U> {
+    /// Writes the criteria as comments and then a tab separated table of the
+    /// `outcomes`, such as every one of `outcomes()`.
+    pub(crate) fn write_table<'a, W, O>(&self, mut writer: W, outcomes: O) -> io::Result<()>
+    where
+        W: Write,
+        O: IntoIterator<Item = Outcome<'a, U>>,
+        U: 'a,
+    {
+        for about in self.criteria.iter() {
+            writeln!(writer, "# {}", about)?;
+        }
+        for (about, resolved) in self.resolved() {
+            writeln!(writer, "# {}: {}", about.name, resolved)?;
+        }
+        for (about, count) in self.vetoed() {
+            writeln!(writer, "# {}: {} pairs vetoed", about.name, count)?;
+        }
+        writeln!(
+            writer,
+            "place\talternative\tpositive_flow\tnegative_flow\tnet_flow\tnormalized_flow"
+        )?;
+        for outcome in outcomes {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                outcome.place,
+                outcome.alternative,
+                outcome.positive_flow,
+                outcome.negative_flow,
+                outcome.net_flow,
+                outcome.normalized_flow
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranking() -> Ranking<f64> {
+        Ranking::new(
+            vec![About {
+                name: "price".to_string(),
+                unit: Some("R$".to_string()),
+                description: Some("Monthly rent".to_string()),
+            }],
+            vec![
+                Alternative::Id("Recife".to_string()),
+                Alternative::Id("Natal".to_string()),
+                Alternative::Id("Patos".to_string()),
+            ],
+            (
+                Flow {
+                    positive_flow: vec![0.25, 0.0, 0.75],
+                    negative_flow: vec![0.25, 0.75, 0.0],
+                    net_flow: vec![0.0, -0.75, 0.75],
+                    normalized_flow: vec![0.5, 0.0, 1.0],
+                },
+                vec![2, 0, 1],
+            ),
+        )
+    }
+
+    #[test]
+    fn queries_by_label() {
+        let ranking = ranking();
+
+        let natal = ranking.get("Natal").unwrap();
+        assert_eq!(3, natal.place);
+        assert_eq!(-0.75, natal.net_flow);
+        assert_eq!(0.0, natal.normalized_flow);
+        assert!(ranking.get("Campina").is_none());
+
+        let best = ranking.outcomes().next().unwrap();
+        assert_eq!(&Alternative::Id("Patos".to_string()), best.alternative);
+    }
+
+    #[test]
+    fn labels_cells() {
+        let cells = Alternative::cells(2, 2);
+        assert_eq!(Alternative::Cell { line: 1, column: 0 }, cells[2]);
+        assert_eq!("1,0", cells[2].to_string());
+    }
+
+    #[test]
+    fn writes_named_table() {
+        let ranking = ranking();
+        let mut table = vec![];
+        ranking.write_table(&mut table, ranking.outcomes()).unwrap();
+
+        assert_eq!(
+            "# price (R$): Monthly rent\n\
+             place\talternative\tpositive_flow\tnegative_flow\tnet_flow\tnormalized_flow\n\
+             1\tPatos\t0.75\t0\t0.75\t1\n\
+             2\tRecife\t0.25\t0.25\t0\t0.5\n\
+             3\tNatal\t0\t0.75\t-0.75\t0\n",
+            String::from_utf8(table).unwrap()
+        );
+    }
+}
@@ -1,20 +1,155 @@
+#[cfg(feature = "serde")]
+mod definition;
+pub(crate) mod fast;
+pub(crate) mod interaction;
+pub(crate) mod label;
+pub(crate) mod normalize;
+pub(crate) mod stream;
+pub(crate) mod umbu;
 pub(crate) mod vanilla;
+pub(crate) mod veto;
+use itertools::{izip, Itertools};
 use num_traits::Pow;
 
 pub(crate) use crate::function::*;
+#[cfg(feature = "serde")]
+pub(crate) use interaction::Effect;
+pub(crate) use interaction::Interaction;
+use interaction::{check_interactions, Interacting};
+pub(crate) use label::{About, Alternative, Ranking};
+use normalize::normalize;
 use std::{
-    fmt::Debug,
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt::{self, Debug},
+    io,
     ops::{Add, Div, Mul, Neg, Sub},
+    path::Path,
+    str::FromStr,
 };
+pub(crate) use stream::StreamedCriteria;
+use veto::Vetoes;
 
+/// Why criteria can not be ranked. Criteria are named by their `About`, or
+/// as `criteria 2` by their position when they have no name.
 #[derive(PartialEq, Debug)]
+pub(crate) enum PrometheeError {
+    NoCriteria,
+    /// Fewer than two alternatives have an action in every criteria.
+    TooFewAlternatives(usize),
+    DifferentLengths {
+        criteria: String,
+        found: usize,
+        first: String,
+        expected: usize,
+    },
+    /// A negative, infinite or NaN weight.
+    InvalidWeight(String),
+    /// A veto threshold that is not positive and finite.
+    InvalidVeto(String),
+    /// Interactions that can not be between the criteria, or would make
+    /// preferring an alternative on a criteria count against it.
+    InvalidInteraction(String),
+    ZeroWeights,
+    /// A target that is not finite, or tolerances that are negative or not
+    /// finite.
+    InvalidGoal {
+        criteria: String,
+        reason: String,
+    },
+    InvalidFunction {
+        criteria: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for PrometheeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrometheeError::NoCriteria => write!(f, "no criteria to rank"),
+            PrometheeError::TooFewAlternatives(1) => write!(
+                f,
+                "only 1 alternative has every action, at least 2 are needed"
+            ),
+            PrometheeError::TooFewAlternatives(n) => write!(
+                f,
+                "only {} alternatives have every action, at least 2 are needed",
+                n
+            ),
+            PrometheeError::DifferentLengths {
+                criteria,
+                found,
+                first,
+                expected,
+            } => write!(
+                f,
+                "{} has {} actions, but {} has {}",
+                criteria, found, first, expected
+            ),
+            PrometheeError::InvalidWeight(criteria) => {
+                write!(f, "weight of {} must be finite and non-negative", criteria)
+            }
+            PrometheeError::InvalidVeto(criteria) => {
+                write!(f, "veto of {} must be finite and positive", criteria)
+            }
+            PrometheeError::InvalidInteraction(reason) => {
+                write!(f, "invalid interaction: {}", reason)
+            }
+            PrometheeError::ZeroWeights => write!(f, "criteria weights sum to zero"),
+            PrometheeError::InvalidGoal { criteria, reason } => {
+                write!(f, "goal of {}: {}", criteria, reason)
+            }
+            PrometheeError::InvalidFunction { criteria, reason } => {
+                write!(f, "preference function of {}: {}", criteria, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PrometheeError {}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "U: PartialOrd + serde::Serialize",
+        deserialize = "U: From<f64> + serde::Deserialize<'de>"
+    ))
+)]
 pub(crate) struct Flow<U> {
-    positive_flow: Vec<U>,
-    negative_flow: Vec<U>,
-    net_flow: Vec<U>,
+    #[cfg_attr(feature = "serde", serde(with = "definition::missing"))]
+    pub(crate) positive_flow: Vec<U>,
+    #[cfg_attr(feature = "serde", serde(with = "definition::missing"))]
+    pub(crate) negative_flow: Vec<U>,
+    #[cfg_attr(feature = "serde", serde(with = "definition::missing"))]
+    pub(crate) net_flow: Vec<U>,
+    #[cfg_attr(feature = "serde", serde(with = "definition::missing"))]
+    pub(crate) normalized_flow: Vec<U>,
+}
+
+impl<U: From<f64> + Clone> Flow<U> {
+    /// Zero flows of `n` alternatives, to add the criteria up to.
+    fn new(n: usize) -> Self {
+        Self {
+            positive_flow: vec![U::from(0.0); n],
+            negative_flow: vec![U::from(0.0); n],
+            net_flow: vec![U::from(0.0); n],
+            normalized_flow: vec![],
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// Flows and rank of criteria, with the number of pairs of alternatives each
+/// vetoed.
+pub(crate) type Vetoed<T> = (Flow<T>, Vec<usize>, Vec<usize>);
+
+/// Flows and rank of criteria read only once each, with the thresholds each
+/// criteria had resolved from its actions and the number of pairs each
+/// vetoed.
+pub(crate) type Streamed = (Flow<f64>, Vec<usize>, Vec<Vec<Resolved>>, Vec<usize>);
+
+#[derive(PartialEq, Debug)]
 pub(crate) struct Criteria<T, I, F>
 where
     T: From<f64>
@@ -32,16 +167,211 @@ where
     pub(crate) weight: T,
     pub(crate) function: F,
     pub(crate) goal: Goal,
+    /// Difference, in the actions compared, beyond which the worse
+    /// alternative can never outrank the better one.
+    pub(crate) veto: Option<T>,
+    pub(crate) about: About,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// Which actions of a criteria are better. Written as `max`, `min` or
+/// `target value`, followed by the tolerance under and over the target when
+/// there is some, a single one for both sides.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
 pub(crate) enum Goal {
     Max,
     Min,
+    /// Actions are better the closer they are to `value`, those within
+    /// `below` under it or `above` over it being on target.
+    Target {
+        value: f64,
+        below: f64,
+        above: f64,
+    },
+}
+
+impl Goal {
+    /// What the engines compare of an action: its distance to the target,
+    /// or the action itself. Missing actions stay missing.
+    #[allow(clippy::eq_op)]
+    pub(crate) fn aim<T>(&self, action: T) -> T
+    where
+        T: From<f64> + Sub<Output = T> + PartialOrd + Copy,
+    {
+        match *self {
+            Goal::Target {
+                value,
+                below,
+                above,
+            } => {
+                // Only a missing action, NaN, is not equal to itself
+                if action != action {
+                    return action;
+                }
+                let (low, high) = (T::from(value - below), T::from(value + above));
+                if action < low {
+                    low - action
+                } else if action > high {
+                    action - high
+                } else {
+                    T::from(0.0)
+                }
+            }
+            Goal::Max | Goal::Min => action,
+        }
+    }
+
+    /// Whether the lesser of the aimed actions are better, as distances to a
+    /// target are.
+    pub(crate) fn minimized(&self) -> bool {
+        !matches!(self, Goal::Max)
+    }
+
+    /// Checks a target is finite and its tolerances finite and non-negative.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let (value, below, above) = match *self {
+            Goal::Target {
+                value,
+                below,
+                above,
+            } => (value, below, above),
+            Goal::Max | Goal::Min => return Ok(()),
+        };
+        if !value.is_finite() {
+            return Err(format!("target must be finite, not {}", value));
+        }
+        match [below, above]
+            .iter()
+            .find(|tolerance| !(**tolerance >= 0.0 && tolerance.is_finite()))
+        {
+            Some(tolerance) => Err(format!(
+                "tolerance must be finite and non-negative, not {}",
+                tolerance
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Goal::Max => write!(f, "max"),
+            Goal::Min => write!(f, "min"),
+            Goal::Target {
+                value,
+                below,
+                above,
+            } if below == above && *below == 0.0 => write!(f, "target {}", value),
+            Goal::Target {
+                value,
+                below,
+                above,
+            } if below == above => write!(f, "target {} {}", value, below),
+            Goal::Target {
+                value,
+                below,
+                above,
+            } => write!(f, "target {} {} {}", value, below, above),
+        }
+    }
 }
 
-pub(crate) trait Promethee {
-    fn rank<T, I, F>(self, criterias: Vec<Criteria<T, I, F>>) -> (Flow<T>, Vec<usize>)
+impl FromStr for Goal {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words = text.split_whitespace().collect_vec();
+        let wrong = || {
+            format!(
+                "{} is not a goal, write max, min or target followed by its value and tolerances",
+                text
+            )
+        };
+        match words.first().map(|word| word.to_lowercase()).as_deref() {
+            Some("max") if words.len() == 1 => return Ok(Goal::Max),
+            Some("min") if words.len() == 1 => return Ok(Goal::Min),
+            Some("target") if (2..=4).contains(&words.len()) => {}
+            _ => return Err(wrong()),
+        }
+        let numbers = words[1..]
+            .iter()
+            .map(|word| word.parse::<f64>().map_err(|_| wrong()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (value, below, above) = match numbers[..] {
+            [value] => (value, 0.0, 0.0),
+            [value, tolerance] => (value, tolerance, tolerance),
+            [value, below, above] => (value, below, above),
+            _ => unreachable!(),
+        };
+        let goal = Goal::Target {
+            value,
+            below,
+            above,
+        };
+        goal.validate()?;
+        Ok(goal)
+    }
+}
+
+impl TryFrom<String> for Goal {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Goal> for String {
+    fn from(goal: Goal) -> Self {
+        goal.to_string()
+    }
+}
+
+pub(crate) trait Promethee: Sized {
+    /// Whether flows are divided by the alternatives compared, and the
+    /// normalized flow given to alternatives out of the study area.
+    fn options(&self) -> (bool, f64);
+
+    /// Interactions between the criteria, added to the aggregated preference
+    /// of every pair of alternatives. None but the vanilla version has them.
+    fn interactions(&self) -> &[Interaction] {
+        &[]
+    }
+
+    /// Adds the weighted preference sums of a criteria, comparing only the
+    /// `valid` alternatives.
+    fn flow<T, I, F>(
+        &mut self,
+        criteria: &Criteria<T, I, F>,
+        valid: &[bool],
+        flow: Flow<T>,
+    ) -> Flow<T>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy
+            + Send
+            + Sync,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug + Sync;
+
+    /// Ranks the criteria, leaving out which pairs were vetoed.
+    #[cfg(test)]
+    fn rank<T, I, F>(
+        self,
+        criterias: Vec<Criteria<T, I, F>>,
+    ) -> Result<(Flow<T>, Vec<usize>), PrometheeError>
     where
         T: From<f64>
             + Neg<Output = T>
@@ -51,7 +381,528 @@ pub(crate) trait Promethee {
             + Mul<Output = T>
             + Pow<T, Output = T>
             + PartialOrd
-            + std::marker::Copy,
+            + std::marker::Copy
+            + Send
+            + Sync,
         I: ExactSizeIterator<Item = T> + Clone,
-        F: ComparisonFunction<T> + Debug;
+        F: ComparisonFunction<T> + Debug + Sync,
+    {
+        let (flow, rank, _) = self.rank_vetoed(criterias)?;
+        Ok((flow, rank))
+    }
+
+    /// Ranks the criteria, adding their interactions and forcing the
+    /// aggregated preferences of the pairs their vetoes apply to, and tells
+    /// how many pairs each vetoed.
+    fn rank_vetoed<T, I, F>(
+        mut self,
+        criterias: Vec<Criteria<T, I, F>>,
+    ) -> Result<Vetoed<T>, PrometheeError>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy
+            + Send
+            + Sync,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug + Sync,
+    {
+        let (valid, total_weight) = check(&criterias)?;
+        check_interactions(
+            self.interactions(),
+            criterias
+                .iter()
+                .map(|criteria| (&criteria.about, criteria.weight)),
+        )?;
+        let mut interacting = Interacting::new(self.interactions(), total_weight, valid.len());
+        let mut vetoes = Vetoes::new();
+        for (index, criteria) in criterias.iter().enumerate() {
+            vetoes.keep(
+                index,
+                criteria.actions.clone(),
+                &criteria.goal,
+                criteria.veto,
+                &valid,
+            );
+        }
+        let counts = vetoes.counts(criterias.len());
+        let mut flow = Flow::new(valid.len());
+
+        for (index, mut criteria) in criterias.into_iter().enumerate() {
+            criteria.weight = criteria.weight / total_weight;
+            flow = self.flow(&criteria, &valid, flow);
+            vetoes.force(&criteria, &mut flow);
+            interacting.add(index, criteria);
+        }
+        interacting.interact(&mut flow, &valid);
+        vetoes.interact(&interacting, &mut flow);
+
+        let (divide_by_alternatives, sentinel) = self.options();
+        let (flow, rank) = conclude(flow, &valid, divide_by_alternatives, sentinel);
+        Ok((flow, rank, counts))
+    }
+
+    /// Net flow of the alternatives on each criteria alone, as if it had all
+    /// the weight, keeping the study area of every criteria.
+    fn unicriterion_flows<T, I, F>(
+        &mut self,
+        criterias: &[Criteria<T, I, F>],
+    ) -> Result<Vec<Vec<T>>, PrometheeError>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy
+            + Send
+            + Sync,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug + Sync + Clone,
+    {
+        let (valid, _) = check(criterias)?;
+        let (divide_by_alternatives, sentinel) = self.options();
+        Ok(criterias
+            .iter()
+            .map(|criteria| {
+                let alone = Criteria {
+                    actions: criteria.actions.clone(),
+                    weight: T::from(1.0),
+                    function: criteria.function.clone(),
+                    goal: criteria.goal.clone(),
+                    veto: None,
+                    about: criteria.about.clone(),
+                };
+                let flow = self.flow(&alone, &valid, Flow::new(valid.len()));
+                conclude(flow, &valid, divide_by_alternatives, sentinel)
+                    .0
+                    .net_flow
+            })
+            .collect())
+    }
+
+    /// Ranks the `alternatives`, so results can be queried by their labels.
+    fn rank_labeled<T, I, F>(
+        self,
+        criterias: Vec<Criteria<T, I, F>>,
+        alternatives: Vec<Alternative>,
+    ) -> Result<Ranking<T>, PrometheeError>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy
+            + Send
+            + Sync,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug + Sync,
+    {
+        let about = criterias
+            .iter()
+            .map(|criteria| criteria.about.clone())
+            .collect();
+        let (flow, rank, vetoes) = self.rank_vetoed(criterias)?;
+        Ok(Ranking::new(about, alternatives, (flow, rank)).with_vetoes(vetoes))
+    }
+
+    /// Ranks criteria read only once each, see `stream::rank`.
+    fn rank_streamed<S, F, E>(
+        self,
+        criterias: Vec<StreamedCriteria<S, F>>,
+        temp_dir: &Path,
+    ) -> Result<Streamed, E>
+    where
+        S: IntoIterator<Item = Result<f64, E>>,
+        F: ComparisonFunction<f64> + Debug + Sync,
+        E: From<io::Error> + From<PrometheeError>,
+    {
+        stream::rank(self, criterias, temp_dir)
+    }
+}
+
+/// Name of the criteria at `index` in messages.
+fn label(index: usize, about: &About) -> String {
+    if about.name.is_empty() {
+        return format!("criteria {}", index + 1);
+    }
+    about.name.clone()
+}
+
+/// Checks what can be told of the criteria at `index` before reading its
+/// actions: its weight and the parameters of its preference function.
+pub(crate) fn check_criteria<T, F>(
+    index: usize,
+    about: &About,
+    weight: T,
+    function: &F,
+) -> Result<(), PrometheeError>
+where
+    T: From<f64> + PartialOrd,
+    F: ComparisonFunction<T>,
+{
+    // NaN fails both comparisons
+    if !(weight >= T::from(0.0) && weight < T::from(f64::INFINITY)) {
+        return Err(PrometheeError::InvalidWeight(label(index, about)));
+    }
+    function
+        .validate()
+        .map_err(|reason| PrometheeError::InvalidFunction {
+            criteria: label(index, about),
+            reason,
+        })
+}
+
+/// Checks the veto threshold of the criteria at `index`, if it has one.
+pub(crate) fn check_veto<T>(
+    index: usize,
+    about: &About,
+    veto: Option<T>,
+) -> Result<(), PrometheeError>
+where
+    T: From<f64> + PartialOrd,
+{
+    match veto {
+        // NaN fails both comparisons
+        Some(veto) if !(veto > T::from(0.0) && veto < T::from(f64::INFINITY)) => {
+            Err(PrometheeError::InvalidVeto(label(index, about)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the function of the criteria at `index` has no threshold left
+/// relative to its actions.
+pub(crate) fn check_resolved<T, F>(
+    index: usize,
+    about: &About,
+    function: &F,
+) -> Result<(), PrometheeError>
+where
+    F: ComparisonFunction<T>,
+{
+    function
+        .check_resolved()
+        .map_err(|reason| PrometheeError::InvalidFunction {
+            criteria: label(index, about),
+            reason,
+        })
+}
+
+/// Checks the goal of the criteria at `index`, and that it and the veto suit
+/// its function: categories are preferred as written in their table, and
+/// have no differences to veto.
+pub(crate) fn check_goal<T, F>(
+    index: usize,
+    about: &About,
+    goal: &Goal,
+    veto: Option<T>,
+    function: &F,
+) -> Result<(), PrometheeError>
+where
+    F: ComparisonFunction<T>,
+{
+    goal.validate()
+        .map_err(|reason| PrometheeError::InvalidGoal {
+            criteria: label(index, about),
+            reason,
+        })?;
+    if !function.compares_actions() {
+        return Ok(());
+    }
+    let reason = if *goal != Goal::Max {
+        format!(
+            "categories are preferred as written in their table, so the goal must be max, not {}",
+            goal
+        )
+    } else if veto.is_some() {
+        "categories have no differences to veto".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(PrometheeError::InvalidFunction {
+        criteria: label(index, about),
+        reason,
+    })
+}
+
+/// Checks every criteria has as many actions as the first one.
+fn check_lengths<'a, L>(lengths: L) -> Result<(), PrometheeError>
+where
+    L: IntoIterator<Item = (&'a About, usize)>,
+{
+    let mut lengths = lengths.into_iter().enumerate();
+    let (first, expected) = match lengths.next() {
+        Some((_, (about, expected))) => (label(0, about), expected),
+        None => return Err(PrometheeError::NoCriteria),
+    };
+    for (index, (about, found)) in lengths {
+        if found != expected {
+            return Err(PrometheeError::DifferentLengths {
+                criteria: label(index, about),
+                found,
+                first,
+                expected,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the `actions` of the criteria at `index` are not negative when a
+/// threshold of its function grows with them. Such lines are meant for
+/// actions like costs, and would fall to zero or below under them.
+pub(crate) fn check_actions<T, F, A>(
+    index: usize,
+    about: &About,
+    function: &F,
+    actions: A,
+) -> Result<(), PrometheeError>
+where
+    T: From<f64> + PartialOrd,
+    F: ComparisonFunction<T>,
+    A: IntoIterator<Item = T>,
+{
+    let variable = function
+        .thresholds()
+        .iter()
+        .any(|(_, threshold)| threshold.is_variable());
+    if !variable || !actions.into_iter().any(|action| action < T::from(0.0)) {
+        return Ok(());
+    }
+    Err(PrometheeError::InvalidFunction {
+        criteria: label(index, about),
+        reason: "thresholds growing with the actions need actions that are not negative"
+            .to_string(),
+    })
+}
+
+/// Checks at least two alternatives are left in the study area to compare.
+fn check_alternatives(valid: &[bool]) -> Result<(), PrometheeError> {
+    check_alternative_count(valid.iter().filter(|valid| **valid).count())
+}
+
+/// Checks there are at least two `alternatives` to compare, for rasters whose
+/// study area is only counted.
+pub(crate) fn check_alternative_count(alternatives: usize) -> Result<(), PrometheeError> {
+    if alternatives < 2 {
+        return Err(PrometheeError::TooFewAlternatives(alternatives));
+    }
+    Ok(())
+}
+
+/// Checks the criteria can be ranked, returning their study area and the
+/// total weight used to normalize the weights.
+fn check<T, I, F>(criterias: &[Criteria<T, I, F>]) -> Result<(Vec<bool>, T), PrometheeError>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy,
+    I: ExactSizeIterator<Item = T> + Clone,
+    F: ComparisonFunction<T>,
+{
+    check_lengths(
+        criterias
+            .iter()
+            .map(|criteria| (&criteria.about, criteria.actions.len())),
+    )?;
+    let mut total_weight = T::from(0.0);
+    for (index, criteria) in criterias.iter().enumerate() {
+        check_criteria(index, &criteria.about, criteria.weight, &criteria.function)?;
+        check_veto(index, &criteria.about, criteria.veto)?;
+        check_resolved(index, &criteria.about, &criteria.function)?;
+        check_goal(
+            index,
+            &criteria.about,
+            &criteria.goal,
+            criteria.veto,
+            &criteria.function,
+        )?;
+        total_weight = total_weight + criteria.weight;
+    }
+    if total_weight.partial_cmp(&T::from(0.0)) != Some(Ordering::Greater) {
+        return Err(PrometheeError::ZeroWeights);
+    }
+
+    let valid = study_area(criterias);
+    check_alternatives(&valid)?;
+    for (index, criteria) in criterias.iter().enumerate() {
+        check_actions(
+            index,
+            &criteria.about,
+            &criteria.function,
+            criteria
+                .actions
+                .clone()
+                .zip(valid.iter())
+                .filter(|(_, valid)| **valid)
+                .map(|(action, _)| action),
+        )?;
+    }
+    Ok((valid, total_weight))
+}
+
+/// Resolves the thresholds of the criteria given relative to their actions,
+/// measured on the alternatives of the study area. Tells what each criteria
+/// had resolved, to be reported with the ranking.
+pub(crate) fn resolve_thresholds<I, F>(criterias: &mut [Criteria<f64, I, F>]) -> Vec<Vec<Resolved>>
+where
+    I: ExactSizeIterator<Item = f64> + Clone,
+    F: ComparisonFunction<f64>,
+{
+    let valid = study_area(criterias);
+    criterias
+        .iter_mut()
+        .map(|criteria| {
+            let spread = Spread::new(
+                criteria
+                    .actions
+                    .clone()
+                    .zip(valid.iter())
+                    .filter(|(_, valid)| **valid)
+                    .map(|(action, _)| criteria.goal.aim(action)),
+            );
+            criteria.function.resolve(&spread)
+        })
+        .collect()
+}
+
+/// Alternatives with a missing (NaN) action in any criteria are left out of
+/// the study area, like nodata pixels in the raster versions.
+pub(crate) fn study_area<T, I, F>(criterias: &[Criteria<T, I, F>]) -> Vec<bool>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy,
+    I: ExactSizeIterator<Item = T> + Clone,
+    F: ComparisonFunction<T>,
+{
+    let n = criterias.first().map_or(0, |x| x.actions.len());
+
+    let mut valid = vec![true; n];
+    for criteria in criterias.iter() {
+        for (action, valid) in criteria.actions.clone().zip(valid.iter_mut()) {
+            if action.partial_cmp(&action).is_none() {
+                *valid = false;
+            }
+        }
+    }
+    valid
+}
+
+/// Turns the weighted preference sums of every alternative into flows and
+/// ranks the alternatives by net flow, the ones out of the study area last.
+pub(crate) fn conclude<T>(
+    mut flow: Flow<T>,
+    valid: &[bool],
+    divide_by_alternatives: bool,
+    sentinel: f64,
+) -> (Flow<T>, Vec<usize>)
+where
+    T: From<f64> + Sub<Output = T> + Div<Output = T> + PartialOrd + std::marker::Copy,
+{
+    let alternatives = valid.iter().filter(|valid| **valid).count();
+    let denominator = T::from((alternatives - 1) as f64);
+    for (positive, negative, net_flow, valid) in izip!(
+        flow.positive_flow.iter_mut(),
+        flow.negative_flow.iter_mut(),
+        flow.net_flow.iter_mut(),
+        valid.iter()
+    ) {
+        if !valid {
+            *positive = T::from(f64::NAN);
+            *negative = T::from(f64::NAN);
+            *net_flow = T::from(f64::NAN);
+            continue;
+        }
+        if divide_by_alternatives {
+            *positive = *positive / denominator;
+            *negative = *negative / denominator;
+        }
+        *net_flow = *positive - *negative;
+    }
+    flow.normalized_flow = normalize(&flow.net_flow, valid, T::from(sentinel));
+
+    let mut rank = (0..valid.len()).collect_vec();
+    rank.sort_by(|a, b| {
+        if valid[*a] != valid[*b] {
+            return valid[*b].cmp(&valid[*a]);
+        }
+        if flow.net_flow[*a] > flow.net_flow[*b] {
+            return Ordering::Less;
+        }
+        if flow.net_flow[*a] < flow.net_flow[*b] {
+            return Ordering::Greater;
+        }
+        Ordering::Equal
+    });
+    (flow, rank)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_goals() {
+        assert_eq!(Ok(Goal::Max), "max".parse());
+        assert_eq!(Ok(Goal::Min), "MIN".parse());
+        assert_eq!(
+            Ok(Goal::Target {
+                value: 7.0,
+                below: 0.5,
+                above: 1.0
+            }),
+            "target 7 0.5 1".parse()
+        );
+        for text in &["max", "min", "target -3", "target 7 0.5", "target 7 0.5 1"] {
+            assert_eq!(*text, text.parse::<Goal>().unwrap().to_string());
+        }
+        assert!("most".parse::<Goal>().is_err());
+        assert!("target".parse::<Goal>().is_err());
+        assert_eq!(
+            Err("tolerance must be finite and non-negative, not -1".to_string()),
+            "target 7 -1".parse::<Goal>()
+        );
+    }
+
+    #[test]
+    fn aims_at_targets() {
+        let ph = Goal::Target {
+            value: 7.0,
+            below: 0.5,
+            above: 1.0,
+        };
+        assert_eq!(1.5, ph.aim(5.0));
+        assert_eq!(0.0, ph.aim(6.5));
+        assert_eq!(0.0, ph.aim(8.0));
+        assert_eq!(1.0, ph.aim(9.0));
+        assert!(ph.aim(f64::NAN).is_nan());
+        assert_eq!(9.0, Goal::Max.aim(9.0));
+        assert!(ph.minimized() && Goal::Min.minimized() && !Goal::Max.minimized());
+    }
 }
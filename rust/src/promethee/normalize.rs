@@ -0,0 +1,59 @@
+use std::ops::{Div, Sub};
+
+/// Min-max scales `values` to the [0, 1] range, as done by `normalize.cpp`.
+///
+/// Only the positions marked in `valid` take part in the scaling, the others
+/// receive `sentinel`. When every valid value is the same they are all mapped
+/// to zero.
+pub(crate) fn normalize<T>(values: &[T], valid: &[bool], sentinel: T) -> Vec<T>
+where
+    T: From<f64> + Sub<Output = T> + Div<Output = T> + PartialOrd + std::marker::Copy,
+{
+    let mut bounds: Option<(T, T)> = None;
+    for (value, _) in values.iter().zip(valid.iter()).filter(|(_, valid)| **valid) {
+        bounds = match bounds {
+            None => Some((*value, *value)),
+            Some((min, max)) => Some((
+                if *value < min { *value } else { min },
+                if *value > max { *value } else { max },
+            )),
+        };
+    }
+
+    values
+        .iter()
+        .zip(valid.iter())
+        .map(|(value, valid)| match bounds {
+            Some((min, max)) if *valid => {
+                if max > min {
+                    (*value - min) / (max - min)
+                } else {
+                    T::from(0.0)
+                }
+            }
+            _ => sentinel,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_max_over_valid_values() {
+        let values = vec![2.0, -1.0, 100.0, 0.5, 3.0];
+        let valid = vec![true, true, false, true, true];
+        assert_eq!(
+            vec![0.75, 0.0, -1.0, 0.375, 1.0],
+            normalize(&values, &valid, -1.0)
+        );
+    }
+
+    #[test]
+    fn constant_values() {
+        let values = vec![4.0, 4.0, 4.0];
+        let valid = vec![true, false, true];
+        assert_eq!(vec![0.0, -9999.0, 0.0], normalize(&values, &valid, -9999.0));
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    fmt::Debug,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use super::*;
+use tempfile::NamedTempFile;
+
+/// Criteria whose actions can be read only once, in order, such as a file,
+/// stdin or the strips of a raster.
+pub(crate) struct StreamedCriteria<S, F> {
+    pub(crate) actions: S,
+    pub(crate) weight: f64,
+    pub(crate) function: F,
+    pub(crate) goal: Goal,
+    /// Difference beyond which the worse alternative can never outrank the
+    /// better one
+    pub(crate) veto: Option<f64>,
+    pub(crate) about: About,
+}
+
+/// Actions of a criteria spooled to a temporary file while its source is read.
+struct Spooled {
+    file: NamedTempFile,
+    len: usize,
+}
+
+impl Spooled {
+    /// Writes every action of `actions` to a temporary file in `temp_dir`,
+    /// leaving the alternatives with missing actions out of `valid`.
+    fn new<S, E>(actions: S, temp_dir: &Path, valid: &mut Vec<bool>) -> Result<Self, E>
+    where
+        S: IntoIterator<Item = Result<f64, E>>,
+        E: From<io::Error>,
+    {
+        let mut file = NamedTempFile::new_in(temp_dir)?;
+        let mut len = 0;
+        {
+            let mut writer = BufWriter::new(file.as_file_mut());
+            for action in actions {
+                let action = action?;
+                if len == valid.len() {
+                    valid.push(true);
+                }
+                if action.is_nan() {
+                    valid[len] = false;
+                }
+                writer.write_all(&action.to_le_bytes())?;
+                len += 1;
+            }
+            writer.flush()?;
+        }
+        Ok(Self { file, len })
+    }
+
+    fn load(&self) -> io::Result<Vec<f64>> {
+        let mut reader = BufReader::new(self.file.reopen()?);
+        let mut actions = Vec::with_capacity(self.len);
+        let mut bytes = [0; 8];
+        for _ in 0..self.len {
+            reader.read_exact(&mut bytes)?;
+            actions.push(f64::from_le_bytes(bytes));
+        }
+        Ok(actions)
+    }
+}
+
+/// Ranks criteria reading each source only once. The study area depends on
+/// every criteria, so sources are first spooled to `temp_dir` and then loaded
+/// back one at a time: at most one criteria is in memory at once. Relative
+/// thresholds are resolved once their criteria is loaded, and are returned
+/// with the flows. Criteria with a veto are loaded once more beforehand, and
+/// their actions kept to force the pairs they veto, and the preferences of the criteria that
+/// interact are kept until every criteria is loaded.
+pub(crate) fn rank<P, S, F, E>(
+    mut promethee: P,
+    criterias: Vec<StreamedCriteria<S, F>>,
+    temp_dir: &Path,
+) -> Result<Streamed, E>
+where
+    P: Promethee,
+    S: IntoIterator<Item = Result<f64, E>>,
+    F: ComparisonFunction<f64> + Debug + Sync,
+    E: From<io::Error> + From<PrometheeError>,
+{
+    // Checked before reading, as sources can be long
+    if criterias.is_empty() {
+        return Err(PrometheeError::NoCriteria.into());
+    }
+    for (index, criteria) in criterias.iter().enumerate() {
+        check_criteria(index, &criteria.about, criteria.weight, &criteria.function)?;
+        check_veto(index, &criteria.about, criteria.veto)?;
+        check_goal(
+            index,
+            &criteria.about,
+            &criteria.goal,
+            criteria.veto,
+            &criteria.function,
+        )?;
+    }
+    // Used to normalize the criteria weights
+    let total_weight = criterias
+        .iter()
+        .map(|criteria| criteria.weight)
+        .sum::<f64>();
+    if total_weight <= 0.0 {
+        return Err(PrometheeError::ZeroWeights.into());
+    }
+    check_interactions(
+        promethee.interactions(),
+        criterias
+            .iter()
+            .map(|criteria| (&criteria.about, criteria.weight)),
+    )?;
+
+    let mut valid = vec![];
+    let mut spooled = Vec::with_capacity(criterias.len());
+    for criteria in criterias.into_iter() {
+        let actions = Spooled::new(criteria.actions, temp_dir, &mut valid)?;
+        spooled.push(StreamedCriteria {
+            actions,
+            weight: criteria.weight / total_weight,
+            function: criteria.function,
+            goal: criteria.goal,
+            veto: criteria.veto,
+            about: criteria.about,
+        });
+    }
+    check_lengths(
+        spooled
+            .iter()
+            .map(|criteria| (&criteria.about, criteria.actions.len)),
+    )?;
+    check_alternatives(&valid)?;
+
+    let mut vetoes = Vetoes::new();
+    for (index, criteria) in spooled.iter().enumerate() {
+        if criteria.veto.is_some() {
+            vetoes.keep(
+                index,
+                criteria.actions.load()?.into_iter(),
+                &criteria.goal,
+                criteria.veto,
+                &valid,
+            );
+        }
+    }
+    let counts = vetoes.counts(spooled.len());
+    let mut interacting = Interacting::new(promethee.interactions(), total_weight, valid.len());
+
+    let mut flow = Flow::new(valid.len());
+    let mut resolved = Vec::with_capacity(spooled.len());
+    for (index, criteria) in spooled.into_iter().enumerate() {
+        let mut loaded = Criteria {
+            actions: criteria.actions.load()?.into_iter(),
+            weight: criteria.weight,
+            function: criteria.function,
+            goal: criteria.goal,
+            veto: criteria.veto,
+            about: criteria.about,
+        };
+        let spread = Spread::new(
+            loaded
+                .actions
+                .as_slice()
+                .iter()
+                .zip(valid.iter())
+                .filter(|(_, valid)| **valid)
+                .map(|(action, _)| loaded.goal.aim(*action)),
+        );
+        resolved.push(loaded.function.resolve(&spread));
+        check_criteria(index, &loaded.about, loaded.weight, &loaded.function)?;
+        check_actions(
+            index,
+            &loaded.about,
+            &loaded.function,
+            loaded
+                .actions
+                .as_slice()
+                .iter()
+                .zip(valid.iter())
+                .filter(|(_, valid)| **valid)
+                .map(|(action, _)| *action),
+        )?;
+        flow = promethee.flow(&loaded, &valid, flow);
+        vetoes.force(&loaded, &mut flow);
+        interacting.add(index, loaded);
+    }
+    interacting.interact(&mut flow, &valid);
+    vetoes.interact(&interacting, &mut flow);
+
+    let (divide_by_alternatives, sentinel) = promethee.options();
+    let (flow, rank) = conclude(flow, &valid, divide_by_alternatives, sentinel);
+    Ok((flow, rank, resolved, counts))
+}
+
+/// Actions written as text, separated by whitespace or new lines, such as a
+/// column of numbers piped to stdin. Missing actions are written as `NaN`.
+pub(crate) struct TextActions<R> {
+    reader: R,
+    line: String,
+    tokens: Vec<String>,
+}
+
+impl<R: BufRead> TextActions<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            tokens: vec![],
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TextActions<R> {
+    type Item = io::Result<f64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.tokens.is_empty() {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    self.tokens = self
+                        .line
+                        .split_whitespace()
+                        .rev()
+                        .map(String::from)
+                        .collect()
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        let token = self.tokens.pop().unwrap();
+        Some(token.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a number", token),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::vanilla::tests::assert_approx_eq;
+    use crate::promethee::vanilla::Vanilla;
+    use crate::raster::RasterError;
+
+    /// Source that panics if read twice.
+    struct Once(Option<Vec<f64>>);
+
+    impl Iterator for Once {
+        type Item = Result<f64, RasterError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let actions = self.0.as_mut().expect("read twice");
+            if actions.is_empty() {
+                self.0 = None;
+                return None;
+            }
+            Some(Ok(actions.remove(0)))
+        }
+    }
+
+    #[test]
+    fn same_as_in_memory() {
+        let first = vec![250.0, 200.0, f64::NAN, 300.0, 275.0];
+        let second = vec![16.0, 16.0, 8.0, 32.0, 32.0];
+        let function = || LinearFunction { m: 100.0.into() };
+
+        let (want_flow, want_rank) = Vanilla::new(true)
+            .rank(vec![
+                Criteria {
+                    actions: first.clone().into_iter(),
+                    weight: 0.35,
+                    function: function(),
+                    goal: Goal::Min,
+                    veto: None,
+                    about: About::default(),
+                },
+                Criteria {
+                    actions: second.clone().into_iter(),
+                    weight: 0.25,
+                    function: function(),
+                    goal: Goal::Max,
+                    veto: None,
+                    about: About::default(),
+                },
+            ])
+            .unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (got_flow, got_rank, resolved, _) = Vanilla::new(true)
+            .rank_streamed(
+                vec![
+                    StreamedCriteria {
+                        actions: Once(Some(first)),
+                        weight: 0.35,
+                        function: function(),
+                        goal: Goal::Min,
+                        veto: None,
+                        about: About::default(),
+                    },
+                    StreamedCriteria {
+                        actions: Once(Some(second)),
+                        weight: 0.25,
+                        function: function(),
+                        goal: Goal::Max,
+                        veto: None,
+                        about: About::default(),
+                    },
+                ],
+                temp_dir.path(),
+            )
+            .unwrap();
+
+        assert_eq!(want_rank, got_rank);
+        assert!(resolved.iter().all(Vec::is_empty));
+        assert!(got_flow.net_flow[2].is_nan());
+        let valid = [0, 1, 3, 4];
+        let pick = |flow: &Flow<f64>| Flow {
+            positive_flow: valid.iter().map(|i| flow.positive_flow[*i]).collect(),
+            negative_flow: valid.iter().map(|i| flow.negative_flow[*i]).collect(),
+            net_flow: valid.iter().map(|i| flow.net_flow[*i]).collect(),
+            normalized_flow: valid.iter().map(|i| flow.normalized_flow[*i]).collect(),
+        };
+        assert_approx_eq(pick(&want_flow), pick(&got_flow), 1e-12);
+        assert_eq!(0, std::fs::read_dir(temp_dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn vetoes_as_in_memory() {
+        let price = vec![100.0, 50.0, 95.0, f64::NAN];
+        let flood = vec![1.0, 6.0, 2.0, 9.0];
+        let function = || LinearFunction { m: 10.0.into() };
+        let criteria = |actions: &Vec<f64>, veto| Criteria {
+            actions: actions.clone().into_iter(),
+            weight: 1.0,
+            function: function(),
+            goal: Goal::Min,
+            veto,
+            about: About::default(),
+        };
+        let (want_flow, want_rank, want_vetoes) = Vanilla::new(true)
+            .rank_vetoed(vec![criteria(&price, None), criteria(&flood, Some(3.0))])
+            .unwrap();
+
+        let streamed = |actions: &[f64], veto| StreamedCriteria {
+            actions: Once(Some(actions.to_vec())),
+            weight: 1.0,
+            function: function(),
+            goal: Goal::Min,
+            veto,
+            about: About::default(),
+        };
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (got_flow, got_rank, _, got_vetoes) = Vanilla::new(true)
+            .rank_streamed(
+                vec![streamed(&price, None), streamed(&flood, Some(3.0))],
+                temp_dir.path(),
+            )
+            .unwrap();
+
+        // The last alternative is out of the study area, so never vetoed
+        assert_eq!(vec![0, 2], got_vetoes);
+        assert_eq!(want_vetoes, got_vetoes);
+        assert_eq!(want_rank, got_rank);
+        assert_eq!(want_flow.net_flow[..3], got_flow.net_flow[..3]);
+    }
+
+    #[test]
+    fn rejects_before_reading() {
+        let criteria = |actions, weight, m| StreamedCriteria {
+            actions,
+            weight,
+            function: LinearFunction { m },
+            goal: Goal::Min,
+            veto: None,
+            about: About::default(),
+        };
+        let temp_dir = tempfile::tempdir().unwrap();
+        let rank = |criterias| {
+            Vanilla::new(true)
+                .rank_streamed(criterias, temp_dir.path())
+                .unwrap_err()
+        };
+
+        // Sources already read panic, so these are rejected before reading
+        let err = rank(vec![criteria(Once(None), 1.0, (-100.0).into())]);
+        assert_eq!(
+            "preference function of criteria 1: m must be finite and positive, not -100",
+            err.to_string()
+        );
+        let err = rank(vec![criteria(Once(None), 0.0, 100.0.into())]);
+        assert!(matches!(
+            err,
+            RasterError::Promethee(PrometheeError::ZeroWeights)
+        ));
+
+        let err = rank(vec![
+            criteria(Once(Some(vec![250.0, 200.0])), 1.0, 100.0.into()),
+            criteria(Once(Some(vec![16.0, 16.0, 8.0])), 1.0, 100.0.into()),
+        ]);
+        assert_eq!(
+            "criteria 2 has 3 actions, but criteria 1 has 2",
+            err.to_string()
+        );
+        assert_eq!(0, std::fs::read_dir(temp_dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn resolves_relative_thresholds() {
+        let criteria = |actions: &[f64], m: &str| {
+            vec![StreamedCriteria {
+                actions: Once(Some(actions.to_vec())),
+                weight: 1.0,
+                function: LinearFunction {
+                    m: m.parse().unwrap(),
+                },
+                goal: Goal::Min,
+                veto: None,
+                about: About::default(),
+            }]
+        };
+        let temp_dir = tempfile::tempdir().unwrap();
+        let rank = |criterias| Vanilla::new(true).rank_streamed(criterias, temp_dir.path());
+
+        // Measured on the study area, leaving the missing action out
+        let actions = [250.0, 200.0, f64::NAN, 300.0];
+        let (want_flow, want_rank, _, _) = rank(criteria(&actions, "50")).unwrap();
+        let (got_flow, got_rank, resolved, _) = rank(criteria(&actions, "50%")).unwrap();
+        assert_eq!(want_rank, got_rank);
+        assert_eq!(
+            format!("{:?}", want_flow.net_flow),
+            format!("{:?}", got_flow.net_flow)
+        );
+        assert_eq!(
+            vec![vec![Resolved {
+                parameter: "m".to_string(),
+                threshold: Threshold::Range(50.0),
+                value: 50.0,
+            }]],
+            resolved
+        );
+
+        let err = rank(criteria(&[5.0, 5.0], "10%")).unwrap_err();
+        assert_eq!(
+            "preference function of criteria 1: m must be finite and positive, not 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn reads_text() {
+        let text = "1.5 2\n\n  NaN\t-3\n4e1\n";
+        let actions = TextActions::new(text.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(5, actions.len());
+        assert_eq!(vec![1.5, 2.0], actions[..2].to_vec());
+        assert!(actions[2].is_nan());
+        assert_eq!(vec![-3.0, 40.0], actions[3..].to_vec());
+
+        let err = TextActions::new("1 two 3".as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap_err();
+        assert_eq!("two is not a number", err.to_string());
+    }
+}
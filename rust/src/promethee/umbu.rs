@@ -0,0 +1,586 @@
+use std::{
+    fmt::Debug,
+    mem::swap,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+use super::*;
+use crate::raster::{self, LineReader, RasterError, RasterInfo};
+use itertools::izip;
+use num_traits::Pow;
+
+/// Most distinct values a criteria may have to be ranked by `Umbu` when the
+/// engine is chosen automatically.
+const MAX_DISTINCT: usize = 4096;
+
+/// Port of the C++ `PrometheeUmbu`: actions are compressed into their
+/// distinct values and how many times each one appears, flows are computed
+/// once per distinct value and expanded back to the actions.
+///
+/// Comparisons grow with the square of the distinct values instead of the
+/// actions, which pays off for classified rasters or integer indices.
+pub(crate) struct Umbu {
+    divide_by_alternatives: bool,
+    sentinel: f64,
+}
+
+/// Distinct values of a criteria, sorted, with the count of each one.
+pub(crate) struct Distinct<T> {
+    values: Vec<T>,
+    counts: Vec<T>,
+}
+
+/// Whether `distinct` values out of `alternatives` are few enough for `Umbu`
+/// to be faster than sorting.
+pub(crate) fn low_cardinality(distinct: usize, alternatives: usize) -> bool {
+    distinct <= MAX_DISTINCT && distinct * distinct <= alternatives
+}
+
+impl<T> Distinct<T>
+where
+    T: From<f64>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + PartialOrd
+        + std::marker::Copy,
+{
+    pub(crate) fn from_sorted(sorted: &[T]) -> Self {
+        let mut distinct = Self {
+            values: vec![],
+            counts: vec![],
+        };
+        for value in sorted {
+            match distinct.values.last() {
+                Some(last) if last == value => {
+                    let count = distinct.counts.last_mut().unwrap();
+                    *count = *count + T::from(1.0);
+                }
+                _ => {
+                    distinct.values.push(*value);
+                    distinct.counts.push(T::from(1.0));
+                }
+            }
+        }
+        distinct
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn alternatives(&self) -> T {
+        let mut total = T::from(0.0);
+        for count in self.counts.iter() {
+            total = total + *count;
+        }
+        total
+    }
+
+    fn index(&self, value: T) -> usize {
+        self.values.partition_point(|other| *other < value)
+    }
+
+    /// Positive and negative preference sums of every distinct value, aimed
+    /// at the `goal`.
+    fn flows<F: ComparisonFunction<T>>(&self, function: &F, goal: &Goal) -> (Vec<T>, Vec<T>) {
+        let mut positive = vec![T::from(0.0); self.len()];
+        let mut negative = vec![T::from(0.0); self.len()];
+
+        for (value, positive, negative) in
+            izip!(self.values.iter(), positive.iter_mut(), negative.iter_mut())
+        {
+            for (other, count) in self.values.iter().zip(self.counts.iter()) {
+                let lower = if *value < *other { *value } else { *other };
+                let (value, other) = (goal.aim(*value), goal.aim(*other));
+                *positive = *positive + *count * function.compare_at(value, other, lower);
+                *negative = *negative + *count * function.compare_at(other, value, lower);
+            }
+        }
+        (positive, negative)
+    }
+}
+
+impl Distinct<f64> {
+    /// Distinct values of a criteria raster, read line by line. None when
+    /// there are too many of them for `Umbu` to pay off.
+    pub(crate) fn of_raster(input: &str) -> Result<Option<Self>, RasterError> {
+        let mut reader = LineReader::open(input)?;
+        let mut distinct = Self {
+            values: vec![],
+            counts: vec![],
+        };
+
+        let mut line = vec![0.0; reader.info.width as usize];
+        for _ in 0..reader.info.height {
+            reader.read_line(&mut line)?;
+            for value in line.iter().filter(|value| !value.is_nan()) {
+                match distinct
+                    .values
+                    .binary_search_by(|other| other.total_cmp(value))
+                {
+                    Ok(index) => distinct.counts[index] += 1.0,
+                    Err(_) if distinct.len() == MAX_DISTINCT => return Ok(None),
+                    Err(index) => {
+                        distinct.values.insert(index, *value);
+                        distinct.counts.insert(index, 1.0);
+                    }
+                }
+            }
+        }
+
+        if low_cardinality(distinct.len(), distinct.alternatives() as usize) {
+            Ok(Some(distinct))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Umbu {
+    pub fn new(divide_by_alternatives: bool) -> Self {
+        Self {
+            divide_by_alternatives,
+            sentinel: -1.0,
+        }
+    }
+
+    /// Sets the normalized flow given to excluded alternatives (-1 by default).
+    pub fn sentinel(mut self, sentinel: f64) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
+
+    /// Adds the flows of a criteria whose valid actions take the `distinct`
+    /// values.
+    pub(super) fn distinct_flow<T, I, F>(
+        criteria: &Criteria<T, I, F>,
+        distinct: &Distinct<T>,
+        valid: &[bool],
+        mut flow: Flow<T>,
+    ) -> Flow<T>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T>,
+    {
+        let (positive, negative) = distinct.flows(&criteria.function, &criteria.goal);
+        let weight = criteria.weight;
+
+        for (action, positive_flow, negative_flow, _) in izip!(
+            criteria.actions.clone(),
+            flow.positive_flow.iter_mut(),
+            flow.negative_flow.iter_mut(),
+            valid.iter()
+        )
+        .filter(|(_, _, _, valid)| **valid)
+        {
+            let index = distinct.index(action);
+            let (mut positive, mut negative) = (positive[index], negative[index]);
+            if criteria.goal.minimized() {
+                swap(&mut positive, &mut negative);
+            }
+
+            *positive_flow = *positive_flow + (weight * positive);
+            *negative_flow = *negative_flow + (weight * negative);
+        }
+
+        flow
+    }
+
+    /// Weighted positive and negative flows of every distinct value of a
+    /// single criteria raster.
+    fn raster_flows<F>(
+        &self,
+        distinct: &Distinct<f64>,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+    ) -> (Vec<f64>, Vec<f64>)
+    where
+        F: ComparisonFunction<f64>,
+    {
+        let weight = if self.divide_by_alternatives {
+            weight / (distinct.alternatives() - 1.0)
+        } else {
+            weight
+        };
+
+        let (mut positive, mut negative) = distinct.flows(function, goal);
+        if goal.minimized() {
+            swap(&mut positive, &mut negative);
+        }
+        for flow in positive.iter_mut().chain(negative.iter_mut()) {
+            *flow *= weight;
+        }
+        (positive, negative)
+    }
+
+    /// Ranks a single criteria raster taking the `distinct` values, writing the
+    /// flows as `<prefix>*flow.tif`. Only a line of the raster is kept in
+    /// memory at a time.
+    pub(crate) fn rank_raster<F>(
+        &self,
+        input: &str,
+        prefix: &str,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        distinct: &Distinct<f64>,
+    ) -> Result<(), RasterError>
+    where
+        F: ComparisonFunction<f64>,
+    {
+        check_alternative_count(distinct.alternatives() as usize)?;
+        check_actions(
+            0,
+            &About::default(),
+            function,
+            distinct.values.iter().copied(),
+        )?;
+        let (positive, negative) = self.raster_flows(distinct, function, goal, weight);
+        let net = izip!(positive.iter(), negative.iter())
+            .map(|(positive, negative)| positive - negative)
+            .collect::<Vec<_>>();
+        let min = net.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = net.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+        let info = RasterInfo {
+            nodata: Some(f64::NAN),
+            ..LineReader::open(input)?.info
+        };
+        let paths = ["positiveflow", "negativeflow", "netflow", "normalizedflow"]
+            .iter()
+            .map(|flow| format!("{}{}.tif", prefix, flow))
+            .collect::<Vec<_>>();
+
+        let written = (|| {
+            write_mapped(&paths[0], input, &info, distinct, |index| positive[index])?;
+            write_mapped(&paths[1], input, &info, distinct, |index| negative[index])?;
+            write_mapped(&paths[2], input, &info, distinct, |index| net[index])?;
+
+            // Same scaling as `normalize`
+            let info = RasterInfo {
+                nodata: Some(self.sentinel),
+                ..info.clone()
+            };
+            write_mapped(&paths[3], input, &info, distinct, |index| {
+                if max > min {
+                    (net[index] - min) / (max - min)
+                } else {
+                    0.0
+                }
+            })
+        })();
+        raster::remove_on_error(&paths, written)
+    }
+
+    /// Writes only the weighted net flow of a single criteria raster taking
+    /// the `distinct` values.
+    pub(crate) fn net_flow_raster<F>(
+        &self,
+        input: &str,
+        output: &str,
+        function: &F,
+        goal: &Goal,
+        weight: f64,
+        distinct: &Distinct<f64>,
+    ) -> Result<(), RasterError>
+    where
+        F: ComparisonFunction<f64>,
+    {
+        check_alternative_count(distinct.alternatives() as usize)?;
+        check_actions(
+            0,
+            &About::default(),
+            function,
+            distinct.values.iter().copied(),
+        )?;
+        let (positive, negative) = self.raster_flows(distinct, function, goal, weight);
+        let info = RasterInfo {
+            nodata: Some(f64::NAN),
+            ..LineReader::open(input)?.info
+        };
+
+        let written = write_mapped(output, input, &info, distinct, |index| {
+            positive[index] - negative[index]
+        });
+        raster::remove_on_error(&[output], written)
+    }
+}
+
+/// Writes a raster reading `input` again, each cell given by `value` from the
+/// index of its distinct value. Cells left out of the study area get the
+/// nodata value of `info`.
+fn write_mapped<V>(
+    path: &str,
+    input: &str,
+    info: &RasterInfo,
+    distinct: &Distinct<f64>,
+    value: V,
+) -> Result<(), RasterError>
+where
+    V: Fn(usize) -> f64,
+{
+    let missing = info.nodata.unwrap_or(f64::NAN);
+    let mut reader = LineReader::open(input)?;
+    raster::write_lines(path, info, |line| {
+        reader.read_line(line)?;
+        for cell in line.iter_mut() {
+            *cell = if cell.is_nan() {
+                missing
+            } else {
+                value(distinct.index(*cell))
+            };
+        }
+        Ok(())
+    })
+}
+
+impl Promethee for Umbu {
+    fn options(&self) -> (bool, f64) {
+        (self.divide_by_alternatives, self.sentinel)
+    }
+
+    fn flow<T, I, F>(
+        &mut self,
+        criteria: &Criteria<T, I, F>,
+        valid: &[bool],
+        flow: Flow<T>,
+    ) -> Flow<T>
+    where
+        T: From<f64>
+            + Neg<Output = T>
+            + Add<Output = T>
+            + Sub<Output = T>
+            + Div<Output = T>
+            + Mul<Output = T>
+            + Pow<T, Output = T>
+            + PartialOrd
+            + std::marker::Copy,
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T> + Debug,
+    {
+        let mut sorted = criteria
+            .actions
+            .clone()
+            .zip(valid.iter())
+            .filter(|(_, valid)| **valid)
+            .map(|(action, _)| action)
+            .collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        Self::distinct_flow(criteria, &Distinct::from_sorted(&sorted), valid, flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::external_sort::ExternalSort;
+    use crate::promethee::fast::Fast;
+    use crate::promethee::vanilla::tests::assert_approx_eq;
+    use crate::promethee::vanilla::Vanilla;
+    use crate::PreferenceFunction;
+
+    // A classified raster: few land-use classes over many cells
+    const CLASSES: [f64; 16] = [
+        3.0, 1.0, 1.0, 2.0, 5.0, 3.0, 3.0, 1.0, 2.0, 2.0, 5.0, 3.0, 1.0, 1.0, 3.0, 2.0,
+    ];
+
+    fn criteria(
+        actions: Vec<f64>,
+        function: PreferenceFunction,
+        goal: Goal,
+    ) -> Vec<Criteria<f64, std::vec::IntoIter<f64>, PreferenceFunction>> {
+        vec![Criteria {
+            actions: actions.into_iter(),
+            weight: 1.0,
+            function,
+            goal,
+            veto: None,
+            about: About::default(),
+        }]
+    }
+
+    fn functions() -> Vec<PreferenceFunction> {
+        vec![
+            PreferenceFunction::Usual(UsualFunction {}),
+            PreferenceFunction::Quasi(QuasiFunction { l: 2.0.into() }),
+            PreferenceFunction::Linear(LinearFunction { m: 3.0.into() }),
+            PreferenceFunction::Level(LevelFunction {
+                weak_treshold: 1.0.into(),
+                weak_area: 2.0.into(),
+            }),
+            PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
+                indiference_threshold: 1.0.into(),
+                linear_area: 3.0.into(),
+            }),
+            PreferenceFunction::Gaussian(GaussianFunction {
+                std_dev: 2.0.into(),
+            }),
+            PreferenceFunction::PiecewiseLinear(PiecewiseLinearFunction {
+                breakpoints: vec![(0.0, 0.0), (2.0, 0.3), (5.0, 0.9), (8.0, 1.0)]
+                    .into_iter()
+                    .map(Breakpoint::from)
+                    .collect(),
+            }),
+            PreferenceFunction::PiecewiseLinear(PiecewiseLinearFunction {
+                breakpoints: vec![Breakpoint::from((0.0, 0.2)), Breakpoint::from((3.0, 0.6))],
+            }),
+            PreferenceFunction::MultiLevel(MultiLevelFunction {
+                steps: vec![Breakpoint::from((0.0, 0.25)), Breakpoint::from((2.0, 1.0))],
+            }),
+            PreferenceFunction::Power(PowerFunction {
+                p: 4.0.into(),
+                exponent: 2.0,
+            }),
+            PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
+                indiference_threshold: "0.25x".parse().unwrap(),
+                linear_area: "0.5x+1".parse().unwrap(),
+            }),
+            PreferenceFunction::Categorical(CategoricalFunction {
+                categories: [
+                    "1:0,0.2,1,0",
+                    "2:0.5,0,0.3,0",
+                    "3:0,0,0,0.8",
+                    "5:1,0.4,0.1,0",
+                ]
+                .iter()
+                .map(|category| category.parse().unwrap())
+                .collect(),
+            }),
+        ]
+    }
+
+    #[test]
+    fn counts_distinct_values() {
+        let mut sorted = CLASSES.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let distinct = Distinct::from_sorted(&sorted);
+
+        assert_eq!(vec![1.0, 2.0, 3.0, 5.0], distinct.values);
+        assert_eq!(vec![5.0, 4.0, 5.0, 2.0], distinct.counts);
+        assert_eq!(16.0, distinct.alternatives());
+        assert!(low_cardinality(distinct.len(), 16));
+        assert!(!low_cardinality(5, 16));
+    }
+
+    #[test]
+    fn same_as_vanilla() {
+        let target = Goal::Target {
+            value: 2.5,
+            below: 0.0,
+            above: 1.0,
+        };
+        for goal in &[Goal::Max, Goal::Min, target] {
+            let goal = || goal.clone();
+            for (want, got) in functions().into_iter().zip(functions()) {
+                // Categories are only ranked by their table, as written
+                if matches!(want, PreferenceFunction::Categorical(_)) && goal() != Goal::Max {
+                    continue;
+                }
+                let mut actions = CLASSES.to_vec();
+                actions[6] = f64::NAN;
+                let (want_flow, want_rank) = Vanilla::new(true)
+                    .rank(criteria(actions.clone(), want, goal()))
+                    .unwrap();
+                let (got_flow, got_rank) = Umbu::new(true)
+                    .rank(criteria(actions, got, goal()))
+                    .unwrap();
+
+                assert_eq!(want_rank, got_rank);
+                assert!(got_flow.net_flow[6].is_nan());
+                let pick = |flow: &Flow<f64>| Flow {
+                    positive_flow: flow
+                        .positive_flow
+                        .iter()
+                        .copied()
+                        .filter(|x| !x.is_nan())
+                        .collect(),
+                    negative_flow: flow
+                        .negative_flow
+                        .iter()
+                        .copied()
+                        .filter(|x| !x.is_nan())
+                        .collect(),
+                    net_flow: flow
+                        .net_flow
+                        .iter()
+                        .copied()
+                        .filter(|x| !x.is_nan())
+                        .collect(),
+                    normalized_flow: flow.normalized_flow.clone(),
+                };
+                assert_approx_eq(pick(&want_flow), pick(&got_flow), 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn fast_chooses_umbu_for_classified_rasters() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("classes.tif");
+        let output = temp_dir.path().join("result_classes.tif");
+        let info = RasterInfo {
+            width: 4,
+            height: 4,
+            nodata: None,
+            geo: Default::default(),
+        };
+        raster::write(&input, &info, &CLASSES).unwrap();
+        let input = input.to_string_lossy().to_string();
+
+        assert!(Distinct::of_raster(&input).unwrap().is_some());
+
+        // Gaussian can not be sorted out of core, so this only works by
+        // counting the distinct values
+        let function = PreferenceFunction::Gaussian(GaussianFunction {
+            std_dev: 2.0.into(),
+        });
+        Fast::new(true)
+            .net_flow_raster(
+                &input,
+                &output.to_string_lossy(),
+                &function,
+                &Goal::Max,
+                0.5,
+                &ExternalSort::new(1 << 20, temp_dir.path()),
+            )
+            .unwrap();
+
+        let (want, _) = Vanilla::new(true)
+            .rank(criteria(CLASSES.to_vec(), function, Goal::Max))
+            .unwrap();
+        let got = raster::read(&output).unwrap().values;
+        for (want, got) in want.net_flow.iter().zip(got.iter()) {
+            assert!((0.5 * want - got).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn too_many_distinct_values() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("criteria.tif");
+        let info = RasterInfo {
+            width: 4,
+            height: 4,
+            nodata: None,
+            geo: Default::default(),
+        };
+        let values = (0..16).map(|x| x as f64).collect::<Vec<_>>();
+        raster::write(&input, &info, &values).unwrap();
+
+        assert!(Distinct::of_raster(&input.to_string_lossy())
+            .unwrap()
+            .is_none());
+    }
+}
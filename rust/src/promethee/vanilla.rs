@@ -1,66 +1,70 @@
 use std::{
-    cmp::Ordering,
     fmt::Debug,
-    mem::swap,
     ops::{Add, Div, Mul, Neg, Sub},
 };
 
 use super::*;
-use itertools::{izip, Itertools};
 use num_traits::Pow;
 
+/// Pairs compared by the workers before their preferences are added, which
+/// bounds the preferences held in memory.
+const PAIRS_PER_BLOCK: usize = 1 << 20;
+
 pub(crate) struct Vanilla {
     divide_by_alternatives: bool,
+    sentinel: f64,
+    threads: usize,
+    interactions: Vec<Interaction>,
 }
 
 impl Vanilla {
     pub fn new(divide_by_alternatives: bool) -> Self {
         Self {
             divide_by_alternatives,
+            sentinel: -1.0,
+            threads: 1,
+            interactions: vec![],
         }
     }
 
-    fn flow<T, I, F>(&mut self, criteria: &Criteria<T, I, F>, mut flow: Flow<T>) -> Flow<T>
-    where
-        T: From<f64>
-            + Neg<Output = T>
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Div<Output = T>
-            + Mul<Output = T>
-            + Pow<T, Output = T>
-            + PartialOrd
-            + std::marker::Copy,
-        I: ExactSizeIterator<Item = T> + Clone,
-        F: ComparisonFunction<T> + Debug,
-    {
-        let actions = criteria.actions.clone().collect::<Vec<_>>();
-        let weight = criteria.weight;
-
-        for (pixel, positive_flow, negative_flow) in izip!(
-            actions.iter(),
-            flow.positive_flow.iter_mut(),
-            flow.negative_flow.iter_mut()
-        ) {
-            for other in actions.iter() {
-                let mut positive = criteria.function.compare(*pixel, *other);
-                let mut negative = criteria.function.compare(*other, *pixel);
-
-                if criteria.goal == Goal::Min {
-                    swap(&mut positive, &mut negative);
-                }
+    /// Splits the alternatives among `threads` workers (1 by default), as the
+    /// C++ `PrometheeThread` did. Preferences are added up in the same order
+    /// as with a single thread, so the flows do not depend on the threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
 
-                *positive_flow = *positive_flow + (weight * positive);
-                *negative_flow = *negative_flow + (weight * negative);
-            }
-        }
+    /// Sets the normalized flow given to excluded alternatives (-1 by default).
+    pub fn sentinel(mut self, sentinel: f64) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
 
-        flow
+    /// Lets the criteria interact instead of adding up their preferences
+    /// alone. The actions of the criteria that interact are kept until the
+    /// interactions are added.
+    pub fn with_interactions(mut self, interactions: Vec<Interaction>) -> Self {
+        self.interactions = interactions;
+        self
     }
 }
 
 impl Promethee for Vanilla {
-    fn rank<T, I, F>(mut self, criterias: Vec<Criteria<T, I, F>>) -> (Flow<T>, Vec<usize>)
+    fn options(&self) -> (bool, f64) {
+        (self.divide_by_alternatives, self.sentinel)
+    }
+
+    fn interactions(&self) -> &[Interaction] {
+        &self.interactions
+    }
+
+    fn flow<T, I, F>(
+        &mut self,
+        criteria: &Criteria<T, I, F>,
+        valid: &[bool],
+        mut flow: Flow<T>,
+    ) -> Flow<T>
     where
         T: From<f64>
             + Neg<Output = T>
@@ -70,58 +74,114 @@ impl Promethee for Vanilla {
             + Mul<Output = T>
             + Pow<T, Output = T>
             + PartialOrd
-            + std::marker::Copy,
+            + std::marker::Copy
+            + Send
+            + Sync,
         I: ExactSizeIterator<Item = T> + Clone,
-        F: ComparisonFunction<T> + Debug,
+        F: ComparisonFunction<T> + Debug + Sync,
     {
-        // Used to normalize the criteria weights
-        let mut total_weight = T::from(0.0);
-        for criteria in criterias.iter() {
-            total_weight = total_weight + criteria.weight;
-        }
-
-        let n = criterias.iter().map(|x| x.actions.len()).max().unwrap();
-
-        let mut flow = Flow {
-            positive_flow: vec![T::from(0.0); n],
-            negative_flow: vec![T::from(0.0); n],
-            net_flow: vec![T::from(0.0); n],
+        // Only the alternatives in the study area are compared
+        let actions = criteria
+            .actions
+            .clone()
+            .zip(valid.iter())
+            .enumerate()
+            .filter(|(_, (_, valid))| **valid)
+            .map(|(index, (action, _))| (index, criteria.goal.aim(action), action))
+            .collect::<Vec<_>>();
+        let function = &criteria.function;
+        let weight = criteria.weight;
+        let zero = T::from(0.0);
+
+        // Preferences of every pair starting at `row` with a later
+        // alternative, as better, worse and preference. The preferences of a
+        // pair come from a single difference of the aimed actions and the
+        // lesser action as read, and only the better alternative can be
+        // preferred, unless the function compares the actions.
+        let compares_actions = function.compares_actions();
+        let actions = &actions;
+        let row_preferences = |row: usize| {
+            let (i, a, read_a) = actions[row];
+            actions[row + 1..].iter().flat_map(move |&(j, b, read_b)| {
+                let lower = if read_a < read_b { read_a } else { read_b };
+                let preferences = if compares_actions {
+                    [
+                        Some((i, j, function.compare_at(a, b, lower))),
+                        Some((j, i, function.compare_at(b, a, lower))),
+                    ]
+                } else {
+                    let difference = a - b;
+                    if difference > zero {
+                        [Some((i, j, function.preference(difference, lower))), None]
+                    } else if difference < zero {
+                        [Some((j, i, function.preference(-difference, lower))), None]
+                    } else {
+                        [None, None]
+                    }
+                };
+                IntoIterator::into_iter(preferences).flatten()
+            })
         };
 
-        for mut criteria in criterias.into_iter() {
-            criteria.weight = criteria.weight / total_weight;
-            flow = self.flow(&criteria, flow);
-        }
+        // Preferring the greater values adds to the positive flow, otherwise
+        // to the negative one
+        let (ahead, behind) = if criteria.goal.minimized() {
+            (&mut flow.negative_flow, &mut flow.positive_flow)
+        } else {
+            (&mut flow.positive_flow, &mut flow.negative_flow)
+        };
+        let mut prefer = |better: usize, worse: usize, preference: T| {
+            let preference = weight * preference;
+            ahead[better] = ahead[better] + preference;
+            behind[worse] = behind[worse] + preference;
+        };
 
-        let denominator = T::from((n - 1) as f64);
-        for (positive, negative, net_flow) in izip!(
-            flow.positive_flow.iter_mut(),
-            flow.negative_flow.iter_mut(),
-            flow.net_flow.iter_mut()
-        ) {
-            if self.divide_by_alternatives {
-                *positive = *positive / denominator;
-                *negative = *negative / denominator;
+        if self.threads == 1 {
+            for row in 0..actions.len() {
+                for (better, worse, preference) in row_preferences(row) {
+                    prefer(better, worse, preference);
+                }
             }
-            *net_flow = *positive - *negative;
+            return flow;
         }
 
-        let mut rank = (0..n).collect_vec();
-        rank.sort_by(|a, b| {
-            if flow.net_flow[*a] > flow.net_flow[*b] {
-                return Ordering::Less;
-            }
-            if flow.net_flow[*a] < flow.net_flow[*b] {
-                return Ordering::Greater;
+        // Workers compare a block of rows at a time, dealt round robin as rows
+        // get shorter, and their preferences are added in row order as with a
+        // single thread, so the flows are the same whatever the threads.
+        let threads = self.threads;
+        let block = threads.max(PAIRS_PER_BLOCK / actions.len().max(1));
+        for start in (0..actions.len()).step_by(block) {
+            let end = (start + block).min(actions.len());
+            let mut rows = std::thread::scope(|scope| {
+                let workers = (0..threads)
+                    .map(|worker| {
+                        let row_preferences = &row_preferences;
+                        scope.spawn(move || {
+                            (start + worker..end)
+                                .step_by(threads)
+                                .map(|row| row_preferences(row).collect::<Vec<_>>())
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap().into_iter())
+                    .collect::<Vec<_>>()
+            });
+            for row in start..end {
+                let preferences = rows[(row - start) % threads].next().unwrap();
+                for (better, worse, preference) in preferences {
+                    prefer(better, worse, preference);
+                }
             }
-            Ordering::Equal
-        });
-        (flow, rank)
+        }
+        flow
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::vec;
 
     use crate::PreferenceFunction;
@@ -132,7 +192,7 @@ mod tests {
         (left - right).abs() < abs_error
     }
 
-    fn eq_floats<'a, Iter: Iterator<Item = f64>>(
+    fn eq_floats<Iter: Iterator<Item = f64>>(
         left: Iter,
         right: Iter,
         eps: f64,
@@ -145,7 +205,7 @@ mod tests {
         None
     }
 
-    fn assert_approx_eq<T: Copy + Into<f64>>(left: Flow<T>, right: Flow<T>, eps: f64) {
+    pub(crate) fn assert_approx_eq<T: Copy + Into<f64>>(left: Flow<T>, right: Flow<T>, eps: f64) {
         if let Some((pos, l, r)) = eq_floats(
             left.positive_flow.clone().into_iter().map(T::into),
             right.positive_flow.clone().into_iter().map(T::into),
@@ -176,6 +236,16 @@ mod tests {
                 pos, l, r
             );
         }
+        if let Some((pos, l, r)) = eq_floats(
+            left.normalized_flow.clone().into_iter().map(T::into),
+            right.normalized_flow.clone().into_iter().map(T::into),
+            eps,
+        ) {
+            panic!(
+                "normalized_flow differs at position {}. left: {}, right: {}",
+                pos, l, r
+            );
+        }
     }
 
     /// Example taken from https://youtu.be/xe2XgGrI0Sg
@@ -184,29 +254,37 @@ mod tests {
         let price = Criteria {
             actions: vec![250.0, 200.0, 300.0, 275.0].into_iter(),
             weight: 0.35,
-            function: LinearFunction { m: 100.0 },
+            function: LinearFunction { m: 100.0.into() },
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let storage = Criteria {
             actions: vec![16.0, 16.0, 32.0, 32.0].into_iter(),
             weight: 0.25,
-            function: LinearFunction { m: 16.0 },
+            function: LinearFunction { m: 16.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let camera = Criteria {
             actions: vec![12.0, 8.0, 16.0, 8.0].into_iter(),
             weight: 0.25,
-            function: LinearFunction { m: 8.0 },
+            function: LinearFunction { m: 8.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let looks = Criteria {
             actions: vec![5.0, 3.0, 4.0, 2.0].into_iter(),
             weight: 0.15,
-            function: LinearFunction { m: 3.0 },
+            function: LinearFunction { m: 3.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let want_rank = vec![2, 0, 1, 3];
@@ -215,11 +293,12 @@ mod tests {
             positive_flow: vec![0.2708333333, 0.2791666667, 0.425, 0.1958333333],
             negative_flow: vec![0.2666666667, 0.3416666667, 0.2208333333, 0.3416666667],
             net_flow: vec![0.004166666667, -0.0625, 0.2041666667, -0.1458333333],
+            normalized_flow: vec![0.4285714286, 0.2380952381, 1.0, 0.0],
         };
 
         let promethee = Vanilla::new(true);
 
-        let (got_flow, got_rank) = promethee.rank(vec![price, storage, camera, looks]);
+        let (got_flow, got_rank) = promethee.rank(vec![price, storage, camera, looks]).unwrap();
         assert_approx_eq(want_flow, got_flow, 1e-9);
         assert_eq!(want_rank, got_rank);
     }
@@ -229,65 +308,370 @@ mod tests {
         let erosao = Criteria {
             actions: vec![4.8, 3.4, 3.8, 4.5].into_iter(),
             weight: 1.0,
-            function: LinearFunction { m: 5.0 },
+            function: LinearFunction { m: 5.0.into() },
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let want_flow = Flow {
             positive_flow: vec![0.00, 0.1933333333, 0.1133333333, 0.02],
             negative_flow: vec![0.18, 0.00, 0.0266666667, 0.12],
             net_flow: vec![-0.18, 0.1933333333, 0.0866666667, -0.1],
+            normalized_flow: vec![0.0, 1.0, 0.7142857143, 0.2142857143],
         };
 
         let want_rank = vec![1, 2, 3, 0];
 
         let promethee = Vanilla::new(true);
-        let (got_flow, got_rank) = promethee.rank(vec![erosao]);
+        let (got_flow, got_rank) = promethee.rank(vec![erosao]).unwrap();
+
+        assert_eq!(got_rank, want_rank);
+        assert_approx_eq(want_flow, got_flow, 1e-9);
+    }
+
+    #[test]
+    fn targets_rank_distances() {
+        // Slopes near 5%, but not over 6%
+        let target = Criteria {
+            actions: vec![2.0, 4.5, 5.5, 7.0, 9.0].into_iter(),
+            weight: 1.0,
+            function: LinearFunction { m: 2.0.into() },
+            goal: Goal::Target {
+                value: 5.0,
+                below: 1.0,
+                above: 0.0,
+            },
+            veto: None,
+            about: About::default(),
+        };
+        let distances = Criteria {
+            actions: vec![2.0, 0.0, 0.5, 2.0, 4.0].into_iter(),
+            weight: 1.0,
+            function: LinearFunction { m: 2.0.into() },
+            goal: Goal::Min,
+            veto: None,
+            about: About::default(),
+        };
+
+        let (want_flow, want_rank) = Vanilla::new(true).rank(vec![distances]).unwrap();
+        let (got_flow, got_rank) = Vanilla::new(true).rank(vec![target]).unwrap();
+        assert_eq!(want_rank, got_rank);
+        assert_eq!(vec![1, 2, 0, 3, 4], got_rank);
+        assert_approx_eq(want_flow, got_flow, 1e-12);
+    }
+
+    #[test]
+    fn variable_thresholds_grow_with_the_actions_as_read() {
+        let criteria = |actions: Vec<f64>, goal: Goal| Criteria {
+            actions: actions.into_iter(),
+            weight: 1.0,
+            function: LinearFunction {
+                m: "0.5x+1".parse().unwrap(),
+            },
+            goal,
+            veto: None,
+            about: About::default(),
+        };
+        let assert_flows = |positive: Vec<f64>, negative: Vec<f64>, flow: Flow<f64>| {
+            for (want, got) in positive.iter().zip(flow.positive_flow.iter()) {
+                assert!((want - got).abs() < 1e-12, "{} != {}", want, got);
+            }
+            for (want, got) in negative.iter().zip(flow.negative_flow.iter()) {
+                assert!((want - got).abs() < 1e-12, "{} != {}", want, got);
+            }
+        };
+
+        // m is 2 at 2 and 2.5 at 3
+        let (flow, _) = Vanilla::new(false)
+            .rank(vec![criteria(vec![2.0, 3.0, 5.0], Goal::Min)])
+            .unwrap();
+        assert_flows(vec![1.5, 0.8, 0.0], vec![0.0, 0.5, 1.8], flow);
+
+        // Distances to the target are 3, 2 and 4, but m is still evaluated
+        // at the lesser action as read: 2 for the pairs of 2, and 2.5 for 3
+        // and 9
+        let target = Goal::Target {
+            value: 5.0,
+            below: 0.0,
+            above: 0.0,
+        };
+        let (flow, _) = Vanilla::new(false)
+            .rank(vec![criteria(vec![2.0, 3.0, 9.0], target)])
+            .unwrap();
+        assert_flows(vec![0.5, 1.3, 0.0], vec![0.5, 0.0, 1.3], flow);
+
+        // Targets built in code are checked as the parsed ones are
+        let wrong = Goal::Target {
+            value: 5.0,
+            below: -1.0,
+            above: 0.0,
+        };
+        assert_eq!(
+            "goal of criteria 1: tolerance must be finite and non-negative, not -1",
+            Vanilla::new(false)
+                .rank(vec![criteria(vec![2.0, 3.0, 9.0], wrong)])
+                .unwrap_err()
+                .to_string()
+        );
+
+        // m would be 0 at -2, so negative actions are refused
+        assert_eq!(
+            "preference function of criteria 1: \
+             thresholds growing with the actions need actions that are not negative",
+            Vanilla::new(false)
+                .rank(vec![criteria(vec![-2.0, 3.0, 9.0], Goal::Max)])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn compares_categories_by_their_codes() {
+        // Codes whose difference is not exact in floating point
+        let land = |goal: Goal, veto: Option<f64>| Criteria {
+            actions: vec![0.1, 0.3, 0.3].into_iter(),
+            weight: 1.0,
+            function: CategoricalFunction {
+                categories: vec!["0.1:0,1".parse().unwrap(), "0.3:0.5,0".parse().unwrap()],
+            },
+            goal,
+            veto,
+            about: About {
+                name: "land".to_string(),
+                ..About::default()
+            },
+        };
+
+        let (got_flow, got_rank) = Vanilla::new(false)
+            .rank(vec![land(Goal::Max, None)])
+            .unwrap();
+        assert_eq!(vec![0, 1, 2], got_rank);
+        assert_eq!(vec![2.0, 0.5, 0.5], got_flow.positive_flow);
+        assert_eq!(vec![1.0, 1.0, 1.0], got_flow.negative_flow);
+
+        assert_eq!(
+            "preference function of land: categories are preferred as written in their table, so the goal must be max, not min",
+            Vanilla::new(true)
+                .rank(vec![land(Goal::Min, None)])
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "preference function of land: categories have no differences to veto",
+            Vanilla::new(true)
+                .rank(vec![land(Goal::Max, Some(1.0))])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn missing_actions_are_excluded() {
+        let erosao = Criteria {
+            actions: vec![4.8, f64::NAN, 3.4, 3.8, 4.5].into_iter(),
+            weight: 1.0,
+            function: LinearFunction { m: 5.0.into() },
+            goal: Goal::Min,
+            veto: None,
+            about: About::default(),
+        };
+
+        let want_rank = vec![2, 3, 4, 0, 1];
+
+        let promethee = Vanilla::new(true).sentinel(-9999.0);
+        let (got_flow, got_rank) = promethee.rank(vec![erosao]).unwrap();
 
         assert_eq!(got_rank, want_rank);
+        assert!(got_flow.positive_flow[1].is_nan());
+        assert!(got_flow.negative_flow[1].is_nan());
+        assert!(got_flow.net_flow[1].is_nan());
+        assert_eq!(got_flow.normalized_flow[1], -9999.0);
+
+        let valid = [0, 2, 3, 4];
+        let got_flow = Flow {
+            positive_flow: valid.iter().map(|i| got_flow.positive_flow[*i]).collect(),
+            negative_flow: valid.iter().map(|i| got_flow.negative_flow[*i]).collect(),
+            net_flow: valid.iter().map(|i| got_flow.net_flow[*i]).collect(),
+            normalized_flow: valid.iter().map(|i| got_flow.normalized_flow[*i]).collect(),
+        };
+        let want_flow = Flow {
+            positive_flow: vec![0.00, 0.1933333333, 0.1133333333, 0.02],
+            negative_flow: vec![0.18, 0.00, 0.0266666667, 0.12],
+            net_flow: vec![-0.18, 0.1933333333, 0.0866666667, -0.1],
+            normalized_flow: vec![0.0, 1.0, 0.7142857143, 0.2142857143],
+        };
         assert_approx_eq(want_flow, got_flow, 1e-9);
     }
 
+    #[test]
+    fn rejects_what_can_not_be_ranked() {
+        let criteria = |name: &str, actions: Vec<f64>, weight: f64, m: f64| Criteria {
+            actions: actions.into_iter(),
+            weight,
+            function: LinearFunction { m: m.into() },
+            goal: Goal::Min,
+            veto: None,
+            about: About {
+                name: name.to_string(),
+                ..About::default()
+            },
+        };
+        let rank = |criterias| Vanilla::new(true).rank(criterias).unwrap_err();
+
+        assert_eq!(PrometheeError::NoCriteria, rank(vec![]));
+        let boxed: Box<dyn std::error::Error> = Box::new(rank(vec![]));
+        assert_eq!("no criteria to rank", boxed.to_string());
+        assert_eq!(
+            "rooms has 3 actions, but price has 4",
+            rank(vec![
+                criteria("price", vec![4.8, 3.4, 3.8, 4.5], 1.0, 5.0),
+                criteria("rooms", vec![2.0, 2.0, 1.0], 1.0, 5.0),
+            ])
+            .to_string()
+        );
+        assert_eq!(
+            "only 1 alternative has every action, at least 2 are needed",
+            rank(vec![criteria("price", vec![4.8, f64::NAN], 1.0, 5.0)]).to_string()
+        );
+        assert_eq!(
+            PrometheeError::TooFewAlternatives(0),
+            rank(vec![criteria("", vec![], 1.0, 5.0)])
+        );
+        assert_eq!(
+            "weight of criteria 2 must be finite and non-negative",
+            rank(vec![
+                criteria("", vec![4.8, 3.4], 1.0, 5.0),
+                criteria("", vec![4.8, 3.4], -0.5, 5.0),
+            ])
+            .to_string()
+        );
+        assert_eq!(
+            PrometheeError::InvalidWeight("price".to_string()),
+            rank(vec![criteria("price", vec![4.8, 3.4], f64::NAN, 5.0)])
+        );
+        assert_eq!(
+            PrometheeError::ZeroWeights,
+            rank(vec![criteria("price", vec![4.8, 3.4], 0.0, 5.0)])
+        );
+        assert_eq!(
+            "preference function of price: m must be finite and positive, not 0",
+            rank(vec![criteria("price", vec![4.8, 3.4], 1.0, 0.0)]).to_string()
+        );
+
+        let mut relative = criteria("price", vec![4.8, 3.4], 1.0, 5.0);
+        relative.function.m = "10%".parse().unwrap();
+        assert_eq!(
+            "preference function of price: threshold 10% of m was not resolved against the actions",
+            rank(vec![relative]).to_string()
+        );
+    }
+
+    #[test]
+    fn threads_give_the_same_flows() {
+        let problem = || {
+            let actions = (0..37)
+                .map(|x| {
+                    if x % 11 == 5 {
+                        f64::NAN
+                    } else {
+                        ((x * 17) % 23) as f64 / 3.0
+                    }
+                })
+                .collect::<Vec<_>>();
+            vec![
+                Criteria {
+                    actions: actions.clone().into_iter(),
+                    weight: 0.7,
+                    function: crate::PreferenceFunction::Linear(LinearFunction { m: 2.5.into() }),
+                    goal: Goal::Max,
+                    veto: None,
+                    about: About::default(),
+                },
+                Criteria {
+                    actions: actions.into_iter().rev().collect::<Vec<_>>().into_iter(),
+                    weight: 0.3,
+                    function: crate::PreferenceFunction::Gaussian(GaussianFunction {
+                        std_dev: 1.5.into(),
+                    }),
+                    goal: Goal::Min,
+                    veto: None,
+                    about: About::default(),
+                },
+            ]
+        };
+        let bits = |flow: &Flow<f64>| {
+            [
+                &flow.positive_flow,
+                &flow.negative_flow,
+                &flow.net_flow,
+                &flow.normalized_flow,
+            ]
+            .iter()
+            .flat_map(|flows| flows.iter().map(|x| x.to_bits()))
+            .collect::<Vec<_>>()
+        };
+
+        let (want_flow, want_rank) = Vanilla::new(true).rank(problem()).unwrap();
+        for threads in 2..=6 {
+            let (got_flow, got_rank) = Vanilla::new(true)
+                .with_threads(threads)
+                .rank(problem())
+                .unwrap();
+            assert_eq!(want_rank, got_rank);
+            assert_eq!(bits(&want_flow), bits(&got_flow));
+        }
+    }
+
     #[test]
     fn max_linear_criteria() {
         let erosao = Criteria {
             actions: vec![4.8, 3.4, 3.8, 4.5].into_iter(),
             weight: 2.0,
-            function: LinearFunction { m: 5.0 },
+            function: LinearFunction { m: 5.0.into() },
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let infpop = Criteria {
             actions: vec![300.0, 155.0, 200.0, 280.0].into_iter(),
             weight: 1.0,
-            function: LinearFunction { m: 500.0 },
+            function: LinearFunction { m: 500.0.into() },
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let prod = Criteria {
             actions: vec![6.2, 4.1, 5.0, 4.0].into_iter(),
             weight: 4.0,
-            function: LinearFunction { m: 7.0 },
+            function: LinearFunction { m: 7.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let rhcp = Criteria {
             actions: vec![2.2, 0.5, 0.7, 2.5].into_iter(),
             weight: 3.0,
-            function: LinearFunction { m: 2.5 },
+            function: LinearFunction { m: 2.5.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let want_flow = Flow {
             positive_flow: vec![0.2327619048, 0.06157142857, 0.07885714286, 0.1693333333],
             negative_flow: vec![0.06566666667, 0.2131428571, 0.1631904762, 0.1005238095],
             net_flow: vec![0.1670952381, -0.1515714286, -0.08433333333, 0.06880952381],
+            normalized_flow: vec![1.0, 0.0, 0.2109982068, 0.6915720263],
         };
 
         let want_rank = vec![0, 3, 2, 1];
 
         let promethee = Vanilla::new(true);
-        let (got_flow, got_rank) = promethee.rank(vec![erosao, infpop, prod, rhcp]);
+        let (got_flow, got_rank) = promethee.rank(vec![erosao, infpop, prod, rhcp]).unwrap();
 
         assert_eq!(want_rank, got_rank);
         assert_approx_eq(want_flow, got_flow, 1e-9);
@@ -299,35 +683,43 @@ mod tests {
         let f1 = Criteria {
             actions: vec![80.0, 65.0, 83.0, 40.0, 52.0, 94.0].into_iter(),
             weight: 1.0,
-            function: PreferenceFunction::Quasi(QuasiFunction { l: 10.0 }),
+            function: PreferenceFunction::Quasi(QuasiFunction { l: 10.0.into() }),
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let f2 = Criteria {
             actions: vec![90.0, 58.0, 60.0, 80.0, 72.0, 96.0].into_iter(),
             weight: 1.0,
-            function: PreferenceFunction::Linear(LinearFunction { m: 30.0 }),
+            function: PreferenceFunction::Linear(LinearFunction { m: 30.0.into() }),
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let f3 = Criteria {
             actions: vec![60.0, 20.0, 40.0, 100.0, 60.0, 70.0].into_iter(),
             weight: 1.0,
             function: PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
-                indiference_threshold: 5.0,
-                linear_area: 45.0,
+                indiference_threshold: 5.0.into(),
+                linear_area: 45.0.into(),
             }),
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let f4 = Criteria {
             actions: vec![5.4, 9.7, 7.2, 7.5, 2.0, 3.6].into_iter(),
             weight: 1.0,
             function: PreferenceFunction::Level(LevelFunction {
-                weak_treshold: 1.0,
-                weak_area: 5.0,
+                weak_treshold: 1.0.into(),
+                weak_area: 5.0.into(),
             }),
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let f5 = Criteria {
@@ -335,13 +727,19 @@ mod tests {
             weight: 1.0,
             function: PreferenceFunction::Usual(UsualFunction {}),
             goal: Goal::Min,
+            veto: None,
+            about: About::default(),
         };
 
         let f6 = Criteria {
             actions: vec![5.0, 1.0, 7.0, 10.0, 8.0, 6.0].into_iter(),
             weight: 1.0,
-            function: PreferenceFunction::Gaussian(GaussianFunction { std_dev: 5.0 }),
+            function: PreferenceFunction::Gaussian(GaussianFunction {
+                std_dev: 5.0.into(),
+            }),
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let want_flow = Flow {
@@ -354,12 +752,15 @@ mod tests {
             net_flow: vec![-0.728, 0.085, -0.447, -0.102, 1.466, -0.274]
                 .into_iter()
                 .collect(),
+            normalized_flow: vec![0.0, 0.371, 0.128, 0.285, 1.0, 0.207]
+                .into_iter()
+                .collect(),
         };
 
         let want_rank = vec![4, 1, 3, 5, 2, 0];
 
         let promethee = Vanilla::new(false);
-        let (got_flow, got_rank) = promethee.rank(vec![f1, f2, f3, f4, f5, f6]);
+        let (got_flow, got_rank) = promethee.rank(vec![f1, f2, f3, f4, f5, f6]).unwrap();
 
         assert_eq!(want_rank, got_rank);
         assert_approx_eq(want_flow, got_flow, 1e-2);
@@ -371,72 +772,152 @@ mod tests {
         let processor = Criteria {
             actions: vec![3.0, 5.0, 5.0, 7.0, 5.0, 3.0].into_iter(),
             weight: 37.657,
-            function: LinearFunction { m: 4.0 },
+            function: LinearFunction { m: 4.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let storage = Criteria {
             actions: vec![5.0, 7.0, 9.0, 9.0, 7.0, 5.0].into_iter(),
             weight: 9.395,
-            function: LinearFunction { m: 4.0 },
+            function: LinearFunction { m: 4.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let operating_system = Criteria {
             actions: vec![3.0, 5.0, 9.0, 9.0, 9.0, 5.0].into_iter(),
             weight: 4.529,
-            function: LinearFunction { m: 6.0 },
+            function: LinearFunction { m: 6.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let ram = Criteria {
             actions: vec![5.0, 5.0, 7.0, 9.0, 7.0, 5.0].into_iter(),
             weight: 21.594,
-            function: LinearFunction { m: 4.0 },
+            function: LinearFunction { m: 4.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let screen = Criteria {
             actions: vec![3.0, 7.0, 7.0, 9.0, 7.0, 7.0].into_iter(),
             weight: 16.932,
-            function: LinearFunction { m: 6.0 },
+            function: LinearFunction { m: 6.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let brand = Criteria {
             actions: vec![9.0, 3.0, 7.0, 2.0, 9.0, 5.0].into_iter(),
             weight: 7.647,
-            function: LinearFunction { m: 7.0 },
+            function: LinearFunction { m: 7.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let color = Criteria {
             actions: vec![3.0, 3.0, 5.0, 9.0, 9.0, 3.0].into_iter(),
             weight: 2.247,
-            function: LinearFunction { m: 6.0 },
+            function: LinearFunction { m: 6.0.into() },
             goal: Goal::Max,
+            veto: None,
+            about: About::default(),
         };
 
         let want_flow = Flow {
             positive_flow: vec![0.04151, 0.12188, 0.26870, 0.60934, 0.26058, 0.03652],
             negative_flow: vec![0.49889, 0.22204, 0.08527, 0.05025, 0.08933, 0.39276],
             net_flow: vec![-0.45738, -0.10015, 0.18343, 0.55909, 0.17125, -0.35624],
+            normalized_flow: vec![0.0, 0.35144, 0.63043, 1.0, 0.61844, 0.09950],
         };
 
         let want_rank = vec![3, 2, 4, 1, 5, 0];
 
         let promethee = Vanilla::new(true);
-        let (got_flow, got_rank) = promethee.rank(vec![
-            processor,
-            storage,
-            operating_system,
-            ram,
-            screen,
-            brand,
-            color,
-        ]);
+        let (got_flow, got_rank) = promethee
+            .rank(vec![
+                processor,
+                storage,
+                operating_system,
+                ram,
+                screen,
+                brand,
+                color,
+            ])
+            .unwrap();
 
         assert_eq!(want_rank, got_rank);
         assert_approx_eq(want_flow, got_flow, 1e-5);
     }
+
+    /// Counts how many preferences are evaluated.
+    #[derive(Debug)]
+    struct Counting {
+        function: GaussianFunction,
+        evaluations: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ComparisonFunction<f64> for Counting {
+        fn preference(&self, difference: f64, lower: f64) -> f64 {
+            self.evaluations
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.function.preference(difference, lower)
+        }
+    }
+
+    fn counting() -> Counting {
+        Counting {
+            function: GaussianFunction {
+                std_dev: 3.0.into(),
+            },
+            evaluations: Default::default(),
+        }
+    }
+
+    #[test]
+    fn evaluates_each_pair_once() {
+        let actions = (0..50).map(|x| ((x * 7) % 50) as f64).collect::<Vec<_>>();
+        for threads in 1..=3 {
+            let criteria = Criteria {
+                actions: actions.clone().into_iter(),
+                weight: 1.0,
+                function: counting(),
+                goal: Goal::Max,
+                veto: None,
+                about: About::default(),
+            };
+            let mut flow = Flow {
+                positive_flow: vec![0.0; 50],
+                negative_flow: vec![0.0; 50],
+                net_flow: vec![],
+                normalized_flow: vec![],
+            };
+            flow = Vanilla::new(true)
+                .with_threads(threads)
+                .flow(&criteria, &[true; 50], flow);
+
+            assert_eq!(
+                50 * 49 / 2,
+                criteria
+                    .function
+                    .evaluations
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            );
+            for (action, positive) in actions.iter().zip(flow.positive_flow.iter()) {
+                let want = actions
+                    .iter()
+                    .map(|other| criteria.function.function.compare(*action, *other))
+                    .sum::<f64>();
+                assert!((want - positive).abs() < 1e-12);
+            }
+        }
+    }
 }
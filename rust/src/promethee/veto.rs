@@ -0,0 +1,340 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use super::{interaction::Interacting, *};
+
+/// Actions of a criteria with a veto, aimed at its goal, kept to tell the
+/// pairs it vetoes without listing them.
+struct Vetoing<T> {
+    index: usize,
+    /// Aimed actions of every alternative, only read for the valid ones
+    actions: Vec<T>,
+    /// Aimed actions of the valid alternatives, sorted along with them
+    sorted: Vec<(T, usize)>,
+    veto: T,
+    minimized: bool,
+}
+
+impl<T> Vetoing<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + PartialOrd + Copy,
+{
+    /// Alternatives better than one of the given aimed `action` by more than
+    /// the veto, which are at one end of the sorted ones.
+    fn better(&self, action: T) -> &[(T, usize)] {
+        if self.minimized {
+            let end = self
+                .sorted
+                .partition_point(|(other, _)| *other < action - self.veto);
+            &self.sorted[..end]
+        } else {
+            let start = self
+                .sorted
+                .partition_point(|(other, _)| *other <= action + self.veto);
+            &self.sorted[start..]
+        }
+    }
+
+    /// Whether `worse` can never outrank `better` by this veto.
+    fn vetoes(&self, better: usize, worse: usize) -> bool {
+        if self.minimized {
+            self.actions[better] < self.actions[worse] - self.veto
+        } else {
+            self.actions[better] > self.actions[worse] + self.veto
+        }
+    }
+}
+
+/// Criteria with a veto, kept to force the preferences of the pairs they
+/// veto criteria by criteria, as each is added to the flows. Vetoed pairs are
+/// visited again each time from the sorted actions, never listed, so only the
+/// actions of the criteria with a veto are kept in memory.
+pub(crate) struct Vetoes<T> {
+    vetoing: Vec<Vetoing<T>>,
+}
+
+impl<T> Vetoes<T>
+where
+    T: From<f64>
+        + Neg<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Div<Output = T>
+        + Mul<Output = T>
+        + Pow<T, Output = T>
+        + PartialOrd
+        + std::marker::Copy,
+{
+    pub(crate) fn new() -> Self {
+        Self { vetoing: vec![] }
+    }
+
+    /// Keeps the `actions` of the `valid` alternatives of the criteria at
+    /// `index`, if it has a veto.
+    pub(crate) fn keep<A>(
+        &mut self,
+        index: usize,
+        actions: A,
+        goal: &Goal,
+        veto: Option<T>,
+        valid: &[bool],
+    ) where
+        A: Iterator<Item = T>,
+    {
+        let veto = match veto {
+            Some(veto) => veto,
+            None => return,
+        };
+        let actions = actions.map(|action| goal.aim(action)).collect_vec();
+        let mut sorted = actions
+            .iter()
+            .zip(valid.iter())
+            .enumerate()
+            .filter(|(_, (_, valid))| **valid)
+            .map(|(alternative, (action, _))| (*action, alternative))
+            .collect_vec();
+        sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        self.vetoing.push(Vetoing {
+            index,
+            actions,
+            sorted,
+            veto,
+            minimized: goal.minimized(),
+        });
+    }
+
+    /// Number of ordered pairs each of the `criterias` vetoed, empty when
+    /// none has a veto.
+    pub(crate) fn counts(&self, criterias: usize) -> Vec<usize> {
+        if self.vetoing.is_empty() {
+            return vec![];
+        }
+        let mut counts = vec![0; criterias];
+        for vetoing in self.vetoing.iter() {
+            counts[vetoing.index] = vetoing
+                .sorted
+                .iter()
+                .map(|(action, _)| vetoing.better(*action).len())
+                .sum();
+        }
+        counts
+    }
+
+    /// Visits each vetoed pair once, as `worse` and `better` by the first
+    /// criteria vetoing it, telling whether `better` can not outrank `worse`
+    /// either by another criteria.
+    fn visit<V>(&self, mut visit: V)
+    where
+        V: FnMut(usize, usize, bool),
+    {
+        for (first, vetoing) in self.vetoing.iter().enumerate() {
+            let (before, after) = self.vetoing.split_at(first);
+            for (action, worse) in vetoing.sorted.iter() {
+                for (_, better) in vetoing.better(*action) {
+                    let (better, worse) = (*better, *worse);
+                    if before
+                        .iter()
+                        .any(|other| other.vetoes(better, worse) || other.vetoes(worse, better))
+                    {
+                        continue;
+                    }
+                    let both = after.iter().any(|other| other.vetoes(worse, better));
+                    visit(worse, better, both);
+                }
+            }
+        }
+    }
+
+    /// Replaces the `summed` preference of `better` over `worse` in the
+    /// flows by the `forced` one.
+    fn replace(flow: &mut Flow<T>, better: usize, worse: usize, summed: T, forced: T) {
+        flow.positive_flow[better] = flow.positive_flow[better] - summed + forced;
+        flow.negative_flow[worse] = flow.negative_flow[worse] - summed + forced;
+    }
+
+    /// Forced preferences of `better` over `worse` and of `worse` over
+    /// `better`: none for the worse, and full for the better unless `both`
+    /// are vetoed.
+    fn forced(both: bool) -> (T, T) {
+        let none = T::from(0.0);
+        if both {
+            (none, none)
+        } else {
+            (T::from(1.0), none)
+        }
+    }
+
+    /// Forces the weighted preferences of the criteria over the vetoed pairs,
+    /// just added to the `flow`. As the weights add up to one, the pairs end
+    /// up with their forced aggregated preferences once every criteria is.
+    pub(crate) fn force<I, F>(&self, criteria: &Criteria<T, I, F>, flow: &mut Flow<T>)
+    where
+        I: ExactSizeIterator<Item = T> + Clone,
+        F: ComparisonFunction<T>,
+    {
+        if self.vetoing.is_empty() {
+            return;
+        }
+        let read = criteria.actions.clone().collect_vec();
+        let actions = read
+            .iter()
+            .map(|action| criteria.goal.aim(*action))
+            .collect_vec();
+        let preference = |a: usize, b: usize| {
+            let lower = if read[a] < read[b] { read[a] } else { read[b] };
+            let preference = if criteria.goal.minimized() {
+                criteria.function.compare_at(actions[b], actions[a], lower)
+            } else {
+                criteria.function.compare_at(actions[a], actions[b], lower)
+            };
+            criteria.weight * preference
+        };
+        self.visit(|worse, better, both| {
+            let (better_over_worse, worse_over_better) = Self::forced(both);
+            Self::replace(
+                flow,
+                better,
+                worse,
+                preference(better, worse),
+                criteria.weight * better_over_worse,
+            );
+            Self::replace(
+                flow,
+                worse,
+                better,
+                preference(worse, better),
+                criteria.weight * worse_over_better,
+            );
+        });
+    }
+
+    /// Takes the interactions back from the forced pairs, once they are
+    /// added to the flows, which leaves them their forced preferences.
+    pub(crate) fn interact<F>(&self, interacting: &Interacting<T, F>, flow: &mut Flow<T>)
+    where
+        F: ComparisonFunction<T>,
+    {
+        self.visit(|worse, better, both| {
+            let (better_over_worse, worse_over_better) = Self::forced(both);
+            Self::replace(
+                flow,
+                better,
+                worse,
+                interacting.aggregate(better, worse, better_over_worse),
+                better_over_worse,
+            );
+            Self::replace(
+                flow,
+                worse,
+                better,
+                interacting.aggregate(worse, better, worse_over_better),
+                worse_over_better,
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::interaction::{Effect, Interaction};
+    use crate::promethee::vanilla::Vanilla;
+
+    fn criteria(
+        actions: Vec<f64>,
+        goal: Goal,
+        veto: Option<f64>,
+    ) -> Criteria<f64, std::vec::IntoIter<f64>, LinearFunction> {
+        Criteria {
+            actions: actions.into_iter(),
+            weight: 1.0,
+            function: LinearFunction { m: 10.0.into() },
+            goal,
+            veto,
+            about: About::default(),
+        }
+    }
+
+    #[test]
+    fn counts_vetoed_pairs() {
+        let valid = [true, true, false, true, true];
+        let count = |goal, veto| {
+            let mut vetoes = Vetoes::new();
+            let flood = criteria(vec![1.0, 4.0, 9.0, 9.0, 2.5], goal, veto);
+            vetoes.keep(1, flood.actions, &flood.goal, flood.veto, &valid);
+            vetoes.counts(2)
+        };
+        // The third alternative is out of the study area, and the fourth can
+        // not outrank the others
+        assert_eq!(vec![0, 3], count(Goal::Min, Some(3.0)));
+        // The others can not outrank the fourth
+        assert_eq!(vec![0, 3], count(Goal::Max, Some(3.0)));
+        assert!(count(Goal::Max, None).is_empty());
+
+        let mut vetoes = Vetoes::new();
+        vetoes.keep(
+            0,
+            vec![1.0, 9.0].into_iter(),
+            &Goal::Max,
+            Some(3.0),
+            &[true; 2],
+        );
+        let mut pairs = vec![];
+        vetoes.visit(|worse, better, both| pairs.push((worse, better, both)));
+        assert_eq!(vec![(0, 1, false)], pairs);
+    }
+
+    #[test]
+    fn forces_vetoed_pairs() {
+        // The second alternative is far cheaper, but floods much more
+        let price = || criteria(vec![100.0, 50.0, 95.0], Goal::Min, None);
+        let flood = |veto| criteria(vec![1.0, 6.0, 2.0], Goal::Min, veto);
+
+        let (flow, _) = Vanilla::new(false)
+            .rank(vec![price(), flood(None)])
+            .unwrap();
+        assert!(flow.net_flow[1] > flow.net_flow[2]);
+
+        let (flow, rank, vetoes) = Vanilla::new(false)
+            .rank_vetoed(vec![price(), flood(Some(3.0))])
+            .unwrap();
+        assert_eq!(vec![0, 2], vetoes);
+        // The second alternative prefers nothing to the others, which are
+        // fully preferred to it, and the other pair is left as it was
+        let near = |want: f64, got: f64| assert!((want - got).abs() < 1e-12, "{}", got);
+        near(0.0, flow.positive_flow[1]);
+        near(2.0, flow.negative_flow[1]);
+        near(1.0 + 0.5 * 0.1, flow.positive_flow[0]);
+        near(1.0 + 0.5 * 0.5, flow.positive_flow[2]);
+        assert_eq!(vec![2, 0, 1], rank);
+
+        // Interactions leave the vetoed pairs forced
+        let (flow, _, _) = Vanilla::new(false)
+            .with_interactions(vec![Interaction {
+                first: 0,
+                second: 1,
+                effect: Effect::Strengthening,
+                coefficient: 1.0,
+            }])
+            .rank_vetoed(vec![price(), flood(Some(3.0))])
+            .unwrap();
+        near(0.0, flow.positive_flow[1]);
+        near(2.0, flow.negative_flow[1]);
+    }
+
+    #[test]
+    fn forces_pairs_once() {
+        // Both criteria veto the first alternative against the second, the
+        // price also against the third, and the last pair is vetoed each way
+        let price = criteria(vec![100.0, 50.0, 10.0], Goal::Min, Some(30.0));
+        let flood = criteria(vec![1.0, 6.0, 2.0], Goal::Max, Some(3.0));
+        let (flow, _, vetoes) = Vanilla::new(false).rank_vetoed(vec![price, flood]).unwrap();
+        assert_eq!(vec![3, 2], vetoes);
+        let near = |want: &[f64], got: &[f64]| {
+            for (want, got) in want.iter().zip(got.iter()) {
+                assert!((want - got).abs() < 1e-12, "{} != {}", want, got);
+            }
+        };
+        near(&[0.0, 1.0, 1.0], &flow.positive_flow);
+        near(&[2.0, 0.0, 0.0], &flow.negative_flow);
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use super::*;
+
+/// Adds up the per criteria flow rasters in `inputs` into `output`, like the
+/// old `caja/playground/src/merge.cpp`, each one scaled by its `weights`
+/// entry (all ones when empty).
+///
+/// The rasters are streamed strip by strip. A cell that is nodata or NaN in
+/// any of the inputs is NaN in the output, and no output is left when merging
+/// fails.
+pub(crate) fn merge<P, Q>(inputs: &[P], weights: &[f64], output: Q) -> Result<(), RasterError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if inputs.is_empty() {
+        return Err(RasterError::Mismatch("nothing to merge".to_string()));
+    }
+    if !weights.is_empty() && weights.len() != inputs.len() {
+        return Err(RasterError::Mismatch(format!(
+            "{} weights given for {} rasters",
+            weights.len(),
+            inputs.len()
+        )));
+    }
+    if let Some((path, _)) = inputs
+        .iter()
+        .zip(weights.iter())
+        .find(|(_, weight)| !weight.is_finite())
+    {
+        return Err(RasterError::InvalidWeight(
+            path.as_ref().display().to_string(),
+        ));
+    }
+
+    let mut readers = inputs
+        .iter()
+        .map(LineReader::open)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (width, height) = (readers[0].info.width, readers[0].info.height);
+    for (reader, path) in readers.iter().zip(inputs.iter()).skip(1) {
+        if (reader.info.width, reader.info.height) != (width, height) {
+            return Err(RasterError::Mismatch(format!(
+                "{} is {}x{} while {} is {}x{}",
+                path.as_ref().display(),
+                reader.info.width,
+                reader.info.height,
+                inputs[0].as_ref().display(),
+                width,
+                height
+            )));
+        }
+    }
+
+    let info = RasterInfo {
+        nodata: Some(f64::NAN),
+        ..readers[0].info.clone()
+    };
+    let mut buffer = vec![0.0; width as usize];
+    let written = write_lines(&output, &info, |line| {
+        for value in line.iter_mut() {
+            *value = 0.0;
+        }
+        for (position, reader) in readers.iter_mut().enumerate() {
+            let weight = weights.get(position).copied().unwrap_or(1.0);
+            reader.read_line(&mut buffer)?;
+            for (value, input) in line.iter_mut().zip(buffer.iter()) {
+                *value += weight * input;
+            }
+        }
+        Ok(())
+    });
+    remove_on_error(&[output], written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::temp_path;
+    use super::*;
+
+    fn info(width: u32, height: u32) -> RasterInfo {
+        RasterInfo {
+            width,
+            height,
+            nodata: Some(-9999.0),
+            geo: Default::default(),
+        }
+    }
+
+    #[test]
+    fn weighted_sum() {
+        let first = temp_path("merge_first.tif");
+        let second = temp_path("merge_second.tif");
+        let output = temp_path("merge_output.tif");
+
+        // Taller than a strip, so more than one is read and written
+        let values = (0..30).map(f64::from).collect::<Vec<_>>();
+        let mut others = vec![1.0; 30];
+        others[3] = -9999.0;
+        others[17] = f64::NAN;
+        write(&first, &info(3, 10), &values).unwrap();
+        write(&second, &info(3, 10), &others).unwrap();
+
+        merge(&[&first, &second], &[0.5, 2.0], &output).unwrap();
+        let merged = read(&output).unwrap();
+        for path in &[&first, &second, &output] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!((3, 10), (merged.info.width, merged.info.height));
+        for (position, value) in merged.values.iter().enumerate() {
+            if position == 3 || position == 17 {
+                assert!(value.is_nan());
+            } else {
+                assert_eq!(position as f64 * 0.5 + 2.0, *value);
+            }
+        }
+    }
+
+    #[test]
+    fn different_dimensions() {
+        let first = temp_path("merge_wide.tif");
+        let second = temp_path("merge_tall.tif");
+        let output = temp_path("merge_mismatch.tif");
+        write(&first, &info(3, 2), &[0.0; 6]).unwrap();
+        write(&second, &info(2, 3), &[0.0; 6]).unwrap();
+
+        let got = merge(&[&first, &second], &[], &output);
+        for path in &[&first, &second] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        match got {
+            Err(RasterError::Mismatch(_)) => {}
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn rejects_weights_that_are_not_finite() {
+        let first = temp_path("merge_infinite.tif");
+        let output = temp_path("merge_infinite_output.tif");
+        write(&first, &info(2, 2), &[0.0; 4]).unwrap();
+
+        let got = merge(&[&first], &[f64::INFINITY], &output);
+        std::fs::remove_file(&first).unwrap();
+
+        assert_eq!(
+            format!("weight of {} must be finite", first.display()),
+            got.unwrap_err().to_string()
+        );
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn failed_merge_leaves_no_output() {
+        let first = temp_path("merge_broken.tif");
+        let output = temp_path("merge_broken_output.tif");
+        write(&first, &info(2, 2), &[0.0; 4]).unwrap();
+        // Points the strip past the end of the file, so it opens but fails
+        // once its cells are read
+        let mut bytes = std::fs::read(&first).unwrap();
+        let ifd = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let entries = u16::from_le_bytes([bytes[ifd], bytes[ifd + 1]]) as usize;
+        let strip_offsets = (0..entries)
+            .map(|entry| ifd + 2 + 12 * entry)
+            .find(|entry| bytes[*entry..*entry + 2] == 273u16.to_le_bytes())
+            .unwrap();
+        bytes[strip_offsets + 8..strip_offsets + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&first, &bytes).unwrap();
+
+        assert!(LineReader::open(&first).is_ok());
+        let got = merge(&[&first], &[], &output);
+        std::fs::remove_file(&first).unwrap();
+
+        assert!(got.is_err());
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use tiff::{
+    decoder::{ChunkType, Decoder, DecodingResult, Limits},
+    encoder::{colortype::Gray64Float, TiffEncoder},
+    tags::Tag,
+    ColorType, TiffError,
+};
+
+use crate::promethee::{Flow, PrometheeError};
+
+pub(crate) mod merge;
+
+/// Same strip height used by the C++ `openFile`.
+const ROWS_PER_STRIP: u32 = 8;
+
+#[derive(Debug)]
+pub(crate) enum RasterError {
+    Io(io::Error),
+    Tiff(TiffError),
+    Unsupported(String),
+    Mismatch(String),
+    /// A weight of the raster named that is infinite or NaN.
+    InvalidWeight(String),
+    Promethee(PrometheeError),
+}
+
+impl fmt::Display for RasterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RasterError::Io(err) => write!(f, "{}", err),
+            RasterError::Tiff(err) => write!(f, "{}", err),
+            RasterError::Unsupported(reason) => write!(f, "unsupported raster: {}", reason),
+            RasterError::Mismatch(reason) => write!(f, "rasters do not match: {}", reason),
+            RasterError::InvalidWeight(raster) => {
+                write!(f, "weight of {} must be finite", raster)
+            }
+            RasterError::Promethee(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RasterError {}
+
+impl From<io::Error> for RasterError {
+    fn from(err: io::Error) -> Self {
+        RasterError::Io(err)
+    }
+}
+
+impl From<PrometheeError> for RasterError {
+    fn from(err: PrometheeError) -> Self {
+        RasterError::Promethee(err)
+    }
+}
+
+impl From<TiffError> for RasterError {
+    fn from(err: TiffError) -> Self {
+        RasterError::Tiff(err)
+    }
+}
+
+/// GeoTIFF tags copied as they are from an input raster to the outputs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct GeoTags {
+    pixel_scale: Option<Vec<f64>>,
+    tiepoint: Option<Vec<f64>>,
+    transformation: Option<Vec<f64>>,
+    key_directory: Option<Vec<u16>>,
+    double_params: Option<Vec<f64>>,
+    ascii_params: Option<String>,
+}
+
+/// Everything about a raster but its cells.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RasterInfo {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) nodata: Option<f64>,
+    pub(crate) geo: GeoTags,
+}
+
+/// A single band raster, cells stored line by line with nodata as NaN.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Raster {
+    pub(crate) info: RasterInfo,
+    pub(crate) values: Vec<f64>,
+}
+
+fn open(path: &Path) -> Result<(Decoder<BufReader<File>>, RasterInfo), RasterError> {
+    let file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let mut decoder = Decoder::new(BufReader::new(file))?.with_limits(Limits::unlimited());
+
+    match decoder.colortype()? {
+        ColorType::Gray(32) | ColorType::Gray(64) => {}
+        other => {
+            return Err(RasterError::Unsupported(format!(
+                "{} has {:?} pixels, only single band 32 or 64 bits floats are read",
+                path.display(),
+                other
+            )))
+        }
+    }
+
+    let (width, height) = decoder.dimensions()?;
+    let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+        Some(value) => {
+            let text = value.into_string()?;
+            let text = text.trim_matches(char::from(0)).trim();
+            Some(text.parse::<f64>().map_err(|_| {
+                RasterError::Unsupported(format!("invalid nodata value {:?}", text))
+            })?)
+        }
+        None => None,
+    };
+    let geo = GeoTags {
+        pixel_scale: f64_tag(&mut decoder, Tag::ModelPixelScaleTag)?,
+        tiepoint: f64_tag(&mut decoder, Tag::ModelTiepointTag)?,
+        transformation: f64_tag(&mut decoder, Tag::ModelTransformationTag)?,
+        key_directory: match decoder.find_tag(Tag::GeoKeyDirectoryTag)? {
+            Some(value) => Some(value.into_u16_vec()?),
+            None => None,
+        },
+        double_params: f64_tag(&mut decoder, Tag::GeoDoubleParamsTag)?,
+        ascii_params: match decoder.find_tag(Tag::GeoAsciiParamsTag)? {
+            Some(value) => Some(value.into_string()?),
+            None => None,
+        },
+    };
+
+    Ok((
+        decoder,
+        RasterInfo {
+            width,
+            height,
+            nodata,
+            geo,
+        },
+    ))
+}
+
+fn f64_tag(
+    decoder: &mut Decoder<BufReader<File>>,
+    tag: Tag,
+) -> Result<Option<Vec<f64>>, RasterError> {
+    Ok(match decoder.find_tag(tag)? {
+        Some(value) => Some(value.into_f64_vec()?),
+        None => None,
+    })
+}
+
+/// Reads a whole single band float TIFF (32 or 64 bits samples). Criteria
+/// are read with `Cells` instead, so only tests hold a raster in memory.
+#[cfg(test)]
+pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Raster, RasterError> {
+    let path = path.as_ref();
+    let (mut decoder, info) = open(path)?;
+
+    let mut values = floats(decoder.read_image()?, path)?;
+    replace_nodata(&mut values, info.nodata);
+
+    Ok(Raster { info, values })
+}
+
+fn floats(result: DecodingResult, path: &Path) -> Result<Vec<f64>, RasterError> {
+    match result {
+        DecodingResult::F32(values) => Ok(values.into_iter().map(f64::from).collect()),
+        DecodingResult::F64(values) => Ok(values),
+        _ => Err(RasterError::Unsupported(format!(
+            "{} samples are not floating point",
+            path.display()
+        ))),
+    }
+}
+
+fn replace_nodata(values: &mut [f64], nodata: Option<f64>) {
+    if let Some(nodata) = nodata {
+        for value in values.iter_mut().filter(|value| **value == nodata) {
+            *value = f64::NAN;
+        }
+    }
+}
+
+/// Reads a raster strip by strip, so only a few lines are kept in memory.
+pub(crate) struct LineReader {
+    path: PathBuf,
+    decoder: Decoder<BufReader<File>>,
+    pub(crate) info: RasterInfo,
+    strip: Vec<f64>,
+    next_strip: u32,
+    position: usize,
+}
+
+impl LineReader {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, RasterError> {
+        let path = path.as_ref();
+        let (decoder, info) = open(path)?;
+        if decoder.get_chunk_type() != ChunkType::Strip {
+            return Err(RasterError::Unsupported(format!(
+                "{} is tiled, only stripped rasters are read line by line",
+                path.display()
+            )));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            decoder,
+            info,
+            strip: vec![],
+            next_strip: 0,
+            position: 0,
+        })
+    }
+
+    /// Fills `line` with the next line of the raster, nodata as NaN.
+    pub(crate) fn read_line(&mut self, line: &mut [f64]) -> Result<(), RasterError> {
+        if self.position == self.strip.len() {
+            self.strip = floats(self.decoder.read_chunk(self.next_strip)?, &self.path)?;
+            replace_nodata(&mut self.strip, self.info.nodata);
+            self.next_strip += 1;
+            self.position = 0;
+        }
+
+        let end = self.position + line.len();
+        line.copy_from_slice(&self.strip[self.position..end]);
+        self.position = end;
+        Ok(())
+    }
+}
+
+/// Cells of a raster, nodata as NaN, read line by line so that a criteria
+/// can be streamed from it.
+pub(crate) struct Cells {
+    reader: LineReader,
+    line: Vec<f64>,
+    column: usize,
+    lines_left: u32,
+}
+
+impl Cells {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, RasterError> {
+        let reader = LineReader::open(path)?;
+        Ok(Self {
+            line: vec![],
+            column: 0,
+            lines_left: reader.info.height,
+            reader,
+        })
+    }
+
+    pub(crate) fn info(&self) -> &RasterInfo {
+        &self.reader.info
+    }
+}
+
+impl Iterator for Cells {
+    type Item = Result<f64, RasterError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.column == self.line.len() {
+            if self.lines_left == 0 {
+                return None;
+            }
+            self.line.resize(self.reader.info.width as usize, 0.0);
+            if let Err(err) = self.reader.read_line(&mut self.line) {
+                self.lines_left = 0;
+                return Some(Err(err));
+            }
+            self.lines_left -= 1;
+            self.column = 0;
+        }
+
+        self.column += 1;
+        Some(Ok(self.line[self.column - 1]))
+    }
+}
+
+/// Writes `values` as a 64 bits float TIFF with the size and georeferencing
+/// of `info`.
+pub(crate) fn write<P: AsRef<Path>>(
+    path: P,
+    info: &RasterInfo,
+    values: &[f64],
+) -> Result<(), RasterError> {
+    let mut lines = values.chunks(info.width as usize);
+    write_lines(path, info, |line| {
+        line.copy_from_slice(lines.next().unwrap());
+        Ok(())
+    })
+}
+
+/// Writes a 64 bits float TIFF with the size and georeferencing of `info`,
+/// asking `fill` for one line at a time, from top to bottom.
+pub(crate) fn write_lines<P, F>(path: P, info: &RasterInfo, mut fill: F) -> Result<(), RasterError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&mut [f64]) -> Result<(), RasterError>,
+{
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut image = encoder.new_image::<Gray64Float>(info.width, info.height)?;
+    image.rows_per_strip(ROWS_PER_STRIP)?;
+
+    let directory = image.encoder();
+    if let Some(pixel_scale) = &info.geo.pixel_scale {
+        directory.write_tag(Tag::ModelPixelScaleTag, &pixel_scale[..])?;
+    }
+    if let Some(tiepoint) = &info.geo.tiepoint {
+        directory.write_tag(Tag::ModelTiepointTag, &tiepoint[..])?;
+    }
+    if let Some(transformation) = &info.geo.transformation {
+        directory.write_tag(Tag::ModelTransformationTag, &transformation[..])?;
+    }
+    if let Some(key_directory) = &info.geo.key_directory {
+        directory.write_tag(Tag::GeoKeyDirectoryTag, &key_directory[..])?;
+    }
+    if let Some(double_params) = &info.geo.double_params {
+        directory.write_tag(Tag::GeoDoubleParamsTag, &double_params[..])?;
+    }
+    if let Some(ascii_params) = &info.geo.ascii_params {
+        directory.write_tag(Tag::GeoAsciiParamsTag, &ascii_params[..])?;
+    }
+    if let Some(nodata) = info.nodata {
+        directory.write_tag(Tag::GdalNodata, &nodata.to_string()[..])?;
+    }
+
+    let width = info.width as usize;
+    let mut strip = vec![];
+    while image.next_strip_sample_count() > 0 {
+        strip.resize(image.next_strip_sample_count() as usize, 0.0);
+        for line in strip.chunks_mut(width) {
+            fill(line)?;
+        }
+        image.write_strip(&strip)?;
+    }
+    image.finish()?;
+    Ok(())
+}
+
+/// Removes the outputs of a failed run, so that no partial raster is left.
+pub(crate) fn remove_on_error<P: AsRef<Path>>(
+    paths: &[P],
+    written: Result<(), RasterError>,
+) -> Result<(), RasterError> {
+    if written.is_err() {
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    written
+}
+
+/// Writes every flow as `<prefix>positiveflow.tif`, `<prefix>negativeflow.tif`,
+/// `<prefix>netflow.tif` and `<prefix>normalizedflow.tif`, the same names used
+/// by the C++ output writer. None of them is left when writing any fails.
+pub(crate) fn write_flow(
+    prefix: &str,
+    info: &RasterInfo,
+    flow: &Flow<f64>,
+    sentinel: f64,
+) -> Result<(), RasterError> {
+    let paths = ["positiveflow", "negativeflow", "netflow", "normalizedflow"]
+        .iter()
+        .map(|flow| format!("{}{}.tif", prefix, flow))
+        .collect::<Vec<_>>();
+    let info = RasterInfo {
+        nodata: Some(f64::NAN),
+        ..info.clone()
+    };
+
+    let written = (|| {
+        write(&paths[0], &info, &flow.positive_flow)?;
+        write(&paths[1], &info, &flow.negative_flow)?;
+        write(&paths[2], &info, &flow.net_flow)?;
+
+        let info = RasterInfo {
+            nodata: Some(sentinel),
+            ..info.clone()
+        };
+        write(&paths[3], &info, &flow.normalized_flow)
+    })();
+    remove_on_error(&paths, written)
+}
+
+#[cfg(test)]
+mod tests {
+    use tiff::encoder::colortype::Gray32Float;
+
+    use super::*;
+
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("promethee-{}-{}", std::process::id(), name))
+    }
+
+    fn geo() -> GeoTags {
+        GeoTags {
+            pixel_scale: Some(vec![30.0, 30.0, 0.0]),
+            tiepoint: Some(vec![0.0, 0.0, 0.0, 500000.0, 9000000.0, 0.0]),
+            transformation: None,
+            key_directory: Some(vec![1, 1, 0, 1, 3072, 0, 1, 31985]),
+            double_params: None,
+            ascii_params: Some("WGS 84 / UTM zone 25S|".to_string()),
+        }
+    }
+
+    #[test]
+    fn read_f32_with_nodata() {
+        let path = temp_path("read_f32.tif");
+        {
+            let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+            let mut image = encoder.new_image::<Gray32Float>(3, 2).unwrap();
+            let directory = image.encoder();
+            directory
+                .write_tag(Tag::ModelPixelScaleTag, &[30.0, 30.0, 0.0][..])
+                .unwrap();
+            directory
+                .write_tag(
+                    Tag::ModelTiepointTag,
+                    &[0.0, 0.0, 0.0, 500000.0, 9000000.0, 0.0][..],
+                )
+                .unwrap();
+            directory
+                .write_tag(
+                    Tag::GeoKeyDirectoryTag,
+                    &[1u16, 1, 0, 1, 3072, 0, 1, 31985][..],
+                )
+                .unwrap();
+            directory
+                .write_tag(Tag::GeoAsciiParamsTag, "WGS 84 / UTM zone 25S|")
+                .unwrap();
+            directory.write_tag(Tag::GdalNodata, "-9999").unwrap();
+            image
+                .write_data(&[1.5, -9999.0, 2.0, f32::NAN, 0.25, 4.0])
+                .unwrap();
+        }
+
+        let raster = read(&path).unwrap();
+        let cells = Cells::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(3, raster.info.width);
+        assert_eq!(2, raster.info.height);
+        assert_eq!(Some(-9999.0), raster.info.nodata);
+        assert_eq!(geo(), raster.info.geo);
+
+        let values = raster.values;
+        assert_eq!(6, values.len());
+        assert_eq!(1.5, values[0]);
+        assert!(values[1].is_nan());
+        assert_eq!(2.0, values[2]);
+        assert!(values[3].is_nan());
+        assert_eq!(0.25, values[4]);
+        assert_eq!(4.0, values[5]);
+        assert_eq!(format!("{:?}", values), format!("{:?}", cells));
+    }
+
+    #[test]
+    fn write_flow_keeps_georeferencing() {
+        let prefix = temp_path("write_flow.").to_string_lossy().to_string();
+        let info = RasterInfo {
+            width: 2,
+            height: 2,
+            nodata: Some(-9999.0),
+            geo: geo(),
+        };
+        let flow = Flow {
+            positive_flow: vec![0.5, f64::NAN, 0.0, 0.25],
+            negative_flow: vec![0.0, f64::NAN, 0.5, 0.25],
+            net_flow: vec![0.5, f64::NAN, -0.5, 0.0],
+            normalized_flow: vec![1.0, -1.0, 0.0, 0.5],
+        };
+
+        write_flow(&prefix, &info, &flow, -1.0).unwrap();
+
+        let net = read(format!("{}netflow.tif", prefix)).unwrap();
+        let normalized = read(format!("{}normalizedflow.tif", prefix)).unwrap();
+        for name in &["positiveflow", "negativeflow", "netflow", "normalizedflow"] {
+            std::fs::remove_file(format!("{}{}.tif", prefix, name)).unwrap();
+        }
+
+        assert_eq!(2, net.info.width);
+        assert_eq!(2, net.info.height);
+        assert_eq!(geo(), net.info.geo);
+        assert!(net.info.nodata.unwrap().is_nan());
+        assert_eq!(0.5, net.values[0]);
+        assert!(net.values[1].is_nan());
+        assert_eq!(-0.5, net.values[2]);
+
+        assert_eq!(Some(-1.0), normalized.info.nodata);
+        assert_eq!(1.0, normalized.values[0]);
+        assert!(normalized.values[1].is_nan());
+        assert_eq!(0.5, normalized.values[3]);
+    }
+
+    #[test]
+    fn failed_write_flow_leaves_no_output() {
+        let prefix = temp_path("failed_flow.").to_string_lossy().to_string();
+        let info = RasterInfo {
+            width: 2,
+            height: 1,
+            nodata: None,
+            geo: Default::default(),
+        };
+        let flow = Flow {
+            positive_flow: vec![0.5, 0.0],
+            negative_flow: vec![0.0, 0.5],
+            net_flow: vec![0.5, -0.5],
+            normalized_flow: vec![1.0, 0.0],
+        };
+        // The last flow can not be written over a directory
+        let normalized = format!("{}normalizedflow.tif", prefix);
+        std::fs::create_dir(&normalized).unwrap();
+
+        let written = write_flow(&prefix, &info, &flow, -1.0);
+        std::fs::remove_dir(&normalized).unwrap();
+
+        assert!(written.is_err());
+        for name in &["positiveflow", "negativeflow", "netflow"] {
+            assert!(!Path::new(&format!("{}{}.tif", prefix, name)).exists());
+        }
+    }
+}
@@ -0,0 +1,612 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    path::Path,
+};
+
+use itertools::Itertools;
+use roxmltree::{Document, Node};
+
+use crate::{
+    function::{
+        GaussianFunction, GaussianWithIndifferenceFunction, LevelFunction, LinearFunction,
+        LinearWithIndeferenceFunction, QuasiFunction, UsualFunction,
+    },
+    matrix::DecisionMatrix,
+    promethee::{About, Alternative, Criteria, Goal, Ranking},
+    PreferenceFunction,
+};
+
+/// Namespace of the XMCDA files written.
+const NAMESPACE: &str = "http://www.decision-deck.org/2019/XMCDA-3.1.1";
+
+#[derive(Debug)]
+pub(crate) enum XmcdaError {
+    Io(io::Error),
+    Xml(String),
+    Invalid(String),
+}
+
+impl fmt::Display for XmcdaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmcdaError::Io(err) => write!(f, "{}", err),
+            XmcdaError::Xml(reason) => write!(f, "{}", reason),
+            XmcdaError::Invalid(reason) => write!(f, "invalid XMCDA: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for XmcdaError {}
+
+impl From<io::Error> for XmcdaError {
+    fn from(err: io::Error) -> Self {
+        XmcdaError::Io(err)
+    }
+}
+
+/// Node of one of the files read, which knows where it is for messages.
+#[derive(Clone, Copy)]
+struct Located<'a, 'input> {
+    node: Node<'a, 'input>,
+    file: &'a str,
+}
+
+impl<'a, 'input> Located<'a, 'input> {
+    fn invalid(&self, reason: String) -> XmcdaError {
+        let position = self.node.document().text_pos_at(self.node.range().start);
+        XmcdaError::Invalid(format!("{} line {}: {}", self.file, position.row, reason))
+    }
+
+    fn children(&self, name: &'static str) -> impl Iterator<Item = Located<'a, 'input>> + 'a {
+        let file = self.file;
+        self.node
+            .children()
+            .filter(move |child| child.tag_name().name() == name)
+            .map(move |node| Located { node, file })
+    }
+
+    fn child(&self, name: &'static str) -> Option<Located<'a, 'input>> {
+        self.children(name).next()
+    }
+
+    fn text(&self) -> &'a str {
+        self.node.text().unwrap_or_default().trim()
+    }
+
+    /// Text of the `name` child, such as the ID in `<criterionID>`. It may
+    /// be nested in an element, as in `<criterion><criterionID>`.
+    fn reference(&self, name: &'static str) -> Option<&'a str> {
+        self.node
+            .descendants()
+            .find(|node| node.tag_name().name() == name)
+            .map(|node| node.text().unwrap_or_default().trim())
+    }
+
+    /// Value in a `<value>` (XMCDA 2) or the first of `<values>` (XMCDA 3).
+    fn value(&self) -> Option<Located<'a, 'input>> {
+        self.child("value").or_else(|| {
+            self.child("values")
+                .and_then(|values| values.child("value"))
+        })
+    }
+
+    /// Number of a value, written as a real, an integer or a rational.
+    fn number(&self) -> Result<f64, XmcdaError> {
+        let value = self
+            .value()
+            .ok_or_else(|| self.invalid("no value".to_string()))?;
+        let parse = |node: Located| {
+            node.text()
+                .parse::<f64>()
+                .map_err(|_| node.invalid(format!("{} is not a number", node.text())))
+        };
+        if let Some(number) = value.child("real").or_else(|| value.child("integer")) {
+            return parse(number);
+        }
+        if let Some(rational) = value.child("rational") {
+            match (rational.child("numerator"), rational.child("denominator")) {
+                (Some(numerator), Some(denominator)) => {
+                    return Ok(parse(numerator)? / parse(denominator)?)
+                }
+                _ => return Err(rational.invalid("incomplete rational".to_string())),
+            }
+        }
+        Err(value.invalid("the value is not a number".to_string()))
+    }
+
+    /// Whether the element is left out with `<active>false</active>`.
+    fn active(&self) -> bool {
+        self.child("active")
+            .is_none_or(|active| active.text() != "false")
+    }
+}
+
+/// Every `name` element of the files, wherever they are.
+fn all<'a, 'input>(
+    documents: &'a [(String, Document<'input>)],
+    name: &'static str,
+) -> Vec<Located<'a, 'input>> {
+    documents
+        .iter()
+        .flat_map(|(file, document)| {
+            document
+                .descendants()
+                .filter(move |node| node.tag_name().name() == name)
+                .map(move |node| Located { node, file })
+        })
+        .collect()
+}
+
+/// Reads alternatives, criteria, performance tables, weights, preference
+/// directions and the PROMETHEE `criteriaParameters` (function `type`, `q`,
+/// `p` and `s`) from XMCDA 2 or 3 files, split in as many files as wanted.
+pub(crate) fn read<P: AsRef<Path>>(paths: &[P]) -> Result<DecisionMatrix, XmcdaError> {
+    let texts = paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            std::fs::read_to_string(path)
+                .map(|text| (path.display().to_string(), text))
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    from_texts(&texts)
+}
+
+fn from_texts(texts: &[(String, String)]) -> Result<DecisionMatrix, XmcdaError> {
+    let documents = texts
+        .iter()
+        .map(|(file, text)| {
+            Document::parse(text)
+                .map(|document| (file.clone(), document))
+                .map_err(|err| XmcdaError::Xml(format!("{}: {}", file, err)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let alternatives = all(&documents, "alternative")
+        .into_iter()
+        .filter(|alternative| alternative.active())
+        .filter_map(|alternative| alternative.node.attribute("id"))
+        .collect_vec();
+    let criteria = all(&documents, "criterion")
+        .into_iter()
+        .filter(|criterion| criterion.active() && criterion.node.attribute("id").is_some())
+        .collect_vec();
+    if alternatives.is_empty() || criteria.is_empty() {
+        return Err(XmcdaError::Invalid(
+            "alternatives and criteria must be defined with an id".to_string(),
+        ));
+    }
+    let alternative_index: HashMap<_, _> = alternatives
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect();
+    let criterion_ids = criteria
+        .iter()
+        .map(|criterion| criterion.node.attribute("id").unwrap())
+        .collect_vec();
+    let criterion_index: HashMap<_, _> = criterion_ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect();
+    let criterion_of = |node: &Located| -> Result<Option<usize>, XmcdaError> {
+        let id = node
+            .reference("criterionID")
+            .ok_or_else(|| node.invalid("no criterionID".to_string()))?;
+        Ok(criterion_index.get(id).copied())
+    };
+
+    let mut columns = vec![vec![f64::NAN; alternatives.len()]; criteria.len()];
+    for performances in all(&documents, "alternativePerformances") {
+        let id = performances
+            .reference("alternativeID")
+            .ok_or_else(|| performances.invalid("no alternativeID".to_string()))?;
+        let alternative = match alternative_index.get(id) {
+            Some(alternative) => *alternative,
+            None => continue,
+        };
+        for performance in performances.children("performance") {
+            if let Some(criterion) = criterion_of(&performance)? {
+                columns[criterion][alternative] = performance.number()?;
+            }
+        }
+    }
+
+    let mut weights = vec![None; criteria.len()];
+    for values in all(&documents, "criteriaValues") {
+        let concept = values
+            .node
+            .attribute("mcdaConcept")
+            .unwrap_or("weights")
+            .to_lowercase();
+        if !concept.contains("weight") && !concept.contains("importance") {
+            continue;
+        }
+        for value in values.children("criterionValue") {
+            if let Some(criterion) = criterion_of(&value)? {
+                weights[criterion] = Some(value.number()?);
+            }
+        }
+    }
+
+    // Quantitative scales of XMCDA 2 criteria or XMCDA 3 criteriaScales
+    let mut goals = vec![Goal::Max; criteria.len()];
+    for (index, criterion) in criteria.iter().enumerate() {
+        if let Some(direction) = criterion.reference("preferenceDirection") {
+            goals[index] = goal(criterion, direction)?;
+        }
+    }
+    for scale in all(&documents, "criterionScale") {
+        if let (Some(criterion), Some(direction)) = (
+            criterion_of(&scale)?,
+            scale.reference("preferenceDirection"),
+        ) {
+            goals[criterion] = goal(&scale, direction)?;
+        }
+    }
+
+    let mut functions = criteria
+        .iter()
+        .map(|_| PreferenceFunction::Usual(UsualFunction {}))
+        .collect_vec();
+    for parameters in all(&documents, "criterionParameters") {
+        if let Some(criterion) = criterion_of(&parameters)? {
+            functions[criterion] = function(&parameters)?;
+        }
+    }
+
+    let criteria = criteria
+        .iter()
+        .zip(criterion_ids)
+        .zip(columns)
+        .zip(weights)
+        .zip(goals)
+        .zip(functions)
+        .map(|(((((criterion, id), actions), weight), goal), function)| {
+            Ok(Criteria {
+                actions: actions.into_iter(),
+                weight: weight.ok_or_else(|| {
+                    criterion.invalid(format!("{} has no weight in criteriaValues", id))
+                })?,
+                function,
+                goal,
+                veto: None,
+                about: About {
+                    name: id.to_string(),
+                    unit: None,
+                    description: criterion.node.attribute("name").map(String::from),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, XmcdaError>>()?;
+
+    Ok(DecisionMatrix {
+        alternatives: alternatives
+            .into_iter()
+            .map(|id| Alternative::Id(id.to_string()))
+            .collect(),
+        criteria,
+    })
+}
+
+fn goal(node: &Located, direction: &str) -> Result<Goal, XmcdaError> {
+    match direction {
+        "max" => Ok(Goal::Max),
+        "min" => Ok(Goal::Min),
+        _ => Err(node.invalid(format!("{} is neither max nor min", direction))),
+    }
+}
+
+/// Preference function of PROMETHEE `criterionParameters`, by its `type`
+/// (1 to 6, or a name) and its `q`, `p` and `s` thresholds.
+fn function(parameters: &Located) -> Result<PreferenceFunction, XmcdaError> {
+    let mut named = HashMap::new();
+    let list = parameters.child("parameters").unwrap_or(*parameters);
+    for parameter in list.children("parameter") {
+        let name = parameter
+            .node
+            .attribute("id")
+            .or_else(|| parameter.node.attribute("name"))
+            .unwrap_or_default()
+            .to_lowercase();
+        named.insert(name, parameter);
+    }
+
+    let kind = match named.get("type") {
+        Some(kind) => match kind.value().and_then(|value| value.child("label")) {
+            Some(label) => label.text().to_lowercase(),
+            None => kind.number()?.to_string(),
+        },
+        None => "usual".to_string(),
+    };
+    let threshold = |name: &str| -> Result<f64, XmcdaError> {
+        match named.get(name) {
+            Some(parameter) => parameter.number(),
+            None => {
+                Err(parameters.invalid(format!("a {} function needs the {} parameter", kind, name)))
+            }
+        }
+    };
+
+    Ok(match kind.as_str() {
+        "1" | "usual" => PreferenceFunction::Usual(UsualFunction {}),
+        "2" | "u-shape" | "quasi" => PreferenceFunction::Quasi(QuasiFunction {
+            l: threshold("q")?.into(),
+        }),
+        "3" | "v-shape" | "linear" => PreferenceFunction::Linear(LinearFunction {
+            m: threshold("p")?.into(),
+        }),
+        "4" | "level" => {
+            let q = threshold("q")?;
+            PreferenceFunction::Level(LevelFunction {
+                weak_treshold: q.into(),
+                weak_area: (threshold("p")? - q).into(),
+            })
+        }
+        "5" | "v-shape-ind" | "linear-with-indifference" => {
+            let q = threshold("q")?;
+            PreferenceFunction::LinearWithIndeference(LinearWithIndeferenceFunction {
+                indiference_threshold: q.into(),
+                linear_area: (threshold("p")? - q).into(),
+            })
+        }
+        "6" | "gaussian" => match named.get("q") {
+            Some(q) => {
+                PreferenceFunction::GaussianWithIndifference(GaussianWithIndifferenceFunction {
+                    q: q.number()?.into(),
+                    s: threshold("s")?.into(),
+                })
+            }
+            None => PreferenceFunction::Gaussian(GaussianFunction {
+                std_dev: threshold("s")?.into(),
+            }),
+        },
+        _ => return Err(parameters.invalid(format!("{} is not a preference function type", kind))),
+    })
+}
+
+/// Escapes text written in an attribute or element.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('>', "&gt;")
+}
+
+/// Writes the flows and the rank of every alternative as XMCDA 3
+/// `alternativesValues`. Alternatives out of the study area have no flows
+/// and no rank.
+pub(crate) fn write_flows<W: Write>(mut writer: W, ranking: &Ranking<f64>) -> io::Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<xmcda:XMCDA xmlns:xmcda="{}">"#, NAMESPACE)?;
+
+    let flow = &ranking.flow;
+    let concepts = [
+        ("positive flows", &flow.positive_flow),
+        ("negative flows", &flow.negative_flow),
+        ("net flows", &flow.net_flow),
+    ];
+    for (concept, values) in concepts.iter() {
+        writeln!(
+            writer,
+            r#"  <alternativesValues mcdaConcept="{}">"#,
+            concept
+        )?;
+        for (alternative, value) in ranking.alternatives.iter().zip(values.iter()) {
+            if value.is_nan() {
+                continue;
+            }
+            write_value(&mut writer, alternative, &format!("<real>{}</real>", value))?;
+        }
+        writeln!(writer, "  </alternativesValues>")?;
+    }
+
+    writeln!(writer, r#"  <alternativesValues mcdaConcept="ranks">"#)?;
+    // Alternatives out of the study area are ranked last, after the others
+    let ranked = ranking
+        .rank
+        .iter()
+        .filter(|index| !flow.net_flow[**index].is_nan());
+    for (place, index) in ranked.enumerate() {
+        write_value(
+            &mut writer,
+            &ranking.alternatives[*index],
+            &format!("<integer>{}</integer>", place + 1),
+        )?;
+    }
+    writeln!(writer, "  </alternativesValues>")?;
+    writeln!(writer, "</xmcda:XMCDA>")
+}
+
+fn write_value<W: Write>(writer: &mut W, alternative: &Alternative, value: &str) -> io::Result<()> {
+    writeln!(writer, "    <alternativeValue>")?;
+    writeln!(
+        writer,
+        "      <alternativeID>{}</alternativeID>",
+        escape(&alternative.to_string())
+    )?;
+    writeln!(writer, "      <values><value>{}</value></values>", value)?;
+    writeln!(writer, "    </alternativeValue>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promethee::{vanilla::Vanilla, Promethee};
+
+    /// Split like the files of a Diviz workflow, each version in one.
+    fn files() -> Vec<(String, String)> {
+        vec![
+            (
+                "problem.xml".to_string(),
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmcda:XMCDA xmlns:xmcda="http://www.decision-deck.org/2019/XMCDA-3.1.1">
+  <alternatives>
+    <alternative id="Recife"/>
+    <alternative id="Natal"/>
+    <alternative id="Patos"><active>false</active></alternative>
+    <alternative id="Sousa"/>
+  </alternatives>
+  <criteria>
+    <criterion id="price" name="Monthly rent"/>
+    <criterion id="rooms"/>
+  </criteria>
+  <criteriaScales>
+    <criterionScale>
+      <criterionID>price</criterionID>
+      <scales><scale><quantitative><preferenceDirection>min</preferenceDirection></quantitative></scale></scales>
+    </criterionScale>
+  </criteriaScales>
+  <performanceTable>
+    <alternativePerformances>
+      <alternativeID>Recife</alternativeID>
+      <performance><criterionID>price</criterionID><values><value><real>250</real></value></values></performance>
+      <performance><criterionID>rooms</criterionID><values><value><integer>2</integer></value></values></performance>
+    </alternativePerformances>
+    <alternativePerformances>
+      <alternativeID>Natal</alternativeID>
+      <performance><criterionID>price</criterionID><values><value><real>200</real></value></values></performance>
+      <performance><criterionID>rooms</criterionID><values><value><integer>2</integer></value></values></performance>
+    </alternativePerformances>
+    <alternativePerformances>
+      <alternativeID>Sousa</alternativeID>
+      <performance><criterionID>price</criterionID><values><value><real>300</real></value></values></performance>
+      <performance><criterionID>rooms</criterionID><values><value><integer>3</integer></value></values></performance>
+    </alternativePerformances>
+  </performanceTable>
+</xmcda:XMCDA>"#
+                    .to_string(),
+            ),
+            (
+                "parameters.xml".to_string(),
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<XMCDA xmlns="http://www.decision-deck.org/2012/XMCDA-2.2.1">
+  <criteriaValues mcdaConcept="Importance">
+    <criterionValue><criterionID>price</criterionID><value><real>0.35</real></value></criterionValue>
+    <criterionValue><criterionID>rooms</criterionID><value><rational><numerator>1</numerator><denominator>4</denominator></rational></value></criterionValue>
+  </criteriaValues>
+  <criteriaParameters>
+    <criterionParameters>
+      <criterion><criterionID>price</criterionID></criterion>
+      <parameters>
+        <parameter name="type"><value><label>v-shape-ind</label></value></parameter>
+        <parameter name="q"><value><real>10</real></value></parameter>
+        <parameter name="p"><value><real>110</real></value></parameter>
+      </parameters>
+    </criterionParameters>
+  </criteriaParameters>
+</XMCDA>"#
+                    .to_string(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn reads_criteria() {
+        let matrix = from_texts(&files()).unwrap();
+
+        assert_eq!(
+            vec!["Recife", "Natal", "Sousa"],
+            matrix
+                .alternatives
+                .iter()
+                .map(|alternative| alternative.to_string())
+                .collect_vec()
+        );
+        let price = &matrix.criteria[0];
+        assert_eq!(Goal::Min, price.goal);
+        assert_eq!(0.35, price.weight);
+        assert_eq!(Some("Monthly rent".to_string()), price.about.description);
+        assert_eq!(
+            vec![250.0, 200.0, 300.0],
+            price.actions.clone().collect_vec()
+        );
+        match &price.function {
+            PreferenceFunction::LinearWithIndeference(function) => {
+                assert_eq!(Some(10.0), function.indiference_threshold.fixed());
+                assert_eq!(Some(100.0), function.linear_area.fixed());
+            }
+            other => panic!("read {:?}", other),
+        }
+
+        let rooms = &matrix.criteria[1];
+        assert_eq!(Goal::Max, rooms.goal);
+        assert_eq!(0.25, rooms.weight);
+        assert!(matches!(rooms.function, PreferenceFunction::Usual(_)));
+    }
+
+    #[test]
+    fn reads_gaussian_with_indifference() {
+        let mut files = files();
+        files[1].1 = files[1].1.replace("v-shape-ind", "gaussian");
+        let err = from_texts(&files).err().unwrap().to_string();
+        assert!(
+            err.ends_with("a gaussian function needs the s parameter"),
+            "{}",
+            err
+        );
+
+        files[1].1 = files[1].1.replace("name=\"p\"", "name=\"s\"");
+        let matrix = from_texts(&files).unwrap();
+        match &matrix.criteria[0].function {
+            PreferenceFunction::GaussianWithIndifference(function) => {
+                assert_eq!(Some(10.0), function.q.fixed());
+                assert_eq!(Some(110.0), function.s.fixed());
+            }
+            other => panic!("read {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tells_where_it_is_wrong() {
+        let mut files = files();
+        files[1].1 = files[1].1.replace("<real>110</real>", "<real>lots</real>");
+        let err = from_texts(&files).err().unwrap();
+        assert_eq!(
+            "invalid XMCDA: parameters.xml line 13: lots is not a number",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn writes_flows() {
+        let matrix = from_texts(&files()).unwrap();
+        let ranking = Vanilla::new(true)
+            .rank_labeled(matrix.criteria, matrix.alternatives)
+            .unwrap();
+
+        let mut written = vec![];
+        write_flows(&mut written, &ranking).unwrap();
+        let written = String::from_utf8(written).unwrap();
+
+        // Read back as any other XMCDA
+        let document = Document::parse(&written).unwrap();
+        let concepts = document
+            .descendants()
+            .filter_map(|node| node.attribute("mcdaConcept"))
+            .collect_vec();
+        assert_eq!(
+            vec!["positive flows", "negative flows", "net flows", "ranks"],
+            concepts
+        );
+        let ranks = document
+            .descendants()
+            .filter(|node| node.attribute("mcdaConcept") == Some("ranks"))
+            .flat_map(|node| node.descendants())
+            .filter(|node| node.tag_name().name() == "alternativeID")
+            .map(|node| node.text().unwrap())
+            .collect_vec();
+        let want = ranking
+            .rank
+            .iter()
+            .map(|index| ranking.alternatives[*index].to_string())
+            .filter(|alternative| alternative != "Patos")
+            .collect_vec();
+        assert_eq!(want, ranks);
+        assert_eq!(3, ranks.len());
+    }
+}